@@ -0,0 +1,57 @@
+// Cycles per second of the model, stepping by cycle and by instruction, running
+// a loop of mixed instructions against a plain 64K memory map.
+//
+// Run with `cargo bench`. There are no external dependencies, so this times
+// a few runs and reports the best, rather than doing anything statistical.
+use std::time::Instant;
+use model_6502::assembler::assemble;
+use model_6502::memory_map::MemoryMap;
+use model_6502::runner::{Runner, Stepping, Stop};
+
+const CYCLES: u64 = 5_000_000;
+const RUNS: usize = 5;
+
+const PROGRAM: &str = "
+    org $0200
+    .start:
+      ldx #$FF
+      txs
+    .outer:
+      ldy #0
+    .inner:
+      lda .table,y
+      clc
+      adc $10
+      sta $10
+      jsr .shift
+      inc $11
+      dey
+      bne .inner
+      bra .outer
+    .shift:
+      asl $12
+      rol $13
+      rts
+    .table:
+      db 1 2 3 4 5 6 7 8
+    org $FFFC
+      dw .start
+";
+
+fn main() {
+    let image = assemble(PROGRAM).unwrap();
+    for stepping in [Stepping::Cycle, Stepping::Instruction] {
+        let mut best = f64::MAX;
+        let mut cycles = 0;
+        for _ in 0 .. RUNS {
+            let mut runner = Runner::new(MemoryMap::from_image(&image).unwrap());
+            runner.set_stepping(stepping);
+            let start = Instant::now();
+            assert_eq!(Stop::CycleLimit, runner.run(Some(CYCLES)));
+            best = best.min(start.elapsed().as_secs_f64());
+            cycles = runner.cycles();
+        }
+        println!("{stepping:?} stepping: {cycles} cycles in {best:.3}s: {:.2}M cycles/s",
+                 cycles as f64 / best / 1e6);
+    }
+}
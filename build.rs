@@ -0,0 +1,48 @@
+// Generates a test for each trace in passing_traces/ and failing_traces/.
+//
+// Each trace gets its own #[test], named after the directory and the log file,
+// e.g. passing_traces/nop_jmp_loop.log becomes passing_nop_jmp_loop. This way
+// each trace shows up by name in `cargo test`, can be filtered on, and a
+// failure doesn't hide the traces after it.
+//
+// The generated tests are included by the trace_tests module in
+// src/trace_tests.rs, which provides assert_passing and assert_failing.
+use std::fmt::Write;
+use std::path::Path;
+
+const TRACE_DIRS: [(&str, &str, &str); 2] = [
+    // directory, test name prefix, check
+    ("passing_traces", "passing", "assert_passing"),
+    ("failing_traces", "failing", "assert_failing"),
+];
+
+// Turn a file stem into something usable as part of a function name.
+fn test_name(stem: &str) -> String {
+    stem.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+fn main() {
+    let mut tests = String::new();
+    for (dir, prefix, check) in TRACE_DIRS {
+        // Rerun when traces are added or removed.
+        println!("cargo:rerun-if-changed={dir}");
+        let mut logs : Vec<String> = std::fs::read_dir(dir)
+            .unwrap_or_else(|e| panic!("Failed to read '{dir}': {e}"))
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+            .map(|path| path.file_stem().unwrap().to_str().unwrap().to_string())
+            .collect();
+        // Keep the generated file stable, regardless of directory order.
+        logs.sort();
+        for stem in logs {
+            let name = test_name(&stem);
+            writeln!(tests, "#[test]\nfn {prefix}_{name}() {{\n    \
+                             {check}(\"{dir}/{stem}\");\n}}\n").unwrap();
+        }
+    }
+
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    std::fs::write(Path::new(&out_dir).join("trace_tests.rs"), tests).unwrap();
+}
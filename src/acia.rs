@@ -0,0 +1,321 @@
+// W65C51 Asynchronous Communications Interface Adapter (ACIA)
+//
+// A serial port, with its transmit and receive sides connected to a host
+// byte stream rather than real serial lines. This lets monitor programs
+// such as WozMon or EhBASIC be used interactively from a terminal:
+//
+//   let acia = Acia::new(std::io::stdin(), std::io::stdout());
+//
+// Bytes take as long to send and receive as they would at the configured baud
+// rate, so programs which poll the status register see realistic timing. The
+// cpu clock used for this conversion can be set with set_cpu_hz.
+//
+// The W65C51N has a well known bug, where the transmit data register empty
+// bit is stuck at 1 and transmit interrupts never happen. Software written for
+// it has to delay between bytes instead. set_tx_bug enables the same behavior.
+use std::io::{Read, Write};
+use std::sync::mpsc::{channel, Receiver};
+use crate::memory_map::Device;
+
+// Register select (RS1-RS0) values.
+const REG_DATA: u16 = 0;
+const REG_STATUS: u16 = 1;  // writes perform a programmed reset
+const REG_COMMAND: u16 = 2;
+const REG_CONTROL: u16 = 3;
+
+// Status register bits.
+const STATUS_OVERRUN: u8 = 0x04;
+const STATUS_RDRF: u8 = 0x08;  // receive data register full
+const STATUS_TDRE: u8 = 0x10;  // transmit data register empty
+const STATUS_IRQ: u8 = 0x80;
+
+// Command register bits.
+const CMD_DTR: u8 = 0x01;      // enables the receiver and interrupts
+const CMD_IRD: u8 = 0x02;      // disables receive interrupts
+const CMD_TIC: u8 = 0x0C;      // transmitter control
+const CMD_TIC_IRQ: u8 = 0x04;  // TIC value enabling transmit interrupts
+const CMD_ECHO: u8 = 0x10;
+const CMD_PARITY_ENABLE: u8 = 0x20;
+
+// Baud rates selected by the low 4 bits of the control register.
+// 0 selects 16x an external clock, which is assumed to be the usual
+// 1.8432MHz crystal.
+const BAUD_RATES: [u32; 16] = [
+    115200, 50, 75, 110, 135, 150, 300, 600,
+    1200, 1800, 2400, 3600, 4800, 7200, 9600, 19200,
+];
+
+pub struct Acia {
+    input: Receiver<u8>,
+    output: Box<dyn Write>,
+
+    command: u8,
+    control: u8,
+    status: u8,
+    rx_data: u8,
+    // Byte being transmitted, and cycles until it has been sent.
+    tx_data: Option<u8>,
+    tx_cycles: u32,
+    // Cycles until the next byte may be received.
+    rx_cycles: u32,
+
+    cpu_hz: u32,
+    tx_bug: bool,
+    translate_newlines: bool,
+}
+
+impl Acia {
+    // Connect the ACIA to a host input and output. The input is read on a
+    // separate thread, so that a blocking source like stdin does not stall
+    // the emulator while no input is available.
+    pub fn new(mut input: impl Read + Send + 'static, output: impl Write + 'static) -> Acia {
+        let (send, recv) = channel();
+        std::thread::spawn(move || {
+            let mut byte = [0u8];
+            while let Ok(1) = input.read(&mut byte) {
+                if send.send(byte[0]).is_err() {
+                    break;
+                }
+            }
+        });
+        Acia::from_channel(recv, Box::new(output))
+    }
+
+    fn from_channel(input: Receiver<u8>, output: Box<dyn Write>) -> Acia {
+        Acia {
+            input,
+            output,
+            command: 0,
+            control: 0,
+            status: STATUS_TDRE,
+            rx_data: 0,
+            tx_data: None,
+            tx_cycles: 0,
+            rx_cycles: 0,
+            cpu_hz: 1_000_000,
+            tx_bug: false,
+            translate_newlines: false,
+        }
+    }
+
+    // Frequency of the cpu clock, used to convert baud rates into cycles.
+    pub fn set_cpu_hz(&mut self, hz: u32) {
+        self.cpu_hz = hz;
+    }
+
+    // Emulate the W65C51N transmit bug.
+    pub fn set_tx_bug(&mut self, enabled: bool) {
+        self.tx_bug = enabled;
+    }
+
+    // Convert between host newlines (LF) and the CR used by most 6502
+    // monitors. Received LFs become CR, and transmitted CRs become LF, with
+    // transmitted LFs dropped so that CR LF pairs produce a single newline.
+    pub fn set_translate_newlines(&mut self, enabled: bool) {
+        self.translate_newlines = enabled;
+    }
+
+    // Cycles taken to shift one character, including start, parity and stop bits.
+    fn byte_cycles(&self) -> u32 {
+        let data_bits = 8 - ((self.control >> 5) & 3) as u32;
+        let parity_bits = (self.command & CMD_PARITY_ENABLE != 0) as u32;
+        let stop_bits = if self.control & 0x80 != 0 { 2 } else { 1 };
+        let bits = 1 + data_bits + parity_bits + stop_bits;
+        let baud = BAUD_RATES[(self.control & 0x0F) as usize];
+        (self.cpu_hz as u64 * bits as u64 / baud as u64).max(1) as u32
+    }
+
+    fn receiver_enabled(&self) -> bool {
+        self.command & CMD_DTR != 0
+    }
+
+    fn send(&mut self, val: u8) {
+        let out: &[u8] = match val {
+            b'\r' if self.translate_newlines => b"\n",
+            b'\n' if self.translate_newlines => b"",
+            _ => &[val],
+        };
+        // A host that has gone away is the same as a disconnected serial line.
+        let _ = self.output.write_all(out);
+        let _ = self.output.flush();
+    }
+
+    fn interrupt(&mut self) {
+        self.status |= STATUS_IRQ;
+    }
+
+    fn tick_tx(&mut self) {
+        let Some(val) = self.tx_data else {
+            return;
+        };
+        self.tx_cycles = self.tx_cycles.saturating_sub(1);
+        if self.tx_cycles > 0 {
+            return;
+        }
+        self.tx_data = None;
+        self.send(val);
+        self.status |= STATUS_TDRE;
+        if !self.tx_bug && self.command & CMD_TIC == CMD_TIC_IRQ {
+            self.interrupt();
+        }
+    }
+
+    fn tick_rx(&mut self) {
+        if self.rx_cycles > 0 {
+            self.rx_cycles -= 1;
+            return;
+        }
+        if !self.receiver_enabled() {
+            return;
+        }
+        let Ok(mut val) = self.input.try_recv() else {
+            return;
+        };
+        self.rx_cycles = self.byte_cycles();
+        if self.translate_newlines && val == b'\n' {
+            val = b'\r';
+        }
+        if self.status & STATUS_RDRF != 0 {
+            // The previous byte hasn't been read yet, so this one is lost.
+            self.status |= STATUS_OVERRUN;
+        } else {
+            self.rx_data = val;
+            self.status |= STATUS_RDRF;
+        }
+        if self.command & CMD_IRD == 0 {
+            self.interrupt();
+        }
+        if self.command & CMD_ECHO != 0 && self.command & CMD_TIC == 0 {
+            self.send(val);
+        }
+    }
+}
+
+impl Device for Acia {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset & 3 {
+            REG_DATA => {
+                self.status &= !(STATUS_RDRF | STATUS_OVERRUN);
+                self.rx_data
+            },
+            REG_STATUS => {
+                let status = if self.tx_bug { self.status | STATUS_TDRE } else { self.status };
+                // Reading status acknowledges the interrupt.
+                self.status &= !STATUS_IRQ;
+                status
+            },
+            REG_COMMAND => self.command,
+            REG_CONTROL => self.control,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, offset: u16, val: u8) {
+        match offset & 3 {
+            REG_DATA => {
+                // On the W65C51N, writing during a transmission corrupts the
+                // byte in flight. Here the newer byte simply replaces it.
+                self.tx_data = Some(val);
+                self.tx_cycles = self.byte_cycles();
+                self.status &= !STATUS_TDRE;
+            },
+            REG_STATUS => {
+                // Programmed reset. Clears the low command bits and the
+                // overrun flag, leaving the control register alone.
+                self.command &= 0xE0;
+                self.status &= !STATUS_OVERRUN;
+            },
+            REG_COMMAND => self.command = val,
+            REG_CONTROL => self.control = val,
+            _ => unreachable!(),
+        }
+    }
+
+    fn tick(&mut self) {
+        self.tick_tx();
+        self.tick_rx();
+    }
+
+    fn irq(&self) -> bool {
+        self.status & STATUS_IRQ != 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Collects transmitted bytes where the test can see them.
+    #[derive(Clone)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_acia(input: &[u8]) -> (Acia, Rc<RefCell<Vec<u8>>>) {
+        let (send, recv) = channel();
+        for b in input {
+            send.send(*b).unwrap();
+        }
+        let output = SharedOutput(Rc::new(RefCell::new(Vec::new())));
+        let mut acia = Acia::from_channel(recv, Box::new(output.clone()));
+        // 19200 baud, 8N1 at 1MHz: 10 bits of 52 cycles.
+        acia.write(REG_CONTROL, 0x1F);
+        (acia, output.0)
+    }
+
+    #[test]
+    fn test_transmit() {
+        let (mut acia, output) = test_acia(&[]);
+        acia.write(REG_COMMAND, CMD_DTR | CMD_IRD | CMD_TIC_IRQ);
+        acia.write(REG_DATA, b'A');
+        assert_eq!(0, acia.read(REG_STATUS) & STATUS_TDRE);
+        for _ in 0 .. 520 {
+            acia.tick();
+        }
+        assert_eq!(b"A", &output.borrow()[..]);
+        assert!(acia.irq());
+        assert_eq!(STATUS_IRQ | STATUS_TDRE, acia.read(REG_STATUS));
+        assert!(!acia.irq());
+    }
+
+    #[test]
+    fn test_receive_and_overrun() {
+        let (mut acia, _) = test_acia(b"hi");
+        acia.write(REG_COMMAND, CMD_DTR);
+        acia.tick();
+        assert!(acia.irq());
+        assert_eq!(STATUS_IRQ | STATUS_TDRE | STATUS_RDRF, acia.read(REG_STATUS));
+        // The second byte arrives before the first is read.
+        for _ in 0 .. 521 {
+            acia.tick();
+        }
+        assert_eq!(STATUS_OVERRUN, acia.read(REG_STATUS) & STATUS_OVERRUN);
+        assert_eq!(b'h', acia.read(REG_DATA));
+        assert_eq!(0, acia.read(REG_STATUS) & (STATUS_RDRF | STATUS_OVERRUN));
+    }
+
+    #[test]
+    fn test_tx_bug() {
+        let (mut acia, output) = test_acia(&[]);
+        acia.set_tx_bug(true);
+        acia.set_translate_newlines(true);
+        acia.write(REG_COMMAND, CMD_DTR | CMD_IRD | CMD_TIC_IRQ);
+        acia.write(REG_DATA, b'\r');
+        // TDRE reads as empty even while the byte is being sent.
+        assert_eq!(STATUS_TDRE, acia.read(REG_STATUS) & STATUS_TDRE);
+        for _ in 0 .. 520 {
+            acia.tick();
+        }
+        assert!(!acia.irq());
+        assert_eq!(b"\n", &output.borrow()[..]);
+    }
+}
@@ -0,0 +1,429 @@
+// 65C02 Assembler
+// Builds 64K images from the assembly source kept alongside the traces, so the
+// source can be the only hand maintained artifact.
+//
+// The dialect is small:
+//   ; comments run to the end of the line
+//   org $300          ; continue output at an address
+//   .loop:            ; define a label, optionally followed by an instruction
+//   db $CA $FE, 12    ; bytes, separated by spaces or commas
+//   dw .nmi .reset    ; little endian words
+//   lda ($10),y       ; instructions, in any case, with the usual operand syntax
+// Numbers are hex with $ (or 0x), binary with %, and otherwise decimal. Operands
+// may add and subtract terms, and a leading < or > takes the low or high byte.
+// * is the address of the current line.
+//
+// Output starts at address 0, and unused bytes are 0. Operands that are numbers
+// below $100 use zero page addressing where it exists. Operands that use labels
+// get absolute addressing when there is a choice, so that instruction sizes
+// don't depend on where the labels end up.
+use std::collections::HashMap;
+use crate::opcodes::{self, Mode};
+
+const IMAGE_SIZE: usize = 0x10000;
+
+// Operand syntax, before choosing an addressing mode.
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(String),
+    Direct(String),             // v
+    DirectX(String),            // v,x
+    DirectY(String),            // v,y
+    Indirect(String),           // (v)
+    IndirectX(String),          // (v,x)
+    IndirectY(String),          // (v),y
+    ZeroPageRelative(String, String),
+}
+
+fn parse_operand(text: &str) -> Operand {
+    // Whitespace is not significant within an operand.
+    let text : String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let lower = text.to_ascii_lowercase();
+    let inner = |prefix_len: usize, suffix_len: usize| {
+        text[prefix_len .. text.len() - suffix_len].to_string()
+    };
+    if text.is_empty() {
+        Operand::None
+    } else if lower == "a" {
+        Operand::Accumulator
+    } else if let Some(value) = text.strip_prefix('#') {
+        Operand::Immediate(value.to_string())
+    } else if lower.starts_with('(') && lower.ends_with(",x)") {
+        Operand::IndirectX(inner(1, 3))
+    } else if lower.starts_with('(') && lower.ends_with("),y") {
+        Operand::IndirectY(inner(1, 3))
+    } else if lower.starts_with('(') && lower.ends_with(')') {
+        Operand::Indirect(inner(1, 1))
+    } else if lower.ends_with(",x") {
+        Operand::DirectX(inner(0, 2))
+    } else if lower.ends_with(",y") {
+        Operand::DirectY(inner(0, 2))
+    } else if let Some((zp, target)) = text.split_once(',') {
+        Operand::ZeroPageRelative(zp.to_string(), target.to_string())
+    } else {
+        Operand::Direct(text)
+    }
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix('$').or(text.strip_prefix("0x")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = text.strip_prefix('%') {
+        i64::from_str_radix(bin, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn is_label(text: &str) -> bool {
+    text.len() > 1 && text.starts_with('.')
+        && text[1..].chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// The result of evaluating an operand.
+#[derive(Clone, Copy)]
+struct Value {
+    value: i64,
+    // Whether any labels were used, which forces absolute addressing.
+    uses_label: bool,
+}
+
+impl Value {
+    fn fits_zero_page(&self) -> bool {
+        !self.uses_label && (0 ..= 0xFF).contains(&self.value)
+    }
+}
+
+struct Assembler {
+    labels: HashMap<String, u16>,
+    // Labels are collected in the first pass, and output written in the second.
+    final_pass: bool,
+    image: Vec<u8>,
+    written: Vec<bool>,
+    // Address of the next byte of output.
+    pc: u32,
+    // Address of the start of the current line.
+    line_pc: u16,
+}
+
+impl Assembler {
+    fn eval(&self, expr: &str) -> Result<Value, String> {
+        if expr.is_empty() {
+            return Err("Missing operand".to_string());
+        }
+        if let Some(rest) = expr.strip_prefix('<') {
+            let v = self.eval(rest)?;
+            return Ok(Value { value: v.value & 0xFF, ..v });
+        }
+        if let Some(rest) = expr.strip_prefix('>') {
+            let v = self.eval(rest)?;
+            return Ok(Value { value: (v.value >> 8) & 0xFF, ..v });
+        }
+
+        // A sum of terms, each optionally negated.
+        let mut result = Value { value: 0, uses_label: false };
+        let mut rest = expr;
+        let mut negate = false;
+        if let Some(r) = rest.strip_prefix('-') {
+            negate = true;
+            rest = r;
+        }
+        loop {
+            let end = rest.find(['+', '-']).unwrap_or(rest.len());
+            let term = &rest[.. end];
+            let value = if term == "*" {
+                self.line_pc as i64
+            } else if is_label(term) {
+                result.uses_label = true;
+                match self.labels.get(term) {
+                    Some(addr) => *addr as i64,
+                    // Unknown in the first pass, sizes don't depend on it.
+                    None if !self.final_pass => 0,
+                    None => return Err(format!("Unknown label: '{term}'")),
+                }
+            } else {
+                parse_number(term).ok_or(format!("Bad number: '{term}'"))?
+            };
+            result.value += if negate { -value } else { value };
+            if end == rest.len() {
+                break;
+            }
+            negate = rest[end ..].starts_with('-');
+            rest = &rest[end + 1 ..];
+        }
+        Ok(result)
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), String> {
+        if self.pc as usize >= IMAGE_SIZE {
+            return Err("Output past $FFFF".to_string());
+        }
+        if self.final_pass {
+            let addr = self.pc as usize;
+            if self.written[addr] {
+                return Err(format!("Output overlaps earlier output at ${addr:04X}"));
+            }
+            self.image[addr] = byte;
+            self.written[addr] = true;
+        }
+        self.pc += 1;
+        Ok(())
+    }
+
+    // Range checks only apply in the final pass, when labels are known.
+    fn byte(&self, v: Value) -> Result<u8, String> {
+        if self.final_pass && !(-0x80 ..= 0xFF).contains(&v.value) {
+            return Err(format!("Value out of range for a byte: ${:X}", v.value));
+        }
+        Ok(v.value as u8)
+    }
+    fn word(&self, v: Value) -> Result<u16, String> {
+        if self.final_pass && !(0 ..= 0xFFFF).contains(&v.value) {
+            return Err(format!("Value out of range for a word: ${:X}", v.value));
+        }
+        Ok(v.value as u16)
+    }
+    // Branch offset to target, from the end of an instruction of `size` bytes.
+    fn branch(&self, target: Value, size: u16) -> Result<u8, String> {
+        let offset = target.value - (self.line_pc as i64 + size as i64);
+        if self.final_pass && !(-0x80 ..= 0x7F).contains(&offset) {
+            return Err(format!("Branch out of range, by {offset}"));
+        }
+        Ok(offset as u8)
+    }
+
+    fn define_label(&mut self, label: &str) -> Result<(), String> {
+        if !is_label(label) {
+            return Err(format!("Bad label: '{label}'"));
+        }
+        if self.final_pass {
+            return Ok(());
+        }
+        if self.pc as usize >= IMAGE_SIZE {
+            return Err(format!("Label past $FFFF: '{label}'"));
+        }
+        if self.labels.insert(label.to_string(), self.pc as u16).is_some() {
+            return Err(format!("Duplicate label: '{label}'"));
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        let line = line.split(';').next().unwrap().trim();
+        self.line_pc = self.pc as u16;
+        let mut rest = line;
+        if line.starts_with('.') {
+            if let Some((label, after)) = line.split_once(':') {
+                self.define_label(label.trim())?;
+                rest = after.trim();
+            }
+        }
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        let (word, operand) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let operand = operand.trim();
+        match word.to_ascii_lowercase().as_str() {
+            "org" => {
+                let v = self.eval(operand)?;
+                if v.uses_label || !(0 ..= 0xFFFF).contains(&v.value) {
+                    return Err(format!("Bad org: '{operand}'"));
+                }
+                self.pc = v.value as u32;
+            },
+            "db" => {
+                for item in operand.split([' ', '\t', ',']).filter(|i| !i.is_empty()) {
+                    let v = self.eval(item)?;
+                    self.emit(self.byte(v)?)?;
+                }
+            },
+            "dw" => {
+                for item in operand.split([' ', '\t', ',']).filter(|i| !i.is_empty()) {
+                    let v = self.word(self.eval(item)?)?;
+                    self.emit(v as u8)?;
+                    self.emit((v >> 8) as u8)?;
+                }
+            },
+            mnemonic => self.instruction(mnemonic, operand)?,
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operand: &str) -> Result<(), String> {
+        use Mode::*;
+        let operand = parse_operand(operand);
+        // Candidate modes for the operand syntax, in order of preference.
+        let (modes, value) : (&[Mode], Option<Value>) = match &operand {
+            Operand::None => (&[Implied, Accumulator], None),
+            Operand::Accumulator => (&[Accumulator], None),
+            Operand::Immediate(e) => (&[Immediate], Some(self.eval(e)?)),
+            Operand::Direct(e) => {
+                let v = self.eval(e)?;
+                if v.fits_zero_page() {
+                    (&[Relative, ZeroPage, Absolute], Some(v))
+                } else {
+                    (&[Relative, Absolute, ZeroPage], Some(v))
+                }
+            },
+            Operand::DirectX(e) => {
+                let v = self.eval(e)?;
+                if v.fits_zero_page() {
+                    (&[ZeroPageX, AbsoluteX], Some(v))
+                } else {
+                    (&[AbsoluteX, ZeroPageX], Some(v))
+                }
+            },
+            Operand::DirectY(e) => {
+                let v = self.eval(e)?;
+                if v.fits_zero_page() {
+                    (&[ZeroPageY, AbsoluteY], Some(v))
+                } else {
+                    (&[AbsoluteY, ZeroPageY], Some(v))
+                }
+            },
+            Operand::Indirect(e) => {
+                let v = self.eval(e)?;
+                if v.fits_zero_page() {
+                    (&[ZeroPageIndirect, Indirect], Some(v))
+                } else {
+                    (&[Indirect, ZeroPageIndirect], Some(v))
+                }
+            },
+            Operand::IndirectX(e) => {
+                let v = self.eval(e)?;
+                if v.fits_zero_page() {
+                    (&[IndirectX, AbsoluteIndirectX], Some(v))
+                } else {
+                    (&[AbsoluteIndirectX, IndirectX], Some(v))
+                }
+            },
+            Operand::IndirectY(e) => (&[IndirectY], Some(self.eval(e)?)),
+            Operand::ZeroPageRelative(zp, _) => (&[ZeroPageRelative], Some(self.eval(zp)?)),
+        };
+
+        let Some((opcode, mode)) = modes.iter()
+            .find_map(|mode| Some((opcodes::encode(mnemonic, *mode)?, *mode))) else {
+            let known = opcodes::OPCODES.iter()
+                .any(|op| op.documented && op.mnemonic.eq_ignore_ascii_case(mnemonic));
+            return Err(if known {
+                format!("Addressing mode not supported by {mnemonic}")
+            } else {
+                format!("Unknown instruction: '{mnemonic}'")
+            });
+        };
+
+        self.emit(opcode)?;
+        let v = value.unwrap_or(Value { value: 0, uses_label: false });
+        match mode {
+            Implied | Accumulator => (),
+            Relative => self.emit(self.branch(v, mode.size())?)?,
+            ZeroPageRelative => {
+                let Operand::ZeroPageRelative(_, target) = &operand else { unreachable!() };
+                let target = self.eval(target)?;
+                self.emit(self.byte(v)?)?;
+                self.emit(self.branch(target, mode.size())?)?;
+            },
+            _ if mode.size() == 2 => self.emit(self.byte(v)?)?,
+            _ => {
+                let w = self.word(v)?;
+                self.emit(w as u8)?;
+                self.emit((w >> 8) as u8)?;
+            },
+        }
+        Ok(())
+    }
+
+    fn pass(&mut self, source: &str, final_pass: bool) -> Result<(), String> {
+        self.final_pass = final_pass;
+        self.pc = 0;
+        for (num, line) in source.lines().enumerate() {
+            self.line(line).map_err(|e| format!("line {}: {e}", num + 1))?;
+        }
+        Ok(())
+    }
+}
+
+// Assemble source into a 64K image.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    Ok(assemble_with_labels(source)?.0)
+}
+
+// As assemble, also returning the address of each label.
+pub fn assemble_with_labels(source: &str) -> Result<(Vec<u8>, HashMap<String, u16>), String> {
+    let mut asm = Assembler {
+        labels: HashMap::new(),
+        final_pass: false,
+        image: vec![0; IMAGE_SIZE],
+        written: vec![false; IMAGE_SIZE],
+        pc: 0,
+        line_pc: 0,
+    };
+    asm.pass(source, false)?;
+    asm.pass(source, true)?;
+    Ok((asm.image, asm.labels))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trace_programs() {
+        // The checked in binaries were built from this source.
+        for name in ["nop_jmp_loop", "load_store_regs_basic"] {
+            let source = std::fs::read_to_string(format!("passing_traces/{name}.asm")).unwrap();
+            let bin = std::fs::read(format!("passing_traces/{name}.bin")).unwrap();
+            assert!(assemble(&source).unwrap() == bin, "{name} differs");
+        }
+    }
+
+    #[test]
+    fn test_modes() {
+        let image = assemble("
+            org $200
+            .start: lda ($12),y
+            jmp (.start,x)
+            sta .data,x
+            lda $1234,y
+            ldx $12,y
+            asl
+            inc a
+            bbr3 $12,.start
+            bne *
+            lda #<.data
+            lda #>.data+1
+            .data: dw .start $BEEF
+            db %101, 10").unwrap();
+        assert_eq!(&[
+            0xB1, 0x12,
+            0x7C, 0x00, 0x02,
+            0x9D, 0x18, 0x02,
+            0xB9, 0x34, 0x12,
+            0xB6, 0x12,
+            0x0A,
+            0x1A,
+            0x3F, 0x12, 0xEE,
+            0xD0, 0xFE,
+            0xA9, 0x18,
+            0xA9, 0x02,
+            0x00, 0x02, 0xEF, 0xBE,
+            0x05, 0x0A,
+        ], &image[0x200 .. 0x21E]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Err("line 2: Unknown label: '.nowhere'".to_string()),
+                   assemble("nop\njmp .nowhere"));
+        assert_eq!(Err("line 1: Addressing mode not supported by stx".to_string()),
+                   assemble("stx $1234,x"));
+        assert_eq!(Err("line 1: Unknown instruction: 'foo'".to_string()),
+                   assemble("foo"));
+        assert_eq!(Err("line 2: Branch out of range, by 200".to_string()),
+                   assemble("org $10\nbne $DA"));
+        assert_eq!(Err("line 3: Output overlaps earlier output at $0000".to_string()),
+                   assemble("nop\norg 0\nnop"));
+    }
+}
@@ -0,0 +1,30 @@
+// Bus
+// The environment the cpu runs in: memory and devices attached to its
+// address and data pins.
+//
+// The model itself only deals in pins (see Inputs and Outputs). A Bus lets
+// a caller describe the rest of the system in terms of reads and writes,
+// and W6502::cycle_bus takes care of driving the pins from it.
+
+pub trait Bus {
+    // Return the value placed on the data bus when the cpu reads addr.
+    fn read(&mut self, addr: u16) -> u8;
+    // Called when the cpu writes val to addr.
+    fn write(&mut self, addr: u16, val: u8);
+    // Return the value at addr without any side effects a read might have,
+    // for debugging and reporting. The cpu never peeks.
+    fn peek(&self, addr: u16) -> u8;
+    // Called once at the end of every cpu cycle, for anything on the bus
+    // that keeps time with the cpu clock.
+    fn tick(&mut self) {}
+    // True while something on the bus is pulling the irq line low.
+    fn irq(&self) -> bool {
+        false
+    }
+    // True if addr is plain memory, which holds what was last written to it.
+    // Rewinding only undoes writes to memory, as writing to a device's
+    // registers would do something rather than restore them.
+    fn is_memory(&self, _addr: u16) -> bool {
+        true
+    }
+}
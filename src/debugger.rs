@@ -0,0 +1,537 @@
+// Debugger
+// A monitor style prompt for running a program on the model: stepping by cycle
+// or instruction, breakpoints on pc, watchpoints on memory, and showing and
+// editing registers and memory. See HELP for the commands.
+//
+// The cpu is always run a cycle at a time, so that watchpoints see every
+// access, dummy reads and writes included, and every cycle can be stepped
+// back to. Edits aren't part of that history, so stepping back stops at the
+// last edit.
+//
+// Addresses can be given as symbols, see set_symbols, which are also used to
+// label disassembly.
+use std::io::{BufRead, Write};
+use crate::bus::Bus;
+use crate::disasm::disassemble_variant;
+use crate::runner::{Runner, Stop};
+use crate::symbols::Symbols;
+use crate::CpuVariant;
+
+const HELP: &str = "\
+step [N]            s  run N instructions (default 1)
+cycle [N]              run N cycles (default 1)
+continue [N]        c  run until a breakpoint, watchpoint or stop, or for N cycles
+back [N]               step back N instructions (default 1)
+break ADDR          b  stop before the instruction at ADDR, which may be a symbol
+delete ADDR            remove the breakpoint at ADDR
+watch RANGE [r|w]   w  stop when RANGE is read (r), written (w) or either (default)
+unwatch RANGE          remove the watchpoint on RANGE
+list                l  list breakpoints and watchpoints
+regs                r  show the registers and flags
+set REG VAL            set PC, A, X, Y, SP or P, between instructions
+mem RANGE           m  show memory
+poke ADDR BYTE...      write bytes to memory, starting at ADDR
+dis [ADDR] [N]      d  disassemble N instructions around pc, or from ADDR
+reset                  reset the cpu
+help                ?  show this
+quit                q  leave the debugger
+Addresses and values are hex, and a RANGE is START-END or a single address.
+Addresses can also be symbols, such as .loop or .loop+2.
+An empty line repeats the last command.";
+
+// History kept for stepping back: 100K cycles.
+const REWIND_INTERVAL: u64 = 1000;
+const REWIND_SNAPSHOTS: usize = 101;
+
+// Instructions shown either side of pc by dis.
+const DIS_BEFORE: usize = 3;
+const DIS_LINES: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Watch {
+    start: u16,
+    end: u16,
+    read: bool,
+    write: bool,
+}
+
+// An access that hit a watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Hit {
+    addr: u16,
+    val: u8,
+    write: bool,
+}
+
+// Passes accesses through to a bus, noting the first to hit a watchpoint.
+struct Watched<B: Bus> {
+    bus: B,
+    watches: Vec<Watch>,
+    hit: Option<Hit>,
+}
+
+impl<B: Bus> Watched<B> {
+    fn check(&mut self, addr: u16, val: u8, write: bool) {
+        let watched = self.watches.iter().any(|watch| {
+            (watch.start ..= watch.end).contains(&addr) && if write { watch.write } else { watch.read }
+        });
+        if watched && self.hit.is_none() {
+            self.hit = Some(Hit { addr, val, write });
+        }
+    }
+}
+
+impl<B: Bus> Bus for Watched<B> {
+    fn read(&mut self, addr: u16) -> u8 {
+        let val = self.bus.read(addr);
+        self.check(addr, val, false);
+        val
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        self.check(addr, val, true);
+        self.bus.write(addr, val);
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+    fn tick(&mut self) {
+        self.bus.tick();
+    }
+    fn irq(&self) -> bool {
+        self.bus.irq()
+    }
+    fn is_memory(&self, addr: u16) -> bool {
+        self.bus.is_memory(addr)
+    }
+}
+
+// Print memory as hex, 16 bytes per line.
+pub fn hexdump(bus: &impl Bus, start: u16, end: u16) -> String {
+    let mut lines = Vec::new();
+    let mut addr = start as u32;
+    while addr <= end as u32 {
+        let line_end = (addr + 15).min(end as u32);
+        let bytes : Vec<String> = (addr ..= line_end)
+            .map(|a| format!("{:02X}", bus.peek(a as u16)))
+            .collect();
+        lines.push(format!("${addr:04X}: {}", bytes.join(" ")));
+        addr = line_end + 1;
+    }
+    lines.join("\n")
+}
+
+fn parse_hex(s: &str) -> Result<u16, String> {
+    let digits = s.strip_prefix('$')
+        .or(s.strip_prefix("0x"))
+        .unwrap_or(s);
+    u16::from_str_radix(digits, 16).or(Err(format!("Bad hex value: '{s}'")))
+}
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+    u8::try_from(parse_hex(s)?).or(Err(format!("Bad byte: '{s}'")))
+}
+
+// A decimal count, or 1 if there isn't one.
+fn parse_count(arg: Option<&&str>) -> Result<u64, String> {
+    arg.map_or(Ok(1), |n| n.parse().or(Err(format!("Bad count: '{n}'"))))
+}
+
+pub struct Debugger<B: Bus> {
+    runner: Runner<Watched<B>>,
+    symbols: Symbols,
+}
+
+impl<B: Bus> Debugger<B> {
+    // Start with the cpu reset, and about to read the reset vector.
+    pub fn new(bus: B, variant: CpuVariant) -> Debugger<B> {
+        let watched = Watched { bus, watches: Vec::new(), hit: None };
+        let mut runner = Runner::with_variant(watched, variant);
+        runner.enable_rewind(REWIND_INTERVAL, REWIND_SNAPSHOTS);
+        Debugger { runner, symbols: Symbols::new() }
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.runner.add_trap(addr);
+    }
+
+    pub fn runner(&self) -> &Runner<impl Bus> {
+        &self.runner
+    }
+
+    pub fn bus(&self) -> &B {
+        &self.runner.bus().bus
+    }
+
+    // Prompt for and run commands until quit, or the end of input.
+    pub fn repl(&mut self, input: &mut impl BufRead, output: &mut impl Write) -> std::io::Result<()> {
+        writeln!(output, "{}", self.status())?;
+        let mut last = String::new();
+        loop {
+            write!(output, "6502> ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = match line.trim() {
+                "" => last.clone(),
+                line => line.to_string(),
+            };
+            if matches!(line.as_str(), "q" | "quit") {
+                return Ok(());
+            }
+            match self.command(&line) {
+                Ok(text) if text.is_empty() => (),
+                Ok(text) => writeln!(output, "{text}")?,
+                Err(e) => writeln!(output, "error: {e}")?,
+            }
+            last = line;
+        }
+    }
+
+    // Run a command, returning what to show.
+    pub fn command(&mut self, line: &str) -> Result<String, String> {
+        let words : Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Ok(String::new());
+        };
+        match (command, args) {
+            ("step" | "s", [] | [_]) => Ok(self.go(None, Some(parse_count(args.first())?))),
+            ("cycle", [] | [_]) => Ok(self.go(Some(parse_count(args.first())?), None)),
+            ("continue" | "c", []) => Ok(self.go(None, None)),
+            ("continue" | "c", [_]) => Ok(self.go(Some(parse_count(args.first())?), None)),
+            ("back", [] | [_]) => {
+                let count = parse_count(args.first())?;
+                let result = (0 .. count).try_for_each(|_| self.runner.step_back_instruction());
+                // Undoing writes isn't the program accessing memory.
+                self.runner.bus_mut().hit = None;
+                result.map(|_| self.status())
+            },
+            ("break" | "b", [addr]) => {
+                let addr = self.symbols.resolve(addr)?;
+                if !self.runner.traps().contains(&addr) {
+                    self.runner.add_trap(addr);
+                }
+                Ok(String::new())
+            },
+            ("delete", [addr]) => {
+                let addr = self.symbols.resolve(addr)?;
+                match self.runner.remove_trap(addr) {
+                    true => Ok(String::new()),
+                    false => Err(format!("No breakpoint at ${addr:04X}")),
+                }
+            },
+            ("watch" | "w", [range] | [range, _]) => {
+                let (start, end) = self.range(range)?;
+                let (read, write) = match args.get(1) {
+                    None => (true, true),
+                    Some(&"r") => (true, false),
+                    Some(&"w") => (false, true),
+                    Some(kind) => return Err(format!("Bad watch kind: '{kind}', expected r or w")),
+                };
+                self.runner.bus_mut().watches.push(Watch { start, end, read, write });
+                Ok(String::new())
+            },
+            ("unwatch", [range]) => {
+                let (start, end) = self.range(range)?;
+                let watches = &mut self.runner.bus_mut().watches;
+                let len = watches.len();
+                watches.retain(|watch| (watch.start, watch.end) != (start, end));
+                match watches.len() != len {
+                    true => Ok(String::new()),
+                    false => Err(format!("No watchpoint on {range}")),
+                }
+            },
+            ("list" | "l", []) => Ok(self.list()),
+            ("regs" | "r", []) => Ok(self.status()),
+            ("set", [reg, val]) => {
+                self.set(reg, parse_hex(val)?)?;
+                Ok(self.status())
+            },
+            ("mem" | "m", [range]) => {
+                let (start, end) = match self.range(range)? {
+                    (start, end) if start == end => (start, start.saturating_add(0xF)),
+                    range => range,
+                };
+                Ok(hexdump(self.bus(), start, end))
+            },
+            ("poke", [addr, bytes @ ..]) if !bytes.is_empty() => {
+                let addr = self.symbols.resolve(addr)?;
+                let bytes = bytes.iter().map(|b| parse_byte(b)).collect::<Result<Vec<u8>, String>>()?;
+                for (i, val) in bytes.into_iter().enumerate() {
+                    // Straight to the bus, so watchpoints don't see it.
+                    self.runner.bus_mut().bus.write(addr.wrapping_add(i as u16), val);
+                }
+                self.edited();
+                Ok(String::new())
+            },
+            ("dis" | "d", []) => Ok(self.dis(None, DIS_LINES)),
+            ("dis" | "d", [addr] | [addr, _]) => {
+                let n = parse_count(args.get(1))? as usize;
+                Ok(self.dis(Some(self.symbols.resolve(addr)?), if args.len() > 1 { n } else { DIS_LINES }))
+            },
+            ("reset", []) => {
+                self.runner.reset();
+                Ok(self.status())
+            },
+            ("help" | "?", []) => Ok(HELP.to_string()),
+            _ => Err(format!("Bad command: '{line}', try help")),
+        }
+    }
+
+    // Run a cycle at a time until something stops it, or for the given
+    // number of cycles or instructions, then describe where it stopped.
+    fn go(&mut self, mut cycles: Option<u64>, mut instructions: Option<u64>) -> String {
+        let reason = loop {
+            if cycles == Some(0) || instructions == Some(0) {
+                break None;
+            }
+            if self.runner.cpu().stopped() {
+                break Some("the cpu is stopped, reset to continue".to_string());
+            }
+            let stop = self.runner.step_cycle();
+            if let Some(hit) = self.runner.bus_mut().hit.take() {
+                let access = if hit.write { "write" } else { "read" };
+                break Some(format!("watchpoint: {access} ${:04X} = {:02X}", hit.addr, hit.val));
+            }
+            match stop {
+                // Stepping onto an instruction stops anyway.
+                Some(Stop::Trap(_) | Stop::Brk(_) | Stop::Stp(_) | Stop::Jam(_))
+                    if instructions == Some(1) => (),
+                Some(stop) => break Some(stop.to_string()),
+                None => (),
+            }
+            cycles = cycles.map(|n| n - 1);
+            let cpu = self.runner.cpu();
+            if cpu.outputs().sync || cpu.halted() {
+                instructions = instructions.map(|n| n - 1);
+            }
+        };
+        match reason {
+            Some(reason) => format!("stopped: {reason}\n{}", self.status()),
+            None => self.status(),
+        }
+    }
+
+    // Where the cpu is: the registers, and the instruction about to be
+    // fetched, or the one part way through.
+    fn status(&self) -> String {
+        let cpu = self.runner.cpu();
+        let at = if cpu.resetting() {
+            "in reset".to_string()
+        } else if cpu.outputs().sync {
+            format!("next {}", self.describe(cpu.outputs().address).0)
+        } else {
+            format!("in {}", self.describe(cpu.instruction().0).0)
+        };
+        format!("cycle {}: {}\n{at}", self.runner.cycles(), cpu.registers())
+    }
+
+    // START-END or a single address, as an inclusive range.
+    fn range(&self, s: &str) -> Result<(u16, u16), String> {
+        // A symbol may have a negative offset, such as .loop-1.
+        let (start, end) = match (self.symbols.resolve(s), s.split_once('-')) {
+            (Ok(addr), _) => (addr, addr),
+            (Err(_), Some((start, end))) => (self.symbols.resolve(start)?, self.symbols.resolve(end)?),
+            (Err(e), None) => return Err(e),
+        };
+        if start > end {
+            return Err(format!("Bad range: '{s}'"));
+        }
+        Ok((start, end))
+    }
+
+    fn location(&self, addr: u16) -> String {
+        match self.symbols.label(addr) {
+            Some(label) => format!("${addr:04X} {label}"),
+            None => format!("${addr:04X}"),
+        }
+    }
+
+    // Disassemble the instruction at addr, returning its text and size.
+    fn describe(&self, addr: u16) -> (String, u16) {
+        let bytes : Vec<u8> = (0 .. 3).map(|i| self.bus().peek(addr.wrapping_add(i))).collect();
+        // There are always enough bytes for an instruction.
+        let instruction = disassemble_variant(self.runner.cpu().variant(), &bytes, addr).unwrap();
+        (format!("{}: {}", self.location(addr), instruction.symbolic(&self.symbols)), instruction.size)
+    }
+
+    // The start of the n instructions that end at addr, if there is one. Code
+    // can't be disassembled backwards reliably, so this tries each start in
+    // turn, nearest first.
+    fn preceding(&self, addr: u16, n: usize) -> Option<u16> {
+        (1 ..= 3 * n as u16).map(|back| addr.wrapping_sub(back)).find(|&start| {
+            let (mut pc, mut count) = (start, 0);
+            while count < n && pc != addr {
+                pc = pc.wrapping_add(self.describe(pc).1);
+                count += 1;
+            }
+            pc == addr && count == n
+        })
+    }
+
+    fn dis(&self, addr: Option<u16>, lines: usize) -> String {
+        let cpu = self.runner.cpu();
+        let current = if cpu.outputs().sync { cpu.outputs().address } else { cpu.instruction().0 };
+        let mut pc = addr.unwrap_or_else(|| {
+            (1 ..= DIS_BEFORE).rev().find_map(|n| self.preceding(current, n)).unwrap_or(current)
+        });
+        let mut text = Vec::new();
+        for _ in 0 .. lines {
+            let (line, size) = self.describe(pc);
+            text.push(format!("{} {line}", if pc == current { ">" } else { " " }));
+            pc = pc.wrapping_add(size);
+        }
+        text.join("\n")
+    }
+
+    fn list(&self) -> String {
+        let mut lines : Vec<String> = self.runner.traps().iter()
+            .map(|&addr| format!("break {}", self.location(addr)))
+            .collect();
+        for watch in &self.runner.bus().watches {
+            let kind = match (watch.read, watch.write) {
+                (true, true) => "rw",
+                (true, false) => "r",
+                _ => "w",
+            };
+            lines.push(format!("watch {}-{} {kind}", self.location(watch.start), self.location(watch.end)));
+        }
+        lines.join("\n")
+    }
+
+    fn set(&mut self, reg: &str, val: u16) -> Result<(), String> {
+        if !self.runner.cpu().outputs().sync {
+            return Err("Registers can only be set between instructions, try step".to_string());
+        }
+        let mut registers = self.runner.cpu().registers();
+        let byte = || u8::try_from(val).or(Err(format!("{val:X} doesn't fit in {reg}")));
+        match reg.to_ascii_lowercase().as_str() {
+            "pc" => registers.pc = val,
+            "a" => registers.acc = byte()?,
+            "x" => registers.x = byte()?,
+            "y" => registers.y = byte()?,
+            "sp" => registers.sp = byte()?,
+            "p" => registers.flags = byte()?,
+            _ => return Err(format!("Unknown register: '{reg}'")),
+        }
+        self.runner.cpu_mut().set_registers(&registers);
+        self.edited();
+        Ok(())
+    }
+
+    // Start the history again, as it can't replay edits.
+    fn edited(&mut self) {
+        self.runner.enable_rewind(REWIND_INTERVAL, REWIND_SNAPSHOTS);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::memory_map::MemoryMap;
+
+    fn debugger() -> Debugger<MemoryMap> {
+        let image = assemble("
+            org $0200
+            .start:
+              ldx #$03
+            .loop:
+              stx $10
+              dex
+              bne .loop
+              stp
+            org $FFFC
+              dw .start
+        ").unwrap();
+        Debugger::new(MemoryMap::from_image(&image).unwrap(), CpuVariant::W65C02S)
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut d = debugger();
+        let mut symbols = Symbols::new();
+        symbols.insert(".loop", 0x0202);
+        symbols.insert(".count", 0x0010);
+        d.set_symbols(symbols);
+        d.command("break .loop+2").unwrap();
+        assert_eq!("break $0204 .loop+2", d.command("list").unwrap());
+        let stop = d.command("c").unwrap();
+        assert!(stop.ends_with("next $0204 .loop+2: DEX"), "{stop}");
+        assert!(d.command("dis").unwrap().contains("  $0205 .loop+3: BNE .loop\n"));
+        assert_eq!("$0010: 03", d.command("mem .count").unwrap().get(.. 9).unwrap());
+        assert!(d.command("break .nowhere").is_err());
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut d = debugger();
+        let mut run = |line: &str| d.command(line).unwrap();
+        assert!(run("regs").ends_with("in reset"));
+        assert!(run("step").ends_with("next $0200: LDX #$03"));
+        run("break 0204");
+        assert!(run("c").starts_with("stopped: trap at $0204"));
+        assert!(run("regs").contains("X=03"));
+
+        run("watch 10 w");
+        assert_eq!("break $0204\nwatch $0010-$0010 w", run("list"));
+        let hit = run("c");
+        assert!(hit.starts_with("stopped: watchpoint: write $0010 = 02"), "{hit}");
+        assert!(hit.ends_with("in $0202: STX $10"), "{hit}");
+        assert!(run("mem 10").starts_with("$0010: 02 00"));
+        run("step");
+        assert!(run("mem 10-11").starts_with("$0010: 02 00"));
+
+        run("poke 11 55 66");
+        assert_eq!("$0010: 02 55 66", run("mem 10-12"));
+        assert!(run("set a 42").contains("A=42"));
+        assert_eq!("  $0200: LDX #$03\n  $0202: STX $10\n> $0204: DEX", run("dis 200 3"));
+        assert!(run("dis").contains("  $0202: STX $10\n> $0204: DEX\n  $0205: BNE $0202"));
+
+        run("delete 0204");
+        run("unwatch 10");
+        assert!(run("c").starts_with("stopped: stp at $0207"));
+        assert!(run("back").ends_with("next $0205: BNE $0202"));
+        // undoing the writes to $10 doesn't hit the watchpoint.
+        run("watch 10 w");
+        assert!(run("back 2").ends_with("next $0202: STX $10"));
+        let cycle = run("cycle");
+        assert!(!cycle.contains("watchpoint") && cycle.ends_with("in $0202: STX $10"), "{cycle}");
+        let hit = run("cycle 2");
+        assert!(hit.starts_with("stopped: watchpoint: write $0010 = 01"), "{hit}");
+        run("unwatch 10");
+        assert!(run("c").starts_with("stopped: stp at $0207"));
+        run("step");
+        assert!(run("step").contains("stopped: the cpu is stopped"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut d = debugger();
+        assert!(d.command("frobnicate").is_err());
+        assert!(d.command("step x").is_err());
+        assert!(d.command("delete 1234").is_err());
+        assert!(d.command("watch 10 q").is_err());
+        assert!(d.command("set a 100").is_err());
+        // During reset, not at an instruction boundary.
+        assert!(d.command("set pc 1234").is_err());
+        assert!(d.command("back").is_err());
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut d = debugger();
+        let mut input = "step\n\nbogus\nq\nstep\n".as_bytes();
+        let mut output = Vec::new();
+        d.repl(&mut input, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        // The empty line repeats the step, and quit stops before the last.
+        assert!(output.contains("next $0202: STX $10\n6502> error: Bad command"), "{output}");
+        assert_eq!(0x0202, d.runner().cpu().registers().pc);
+    }
+}
@@ -0,0 +1,545 @@
+// Instruction Decoder
+// Every opcode is decoded from its entry in the opcode table, by combining
+// two parts:
+// - the addressing mode, which gives the uops that read the operand bytes
+//   and work out the effective address, and
+// - the mnemonic, which gives what is done there: a load or ALU operation,
+//   a store, a read-modify-write, or one of the instructions with their own
+//   sequences (branches, jumps, the stack and interrupts).
+//
+// Bus cycles follow the W65C02S datasheet. Where the 65C02 spends an extra
+// cycle that the table's cycle count doesn't include (page crossings, taken
+// branches and decimal mode), the uop is queued with a condition, and skipped
+// when the condition doesn't hold.
+//
+// Each variant has its own opcode table and programs. The NMOS 6502 differs in
+// its dummy cycles: it reads addresses before they are fixed up rather than
+// re-reading an operand, and writes back the unmodified value in
+// read-modify-write instructions. It also keeps the jmp (abs) page wrap bug.
+use std::sync::OnceLock;
+use crate::opcodes::{CpuVariant, Mode};
+use crate::operations::{Condition, Operation, FLAG_C, FLAG_D, FLAG_I, FLAG_N, FLAG_V, FLAG_Z};
+use crate::{Register, Source, UOp, When, IRQ_VECTOR, NMI_VECTOR};
+
+// What an instruction does, once its operand is found.
+pub(crate) enum Behavior {
+    // Read the operand and apply the operation to it.
+    Read(Operation),
+    // Write a register to the operand.
+    Write(Register),
+    // The unstable NMOS stores, see W6502::store_high.
+    StoreHigh(Register),
+    // Read the operand, apply the operation, and write it back. Also gives
+    // when indexed modes take the extra cycle.
+    Modify(Operation, When),
+    // Apply the operation to a register, during a dummy read.
+    Implied(Operation, Register),
+    Branch(Condition),
+    // bbr and bbs, which test a bit of a zero page byte.
+    BranchBit(Condition),
+    Push(Register),
+    Pull(Register),
+    Jmp,
+    Jsr,
+    Rts,
+    Rti,
+    Brk,
+    Wai,
+    Stp,
+    Nop,
+}
+
+// The bit number at the end of rmb, smb, bbr and bbs mnemonics.
+fn bit(mnemonic: &str) -> u8 {
+    mnemonic.as_bytes()[3] - b'0'
+}
+
+pub(crate) fn behavior(mnemonic: &str, mode: Mode, variant: CpuVariant) -> Behavior {
+    use Behavior::*;
+    use Register::{Acc, Flags, Sp, X, Y};
+    // The 65C02 saves a cycle on shifts and rotates that don't cross a page.
+    let shift_penalty = if variant.is_nmos() { When::Always } else { When::PageCrossed };
+    match mnemonic {
+        "LDA" => Read(Operation::Load(Acc)),
+        "LDX" => Read(Operation::Load(X)),
+        "LDY" => Read(Operation::Load(Y)),
+        "ORA" => Read(Operation::Ora),
+        "AND" => Read(Operation::And),
+        "EOR" => Read(Operation::Eor),
+        "ADC" => Read(Operation::Adc),
+        "SBC" => Read(Operation::Sbc),
+        "CMP" => Read(Operation::Compare(Acc)),
+        "CPX" => Read(Operation::Compare(X)),
+        "CPY" => Read(Operation::Compare(Y)),
+        "BIT" if mode == Mode::Immediate => Read(Operation::BitImmediate),
+        "BIT" => Read(Operation::Bit),
+        "LAX" => Read(Operation::Lax),
+        "LAS" => Read(Operation::Las),
+        "ANC" => Read(Operation::Anc),
+        "ALR" => Read(Operation::Alr),
+        "ARR" => Read(Operation::Arr),
+        "SBX" => Read(Operation::Sbx),
+        "ANE" => Read(Operation::Ane),
+        "LXA" => Read(Operation::Lxa),
+
+        "STA" => Write(Acc),
+        "STX" => Write(X),
+        "STY" => Write(Y),
+        "STZ" => Write(Register::Zero),
+        "SAX" => Write(Register::AccAndX),
+        "SHA" => StoreHigh(Register::AccAndX),
+        "SHX" => StoreHigh(X),
+        "SHY" => StoreHigh(Y),
+        "TAS" => StoreHigh(Sp),
+
+        "ASL" => Modify(Operation::Asl, shift_penalty),
+        "LSR" => Modify(Operation::Lsr, shift_penalty),
+        "ROL" => Modify(Operation::Rol, shift_penalty),
+        "ROR" => Modify(Operation::Ror, shift_penalty),
+        "INC" => Modify(Operation::Inc, When::Always),
+        "DEC" => Modify(Operation::Dec, When::Always),
+        "TSB" => Modify(Operation::Tsb, When::Always),
+        "TRB" => Modify(Operation::Trb, When::Always),
+        _ if mnemonic.starts_with("RMB") => Modify(Operation::Rmb(bit(mnemonic)), When::Always),
+        _ if mnemonic.starts_with("SMB") => Modify(Operation::Smb(bit(mnemonic)), When::Always),
+        "SLO" => Modify(Operation::Slo, When::Always),
+        "RLA" => Modify(Operation::Rla, When::Always),
+        "SRE" => Modify(Operation::Sre, When::Always),
+        "RRA" => Modify(Operation::Rra, When::Always),
+        "DCP" => Modify(Operation::Dcp, When::Always),
+        "ISC" => Modify(Operation::Isc, When::Always),
+
+        "CLC" => Implied(Operation::ClearFlag(FLAG_C), Acc),
+        "SEC" => Implied(Operation::SetFlag(FLAG_C), Acc),
+        "CLI" => Implied(Operation::ClearFlag(FLAG_I), Acc),
+        "SEI" => Implied(Operation::SetFlag(FLAG_I), Acc),
+        "CLV" => Implied(Operation::ClearFlag(FLAG_V), Acc),
+        "CLD" => Implied(Operation::ClearFlag(FLAG_D), Acc),
+        "SED" => Implied(Operation::SetFlag(FLAG_D), Acc),
+        "TAX" => Implied(Operation::Transfer(Acc), X),
+        "TAY" => Implied(Operation::Transfer(Acc), Y),
+        "TXA" => Implied(Operation::Transfer(X), Acc),
+        "TYA" => Implied(Operation::Transfer(Y), Acc),
+        "TSX" => Implied(Operation::Transfer(Sp), X),
+        "TXS" => Implied(Operation::Transfer(X), Sp),
+        "INX" => Implied(Operation::Inc, X),
+        "INY" => Implied(Operation::Inc, Y),
+        "DEX" => Implied(Operation::Dec, X),
+        "DEY" => Implied(Operation::Dec, Y),
+
+        "BPL" => Branch(Condition::FlagClear(FLAG_N)),
+        "BMI" => Branch(Condition::FlagSet(FLAG_N)),
+        "BVC" => Branch(Condition::FlagClear(FLAG_V)),
+        "BVS" => Branch(Condition::FlagSet(FLAG_V)),
+        "BCC" => Branch(Condition::FlagClear(FLAG_C)),
+        "BCS" => Branch(Condition::FlagSet(FLAG_C)),
+        "BNE" => Branch(Condition::FlagClear(FLAG_Z)),
+        "BEQ" => Branch(Condition::FlagSet(FLAG_Z)),
+        "BRA" => Branch(Condition::Always),
+        _ if mnemonic.starts_with("BBR") => BranchBit(Condition::BitClear(bit(mnemonic))),
+        _ if mnemonic.starts_with("BBS") => BranchBit(Condition::BitSet(bit(mnemonic))),
+
+        "PHA" => Push(Acc),
+        "PHX" => Push(X),
+        "PHY" => Push(Y),
+        "PHP" => Push(Flags),
+        "PLA" => Pull(Acc),
+        "PLX" => Pull(X),
+        "PLY" => Pull(Y),
+        "PLP" => Pull(Flags),
+
+        "JMP" => Jmp,
+        "JSR" => Jsr,
+        "RTS" => Rts,
+        "RTI" => Rti,
+        "BRK" => Brk,
+        "WAI" => Wai,
+        "STP" | "JAM" => Stp,
+        "NOP" => Nop,
+        _ => unreachable!("No behavior for {mnemonic}"),
+    }
+}
+
+// Add the uops that locate the operand, and return where the operand is.
+// index_penalty is when an indexed mode takes an extra cycle to fix up the
+// high byte of the address.
+fn operand(mode: Mode, index_penalty: When, nmos: bool, q: &mut Vec<UOp>) -> Source {
+    let at = Source::Operand;
+    let zero_page = Source::RegVal(Register::Scratch2);
+    let pointer_high = Source::RegValNext(Register::Scratch2);
+    // The dummy reads while indexing. The NMOS 6502 reads the zero page
+    // address before indexing, and the effective address before fixing up
+    // its high byte.
+    let (add_index, fix_up) = if nmos { (zero_page, Source::EaUnfixed) } else { (at(1), at(2)) };
+    let mut q = |op: UOp| q.push(op);
+    match mode {
+        Mode::Immediate => at(1),
+        Mode::ZeroPage => {
+            q(UOp::Read{src: at(1), reg: Register::Scratch2});
+            zero_page
+        },
+        Mode::ZeroPageX | Mode::ZeroPageY => {
+            let reg = if mode == Mode::ZeroPageX { Register::X } else { Register::Y };
+            q(UOp::Read{src: at(1), reg: Register::Scratch2});
+            q(UOp::AddIndex{src: add_index, reg});
+            zero_page
+        },
+        Mode::ZeroPageIndirect => {
+            q(UOp::Read{src: at(1), reg: Register::Scratch2});
+            q(UOp::ReadEa{src: zero_page, high: false, index: None});
+            q(UOp::ReadEa{src: pointer_high, high: true, index: None});
+            Source::Ea
+        },
+        Mode::IndirectX => {
+            q(UOp::Read{src: at(1), reg: Register::Scratch2});
+            q(UOp::AddIndex{src: add_index, reg: Register::X});
+            q(UOp::ReadEa{src: zero_page, high: false, index: None});
+            q(UOp::ReadEa{src: pointer_high, high: true, index: None});
+            Source::Ea
+        },
+        Mode::IndirectY => {
+            q(UOp::Read{src: at(1), reg: Register::Scratch2});
+            q(UOp::ReadEa{src: zero_page, high: false, index: None});
+            q(UOp::ReadEa{src: pointer_high, high: true, index: Some(Register::Y)});
+            let fix_up = if nmos { fix_up } else { pointer_high };
+            q(UOp::Dummy{src: fix_up, when: index_penalty});
+            Source::Ea
+        },
+        Mode::Absolute => {
+            q(UOp::ReadEa{src: at(1), high: false, index: None});
+            q(UOp::ReadEa{src: at(2), high: true, index: None});
+            Source::Ea
+        },
+        Mode::AbsoluteX | Mode::AbsoluteY => {
+            let reg = if mode == Mode::AbsoluteX { Register::X } else { Register::Y };
+            q(UOp::ReadEa{src: at(1), high: false, index: None});
+            q(UOp::ReadEa{src: at(2), high: true, index: Some(reg)});
+            q(UOp::Dummy{src: fix_up, when: index_penalty});
+            Source::Ea
+        },
+        _ => unreachable!("{mode:?} has no operand in memory"),
+    }
+}
+
+// The uops shared by brk and interrupts, which push scratch1 as the flags.
+const fn interrupt(first: UOp, vector: u16) -> [UOp; 6] {
+    [
+        first,
+        UOp::PushPc{high: true},
+        UOp::PushPc{high: false},
+        UOp::Push{val: Register::Scratch1},
+        UOp::ReadPC{first: true, src: Source::Address(vector)},
+        UOp::ReadPC{first: false, src: Source::Address(vector + 1)},
+    ]
+}
+
+// Interrupts start in place of an opcode fetch. The opcode is fetched (with
+// sync high) and discarded, then pc is read once more before pushing.
+pub(crate) static IRQ: [UOp; 6] = interrupt(UOp::Nop, IRQ_VECTOR);
+pub(crate) static NMI: [UOp; 6] = interrupt(UOp::Nop, NMI_VECTOR);
+
+// Unspecified behavior for 6 cycles, then read the reset vector into pc.
+pub(crate) static RESET: [UOp; 8] = [
+    UOp::Nop, UOp::Nop, UOp::Nop, UOp::Nop, UOp::Nop, UOp::Nop,
+    UOp::ReadPC{first: true, src: Source::Address(0xFFFC)},
+    UOp::ReadPC{first: false, src: Source::Address(0xFFFD)},
+];
+
+// The uops for the rest of an instruction after its opcode is fetched, and
+// how far pc advances past the opcode.
+pub(crate) struct Program {
+    pub(crate) uops: Box<[UOp]>,
+    pub(crate) length: u16,
+}
+
+// The program for an opcode. These are compiled once per variant, on first
+// use, so that decoding an instruction is just a lookup.
+pub(crate) fn program(variant: CpuVariant, opcode: u8) -> &'static Program {
+    static PROGRAMS: [OnceLock<Vec<Program>>; CpuVariant::ALL.len()] =
+        [OnceLock::new(), OnceLock::new(), OnceLock::new(), OnceLock::new(), OnceLock::new()];
+    let programs = PROGRAMS[variant as usize]
+        .get_or_init(|| (0 ..= 255).map(|opcode| compile(variant, opcode)).collect());
+    &programs[opcode as usize]
+}
+
+// Build the program for an opcode. Operand sources are relative to the
+// address of the opcode, so a program works wherever it is fetched from.
+// pc advances to the next instruction, except where noted.
+fn compile(variant: CpuVariant, opcode: u8) -> Program {
+    let op = &variant.opcodes()[opcode as usize];
+    let mut uops = Vec::new();
+    let length = build(op.mnemonic, op.mode, op.cycles, op.documented, variant, &mut uops);
+    Program { uops: uops.into_boxed_slice(), length }
+}
+
+fn build(mnemonic: &str, mode: Mode, cycles: u8, documented: bool, variant: CpuVariant,
+         q: &mut Vec<UOp>) -> u16 {
+    let at = Source::Operand;
+    let size = mode.size();
+    let next = Source::Operand(size);
+    let nmos = variant.is_nmos();
+    if !documented && mnemonic == "NOP" {
+        // Undocumented nops read their operand, and then more, to take the
+        // right number of cycles.
+        let src = if mode == Mode::Implied {
+            Source::Operand(1)
+        } else {
+            operand(mode, When::PageCrossed, nmos, q)
+        };
+        let conditional = q.iter()
+            .filter(|uop| matches!(uop, UOp::Dummy{when: When::PageCrossed, ..}))
+            .count();
+        while q.len() - conditional + 1 < cycles as usize {
+            q.push(UOp::Dummy{src, when: When::Always});
+        }
+        return size;
+    }
+
+    match behavior(mnemonic, mode, variant) {
+        Behavior::Read(operation) => {
+            let src = operand(mode, When::PageCrossed, nmos, q);
+            q.push(UOp::ReadOp{src, op: operation});
+            if !nmos && matches!(operation, Operation::Adc | Operation::Sbc) {
+                q.push(UOp::Dummy{src, when: When::Decimal});
+            }
+        },
+        Behavior::Write(reg) => {
+            let dst = operand(mode, When::Always, nmos, q);
+            q.push(UOp::Write{dst, val: reg});
+        },
+        Behavior::StoreHigh(reg) => {
+            operand(mode, When::Always, nmos, q);
+            q.push(UOp::WriteHigh{val: reg});
+        },
+        Behavior::Modify(operation, _) if mode == Mode::Accumulator => {
+            q.push(UOp::Implied{op: operation, reg: Register::Acc});
+        },
+        Behavior::Modify(operation, index_penalty) => {
+            let src = operand(mode, index_penalty, nmos, q);
+            q.push(UOp::Read{src, reg: Register::Scratch1});
+            if nmos {
+                q.push(UOp::WriteModify{dst: src, op: operation});
+            } else {
+                q.push(UOp::Modify{src, op: operation});
+            }
+            q.push(UOp::Write{dst: src, val: Register::Scratch1});
+        },
+        Behavior::Implied(operation, reg) => q.push(UOp::Implied{op: operation, reg}),
+        Behavior::Branch(condition) => {
+            // bra always takes the extra cycle, so it is part of its count.
+            let taken = if condition == Condition::Always { When::Always } else { When::BranchTaken };
+            let fix_up = if nmos { Source::BranchUnfixed } else { next };
+            q.push(UOp::ReadOp{src: at(1), op: Operation::Branch(condition)});
+            q.push(UOp::Dummy{src: next, when: taken});
+            q.push(UOp::Dummy{src: fix_up, when: When::PageCrossed});
+        },
+        Behavior::BranchBit(condition) => {
+            q.push(UOp::Read{src: at(1), reg: Register::Scratch2});
+            q.push(UOp::Read{src: Source::RegVal(Register::Scratch2), reg: Register::Scratch1});
+            q.push(UOp::Dummy{src: Source::RegVal(Register::Scratch2), when: When::Always});
+            q.push(UOp::ReadOp{src: at(2), op: Operation::Branch(condition)});
+            q.push(UOp::Dummy{src: next, when: When::BranchTaken});
+            q.push(UOp::Dummy{src: next, when: When::PageCrossed});
+        },
+        Behavior::Push(Register::Flags) => {
+            q.push(UOp::Implied{op: Operation::SaveFlags, reg: Register::Scratch1});
+            q.push(UOp::Push{val: Register::Scratch1});
+        },
+        Behavior::Push(reg) => {
+            q.push(UOp::Nop);
+            q.push(UOp::Push{val: reg});
+        },
+        Behavior::Pull(reg) => {
+            q.push(UOp::Nop);
+            q.push(UOp::Dummy{src: Source::Stack, when: When::Always});
+            q.push(UOp::Pull{reg});
+        },
+        Behavior::Jmp => {
+            match mode {
+                Mode::Absolute => {},
+                Mode::Indirect => {
+                    q.push(UOp::ReadEa{src: at(1), high: false, index: None});
+                    q.push(UOp::ReadEa{src: at(2), high: true, index: None});
+                    if !nmos {
+                        q.push(UOp::Dummy{src: at(2), when: When::Always});
+                    }
+                },
+                _ => {
+                    q.push(UOp::ReadEa{src: at(1), high: false, index: None});
+                    q.push(UOp::ReadEa{src: at(2), high: true, index: Some(Register::X)});
+                    q.push(UOp::Dummy{src: at(2), when: When::Always});
+                },
+            }
+            // The NMOS 6502 doesn't carry into the high byte of the pointer,
+            // so a pointer at $xxFF wraps within its page.
+            let (low, high) = match mode {
+                Mode::Absolute => (at(1), at(2)),
+                Mode::Indirect if nmos => (Source::Ea, Source::EaNextInPage),
+                _ => (Source::Ea, Source::EaNext),
+            };
+            q.push(UOp::ReadPC{first: true, src: low});
+            q.push(UOp::ReadPC{first: false, src: high});
+        },
+        Behavior::Jsr => {
+            // The high byte of the target is read after pushing, so pc
+            // points at it, and that is the return address pushed.
+            q.push(UOp::Read{src: at(1), reg: Register::Scratch1});
+            q.push(UOp::Dummy{src: Source::Stack, when: When::Always});
+            q.push(UOp::PushPc{high: true});
+            q.push(UOp::PushPc{high: false});
+            q.push(UOp::Jump{src: at(2)});
+            return 2;
+        },
+        Behavior::Rts => {
+            q.push(UOp::Nop);
+            q.push(UOp::Dummy{src: Source::Stack, when: When::Always});
+            q.push(UOp::Pull{reg: Register::Scratch1});
+            q.push(UOp::PullPc);
+            q.push(UOp::IncPc);
+        },
+        Behavior::Rti => {
+            q.push(UOp::Nop);
+            q.push(UOp::Dummy{src: Source::Stack, when: When::Always});
+            q.push(UOp::Pull{reg: Register::Flags});
+            q.push(UOp::Pull{reg: Register::Scratch1});
+            q.push(UOp::PullPc);
+        },
+        Behavior::Brk => {
+            // The signature byte is read, then skipped by the break operation.
+            q.extend(interrupt(UOp::Implied{op: Operation::Break, reg: Register::Scratch1}, IRQ_VECTOR));
+        },
+        Behavior::Wai => {
+            q.push(UOp::Nop);
+            q.push(UOp::Wait);
+        },
+        Behavior::Stp => {
+            q.push(UOp::Nop);
+            q.push(UOp::Stop);
+        },
+        Behavior::Nop => q.push(UOp::Nop),
+    }
+    size
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::bus::Bus;
+    use crate::memory_map::MemoryMap;
+    use crate::runner::{Runner, Stop};
+
+    #[test]
+    fn test_program() {
+        // Sum a table, double it in a subroutine, and load through a pointer.
+        let image = assemble("
+            org $0200
+            .start:
+              ldx #$FF
+              txs
+              ldx #0
+              txa
+              clc
+            .loop:
+              adc .table,x
+              inx
+              cpx #4
+              bne .loop
+              sta $10
+              jsr .double
+              sta $11
+              lda #<.table
+              sta $20
+              lda #>.table
+              sta $21
+              ldy #3
+              lda ($20),y
+              sta $12
+              stp
+            .double:
+              asl a
+              rts
+            .table:
+              db 1 2 3 4
+            org $FFFC
+              dw .start
+        ").unwrap();
+        let mut runner = Runner::new(MemoryMap::from_image(&image).unwrap());
+        assert!(matches!(runner.run(Some(1000)), Stop::Stp(_)));
+        let bus = runner.bus();
+        assert_eq!((10, 20, 4), (bus.peek(0x10), bus.peek(0x11), bus.peek(0x12)));
+        assert_eq!(0xFF, runner.cpu().registers().sp);
+    }
+
+    // Run a program until stp or brk, returning the writes made.
+    fn writes(variant: CpuVariant, source: &str) -> Vec<(u16, u8)> {
+        let image = assemble(source).unwrap();
+        let mut runner = Runner::with_variant(MemoryMap::from_image(&image).unwrap(), variant);
+        let mut writes = Vec::new();
+        while runner.step_cycle().is_none() {
+            let outputs = runner.cpu().outputs();
+            if let Some(val) = outputs.data {
+                writes.push((outputs.address, val));
+            }
+        }
+        writes
+    }
+
+    #[test]
+    fn test_variants() {
+        // The NMOS 6502 writes back the unmodified value, then the result.
+        let source = "
+            org $0200
+            .start:
+              inc $10
+              brk
+            org $FFFC
+              dw .start
+        ";
+        assert_eq!(vec![(0x10, 0x00), (0x10, 0x01)], writes(CpuVariant::Nmos6502, source));
+        assert_eq!(vec![(0x10, 0x01)], writes(CpuVariant::W65C02S, source));
+
+        // jmp ($02FF) reads its high byte from $0200 on the NMOS 6502. Memory
+        // is otherwise zero, so either way it jumps to a brk.
+        let mut image = assemble("
+            org $0200
+            .start:
+              jmp ($02FF)
+            org $FFFC
+              dw .start
+        ").unwrap();
+        image[0x02FF] = 0x10;
+        image[0x0300] = 0x03;
+        for (variant, want) in [(CpuVariant::Nmos6502, 0x6C10), (CpuVariant::W65C02S, 0x0310)] {
+            let mut runner = Runner::with_variant(MemoryMap::from_image(&image).unwrap(), variant);
+            assert_eq!(Stop::Brk(want), runner.run(Some(100)), "{variant}");
+        }
+
+        // wai and stp are single cycle nops on the R65C02.
+        let image = assemble("
+            org $0200
+            .start:
+              wai
+              stp
+            org $FFFC
+              dw .start
+        ").unwrap();
+        let mut runner = Runner::with_variant(MemoryMap::from_image(&image).unwrap(), CpuVariant::R65C02);
+        assert_eq!(Stop::Brk(0x0202), runner.run(Some(100)));
+    }
+
+    #[test]
+    fn test_cycle_counts() {
+        // Without the conditional cycles, every opcode takes the number of
+        // cycles in the table: the fetch, then one per uop.
+        for variant in CpuVariant::ALL {
+            for (opcode, op) in variant.opcodes().iter().enumerate() {
+                let q = &program(variant, opcode as u8).uops;
+                let cycles = 1 + q.iter()
+                    .filter(|uop| !matches!(uop, UOp::Dummy{when, ..} if *when != When::Always))
+                    .count();
+                assert_eq!(op.cycles as usize, cycles, "{variant} {opcode:02X} {} {q:?}", op.mnemonic);
+            }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+// Disassembler
+// Turns 65C02 machine code back into assembly, using the same opcode table as
+// the assembler and the decoder.
+//
+// Operands are formatted so that the assembler accepts them, with branch
+// targets shown as addresses rather than offsets, or as symbols when there are
+// some.
+use std::fmt;
+use crate::opcodes::{CpuVariant, Mode};
+use crate::symbols::Symbols;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    // e.g. "#$AB", "($12),Y" or "$0305" for a branch. Empty if there is none.
+    pub operand: String,
+    // The address the operand refers to, if any. For BBR and BBS, this is the
+    // branch target.
+    pub address: Option<u16>,
+    pub size: u16,
+    // Cycles taken, before any page crossing or branch penalties.
+    pub cycles: u8,
+    pub documented: bool,
+}
+
+impl Instruction {
+    // The instruction with its address operand shown as a symbol, if there
+    // is one near it.
+    pub fn symbolic(&self, symbols: &Symbols) -> String {
+        let label = self.address.and_then(|addr| Some((addr, symbols.label(addr)?)));
+        let Some((addr, label)) = label else {
+            return self.to_string();
+        };
+        let number = match self.mode {
+            Mode::ZeroPage | Mode::ZeroPageX | Mode::ZeroPageY | Mode::ZeroPageIndirect
+                | Mode::IndirectX | Mode::IndirectY => format!("${addr:02X}"),
+            _ => format!("${addr:04X}"),
+        };
+        // The address is the last number in the operand.
+        let at = self.operand.rfind(&number).unwrap();
+        let operand = format!("{}{label}{}", &self.operand[.. at], &self.operand[at + number.len() ..]);
+        format!("{} {operand}", self.mnemonic)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.operand.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operand)
+        }
+    }
+}
+
+// Disassemble the instruction at the start of bytes, which are located at addr.
+// Returns None if bytes ends before the instruction does.
+pub fn disassemble(bytes: &[u8], addr: u16) -> Option<Instruction> {
+    disassemble_variant(CpuVariant::W65C02S, bytes, addr)
+}
+
+// As disassemble, using the opcode table of another cpu.
+pub fn disassemble_variant(variant: CpuVariant, bytes: &[u8], addr: u16) -> Option<Instruction> {
+    let opcode = *bytes.first()?;
+    let op = &variant.opcodes()[opcode as usize];
+    let size = op.mode.size();
+    let operand = bytes.get(1 .. size as usize)?;
+    let byte = || operand[0];
+    let word = || u16::from_le_bytes([operand[0], operand[1]]);
+    // Branch targets are relative to the end of the instruction.
+    let target = |offset: u8| addr.wrapping_add(size).wrapping_add(offset as i8 as u16);
+
+    let address = match op.mode {
+        Mode::Implied | Mode::Accumulator | Mode::Immediate => None,
+        Mode::Relative => Some(target(byte())),
+        Mode::ZeroPageRelative => Some(target(operand[1])),
+        _ if size == 2 => Some(byte() as u16),
+        _ => Some(word()),
+    };
+    let operand = match op.mode {
+        Mode::Implied => String::new(),
+        Mode::Accumulator => "A".to_string(),
+        Mode::Immediate => format!("#${:02X}", byte()),
+        Mode::ZeroPage => format!("${:02X}", byte()),
+        Mode::ZeroPageX => format!("${:02X},X", byte()),
+        Mode::ZeroPageY => format!("${:02X},Y", byte()),
+        Mode::ZeroPageIndirect => format!("(${:02X})", byte()),
+        Mode::IndirectX => format!("(${:02X},X)", byte()),
+        Mode::IndirectY => format!("(${:02X}),Y", byte()),
+        Mode::Absolute => format!("${:04X}", word()),
+        Mode::AbsoluteX => format!("${:04X},X", word()),
+        Mode::AbsoluteY => format!("${:04X},Y", word()),
+        Mode::Indirect => format!("(${:04X})", word()),
+        Mode::AbsoluteIndirectX => format!("(${:04X},X)", word()),
+        Mode::Relative => format!("${:04X}", target(byte())),
+        Mode::ZeroPageRelative => format!("${:02X},${:04X}", byte(), target(operand[1])),
+    };
+    Some(Instruction {
+        addr,
+        opcode,
+        mnemonic: op.mnemonic,
+        mode: op.mode,
+        operand,
+        address,
+        size,
+        cycles: op.cycles,
+        documented: op.documented,
+    })
+}
+
+// Disassemble the instruction at addr within a 64K memory image, as
+// "$ADDR: TEXT", or "$ADDR LABEL: TEXT" where there is a symbol. Falls back to
+// the opcode byte if the instruction runs past the end of memory.
+pub fn describe(memory: &[u8], addr: u16, symbols: &Symbols) -> String {
+    describe_variant(CpuVariant::W65C02S, memory, addr, symbols)
+}
+
+// As describe, using the opcode table of another cpu.
+pub fn describe_variant(variant: CpuVariant, memory: &[u8], addr: u16, symbols: &Symbols) -> String {
+    let location = match symbols.label(addr) {
+        Some(label) => format!("${addr:04X} {label}"),
+        None => format!("${addr:04X}"),
+    };
+    match disassemble_variant(variant, memory.get(addr as usize ..).unwrap_or(&[]), addr) {
+        Some(instruction) => format!("{location}: {}", instruction.symbolic(symbols)),
+        None => match memory.get(addr as usize) {
+            Some(opcode) => format!("{location}: {opcode:02X}"),
+            None => format!("{location}: ??"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::opcodes::OPCODES;
+
+    #[test]
+    fn test_disassemble() {
+        let text = |bytes: &[u8], addr| disassemble(bytes, addr).unwrap().to_string();
+        assert_eq!("LDA #$AB", text(&[0xA9, 0xAB], 0));
+        assert_eq!("JMP ($1234,X)", text(&[0x7C, 0x34, 0x12], 0));
+        assert_eq!("BNE $0300", text(&[0xD0, 0xFE], 0x0300));
+        assert_eq!("BBS7 $12,$0310", text(&[0xFF, 0x12, 0x0D], 0x0300));
+        assert_eq!("ASL A", text(&[0x0A], 0));
+        let brk = disassemble(&[0x00], 0).unwrap();
+        assert_eq!(("BRK", 1, 7), (brk.mnemonic, brk.size, brk.cycles));
+        assert_eq!(None, disassemble(&[0xAD, 0x00], 0));
+        assert_eq!("$0001: 4C", describe(&[0xEA, 0x4C], 1, &Symbols::new()));
+        let lax = disassemble_variant(CpuVariant::Nmos6502, &[0xA7, 0x12], 0).unwrap();
+        assert_eq!(("LAX $12", false), (lax.to_string().as_str(), lax.documented));
+        let lax = describe_variant(CpuVariant::Nmos6502, &[0xA7, 0x12], 0, &Symbols::new());
+        assert_eq!("$0000: LAX $12", lax);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut symbols = Symbols::new();
+        symbols.insert(".loop", 0x0300);
+        symbols.insert(".ptr", 0x0010);
+        let text = |bytes: &[u8]| disassemble(bytes, 0x0300).unwrap().symbolic(&symbols);
+        assert_eq!("JMP .loop", text(&[0x4C, 0x00, 0x03]));
+        assert_eq!("LDA (.ptr+2),Y", text(&[0xB1, 0x12]));
+        assert_eq!("BNE .loop", text(&[0xD0, 0xFE]));
+        assert_eq!("BBS7 $10,.loop+3", text(&[0xFF, 0x10, 0x00]));
+        assert_eq!("LDA #$10", text(&[0xA9, 0x10]));
+        assert_eq!("LDA $1234", text(&[0xAD, 0x34, 0x12]));
+        let mut memory = vec![0; 0x10000];
+        memory[0x0302 .. 0x0305].copy_from_slice(&[0x4C, 0x00, 0x03]);
+        assert_eq!("$0302 .loop+2: JMP .loop", describe(&memory, 0x0302, &symbols));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Every documented instruction reassembles to the same bytes.
+        for (opcode, op) in OPCODES.iter().enumerate() {
+            if !op.documented {
+                continue;
+            }
+            let bytes = [opcode as u8, 0x34, 0x12];
+            let instruction = disassemble(&bytes, 0x1000).unwrap();
+            let image = assemble(&format!("org $1000\n{instruction}")).unwrap();
+            let size = instruction.size as usize;
+            assert_eq!(&bytes[.. size], &image[0x1000 .. 0x1000 + size], "{instruction}");
+        }
+    }
+}
@@ -0,0 +1,479 @@
+// GDB
+// A stub for the gdb remote serial protocol, so that gdb, lldb, or an IDE
+// using either, can debug a program running on the model. It serves a single
+// client over TCP, and supports reading and writing registers and memory,
+// breakpoints, stepping, continuing, and interrupting a running program.
+//
+// There is no 6502 target in gdb, so the registers are described to clients
+// by TARGET_XML, in the order a, x, y, p, sp, pc. Values are sent little
+// endian, as the protocol sends them in target byte order.
+//
+// The protocol is documented at:
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use crate::bus::Bus;
+use crate::runner::{Runner, Stop};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.m6502.core">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8"/>
+    <reg name="y" bitsize="8"/>
+    <reg name="p" bitsize="8"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Cycles run between checks for an interrupt from the client.
+const POLL_CYCLES: u64 = 10000;
+
+// Stop replies, by signal number.
+const SIGINT: &str = "S02";
+const SIGILL: &str = "S04";
+const SIGTRAP: &str = "S05";
+const SIGABRT: &str = "S06";
+
+// What the client sent.
+#[derive(Clone, Debug, PartialEq)]
+enum Received {
+    Packet(String),
+    // Ctrl-C, outside of a packet.
+    Interrupt,
+}
+
+// The framing of packets: $data#checksum, acknowledged with + or -.
+struct Connection {
+    stream: TcpStream,
+    received: VecDeque<u8>,
+    // Cleared by QStartNoAckMode.
+    ack: bool,
+}
+
+impl Connection {
+    // Read more from the client, returning false at the end of the stream.
+    // Without blocking, returns true if nothing was waiting.
+    fn fill(&mut self, block: bool) -> std::io::Result<bool> {
+        let mut buf = [0; 4096];
+        self.stream.set_nonblocking(!block)?;
+        let result = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Ok(false),
+            Ok(n) => {
+                self.received.extend(&buf[.. n]);
+                Ok(true)
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn byte(&mut self) -> std::io::Result<Option<u8>> {
+        while self.received.is_empty() {
+            if !self.fill(true)? {
+                return Ok(None);
+            }
+        }
+        Ok(self.received.pop_front())
+    }
+
+    // Wait for a packet or an interrupt. None if the client has gone.
+    fn receive(&mut self) -> std::io::Result<Option<Received>> {
+        loop {
+            match self.byte()? {
+                None => return Ok(None),
+                Some(0x03) => return Ok(Some(Received::Interrupt)),
+                Some(b'$') => (),
+                // Acks, and anything else between packets.
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let (Some(high), Some(low)) = (self.byte()?, self.byte()?) else {
+                return Ok(None);
+            };
+            let checksum = std::str::from_utf8(&[high, low]).ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            let valid = checksum == Some(checksum_of(&data));
+            if self.ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(Received::Packet(String::from_utf8_lossy(&data).into_owned())));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> std::io::Result<()> {
+        let packet = format!("${data}#{:02x}", checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    // Whether the client has sent an interrupt, without waiting for one.
+    fn interrupted(&mut self) -> std::io::Result<bool> {
+        if !self.fill(false)? {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let interrupted = self.received.contains(&0x03);
+        if interrupted {
+            self.received.clear();
+        }
+        Ok(interrupted)
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0 .. s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i .. i + 2)?, 16).ok()).collect()
+}
+
+// "ADDR,LEN" as used by memory and breakpoint packets.
+fn addr_len(s: &str) -> Option<(u16, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((u16::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+fn stop_reply(stop: &Stop) -> &'static str {
+    match stop {
+        Stop::Jam(_) => SIGILL,
+        Stop::Error(_) => SIGABRT,
+        _ => SIGTRAP,
+    }
+}
+
+// What to do after a packet.
+enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    // The client detached, or killed the program.
+    End(Option<String>),
+}
+
+struct Stub<'a, B: Bus> {
+    runner: &'a mut Runner<B>,
+    // The reply to ?, why the program last stopped.
+    last_stop: &'static str,
+}
+
+impl<B: Bus> Stub<'_, B> {
+    fn registers(&self) -> Vec<u8> {
+        let r = self.runner.cpu().registers();
+        let [pc_low, pc_high] = r.pc.to_le_bytes();
+        vec![r.acc, r.x, r.y, r.flags, r.sp, pc_low, pc_high]
+    }
+
+    fn set_registers(&mut self, bytes: &[u8]) {
+        let mut r = self.runner.cpu().registers();
+        r.acc = bytes[0];
+        r.x = bytes[1];
+        r.y = bytes[2];
+        r.flags = bytes[3];
+        r.sp = bytes[4];
+        r.pc = u16::from_le_bytes([bytes[5], bytes[6]]);
+        self.runner.cpu_mut().set_registers(&r);
+    }
+
+    // Register n's offset and size within the bytes of a g packet.
+    fn register(n: usize) -> Option<(usize, usize)> {
+        match n {
+            0 ..= 4 => Some((n, 1)),
+            5 => Some((5, 2)),
+            _ => None,
+        }
+    }
+
+    fn packet(&mut self, packet: &str) -> Action {
+        let reply = |s: &str| Action::Reply(s.to_string());
+        let error = || reply("E01");
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        match command {
+            "?" => reply(self.last_stop),
+            "g" => Action::Reply(hex(&self.registers())),
+            "G" => match unhex(args) {
+                Some(bytes) if bytes.len() == 7 => {
+                    self.set_registers(&bytes);
+                    reply("OK")
+                },
+                _ => error(),
+            },
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(Self::register) {
+                Some((offset, size)) => Action::Reply(hex(&self.registers()[offset .. offset + size])),
+                None => error(),
+            },
+            "P" => {
+                let Some((n, val)) = args.split_once('=') else {
+                    return error();
+                };
+                match (usize::from_str_radix(n, 16).ok().and_then(Self::register), unhex(val)) {
+                    (Some((offset, size)), Some(val)) if val.len() == size => {
+                        let mut bytes = self.registers();
+                        bytes[offset .. offset + size].copy_from_slice(&val);
+                        self.set_registers(&bytes);
+                        reply("OK")
+                    },
+                    _ => error(),
+                }
+            },
+            "m" => match addr_len(args) {
+                Some((addr, len)) => {
+                    let bus = self.runner.bus();
+                    let bytes : Vec<u8> = (0 .. len).map(|i| bus.peek(addr.wrapping_add(i as u16))).collect();
+                    Action::Reply(hex(&bytes))
+                },
+                None => error(),
+            },
+            "M" => {
+                let Some((range, data)) = args.split_once(':') else {
+                    return error();
+                };
+                match (addr_len(range), unhex(data)) {
+                    (Some((addr, len)), Some(bytes)) if bytes.len() == len => {
+                        for (i, val) in bytes.into_iter().enumerate() {
+                            self.runner.bus_mut().write(addr.wrapping_add(i as u16), val);
+                        }
+                        reply("OK")
+                    },
+                    _ => error(),
+                }
+            },
+            // Software and hardware breakpoints are the same here.
+            "Z" | "z" => {
+                let Some(("0" | "1", args)) = args.split_once(',') else {
+                    // Watchpoints aren't supported.
+                    return reply("");
+                };
+                match addr_len(args) {
+                    Some((addr, _)) if command == "Z" => {
+                        if !self.runner.traps().contains(&addr) {
+                            self.runner.add_trap(addr);
+                        }
+                        reply("OK")
+                    },
+                    Some((addr, _)) => {
+                        self.runner.remove_trap(addr);
+                        reply("OK")
+                    },
+                    None => error(),
+                }
+            },
+            // Resuming somewhere else isn't supported, the client can set pc.
+            "c" if args.is_empty() => Action::Continue,
+            "s" if args.is_empty() => Action::Step,
+            "D" => Action::End(Some("OK".to_string())),
+            "k" => Action::End(None),
+            // There is only one thread.
+            "H" | "T" => reply("OK"),
+            _ => match packet {
+                _ if packet.starts_with("qSupported") => {
+                    reply("PacketSize=1000;qXfer:features:read+;QStartNoAckMode+")
+                },
+                "qAttached" => reply("1"),
+                "qC" => reply("QC1"),
+                "qfThreadInfo" => reply("m1"),
+                "qsThreadInfo" => reply("l"),
+                _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                    let args = &packet["qXfer:features:read:target.xml:".len() ..];
+                    match addr_len(args) {
+                        Some((offset, len)) => {
+                            let xml = TARGET_XML.get(offset as usize ..).unwrap_or("");
+                            match xml.get(.. len) {
+                                Some(part) => Action::Reply(format!("m{part}")),
+                                None => Action::Reply(format!("l{xml}")),
+                            }
+                        },
+                        None => error(),
+                    }
+                },
+                // Anything else is unsupported, which is an empty reply.
+                _ => reply(""),
+            },
+        }
+    }
+
+    // Run until the program stops, or the client interrupts it.
+    fn resume(&mut self, connection: &mut Connection) -> std::io::Result<&'static str> {
+        loop {
+            match self.runner.run(Some(POLL_CYCLES)) {
+                Stop::CycleLimit => (),
+                stop => return Ok(stop_reply(&stop)),
+            }
+            if connection.interrupted()? {
+                return Ok(SIGINT);
+            }
+        }
+    }
+}
+
+// Serve a client on the listener, such as one bound to 127.0.0.1:1234 for
+// gdb's "target remote :1234", until it detaches, kills the program, or
+// disconnects. The runner is first run to the next instruction boundary, such
+// as the end of reset.
+pub fn serve<B: Bus>(runner: &mut Runner<B>, listener: &TcpListener) -> Result<(), String> {
+    let io_error = |e: std::io::Error| format!("gdb connection failed: {e}");
+    let (stream, _) = listener.accept().map_err(io_error)?;
+    stream.set_nodelay(true).map_err(io_error)?;
+    let mut connection = Connection { stream, received: VecDeque::new(), ack: true };
+    if !runner.cpu().outputs().sync {
+        runner.step_instruction();
+    }
+    let mut stub = Stub { runner, last_stop: SIGTRAP };
+    while let Some(received) = connection.receive().map_err(io_error)? {
+        let packet = match received {
+            // Only meaningful while running, but the client wants a stop.
+            Received::Interrupt => {
+                stub.last_stop = SIGINT;
+                connection.send(SIGINT).map_err(io_error)?;
+                continue;
+            },
+            Received::Packet(packet) => packet,
+        };
+        if packet == "QStartNoAckMode" {
+            connection.send("OK").map_err(io_error)?;
+            connection.ack = false;
+            continue;
+        }
+        let reply = match stub.packet(&packet) {
+            Action::Reply(reply) => reply,
+            Action::Continue => {
+                stub.last_stop = stub.resume(&mut connection).map_err(io_error)?;
+                stub.last_stop.to_string()
+            },
+            Action::Step => {
+                stub.last_stop = stub.runner.step_instruction().as_ref().map_or(SIGTRAP, stop_reply);
+                stub.last_stop.to_string()
+            },
+            Action::End(reply) => {
+                if let Some(reply) = reply {
+                    connection.send(&reply).map_err(io_error)?;
+                }
+                return Ok(());
+            },
+        };
+        connection.send(&reply).map_err(io_error)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::BufReader;
+    use crate::assembler::assemble;
+    use crate::memory_map::MemoryMap;
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) {
+            let packet = format!("${data}#{:02x}", checksum_of(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+        }
+
+        // Read a reply, skipping acks.
+        fn reply(&mut self) -> String {
+            let mut byte = [0];
+            while byte[0] != b'$' {
+                self.reader.read_exact(&mut byte).unwrap();
+            }
+            let mut packet = Vec::new();
+            loop {
+                self.reader.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                packet.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum).unwrap();
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+            assert_eq!(checksum_of(&packet), checksum);
+            String::from_utf8(packet).unwrap()
+        }
+
+        fn ask(&mut self, data: &str) -> String {
+            self.send(data);
+            self.reply()
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        let image = assemble("
+            org $0200
+            .start:
+              lda #$42
+              sta $10
+            .loop:
+              inc $11
+              jmp .loop
+            org $FFFC
+              dw .start
+        ").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut runner = Runner::new(MemoryMap::from_image(&image).unwrap());
+            serve(&mut runner, &listener)
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut client = Client { reader: BufReader::new(stream.try_clone().unwrap()), stream };
+        assert!(client.ask("qSupported:multiprocess+").contains("qXfer:features:read+"));
+        assert_eq!("OK", client.ask("QStartNoAckMode"));
+        assert!(client.ask("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+        assert_eq!(SIGTRAP, client.ask("?"));
+        // After reset, at the start of the program.
+        assert_eq!("0002", client.ask("p5"));
+
+        assert_eq!("OK", client.ask("Z0,204,1"));
+        assert_eq!(SIGTRAP, client.ask("c"));
+        let registers = client.ask("g");
+        assert_eq!(("42", "0402"), (&registers[0 .. 2], &registers[10 ..]));
+        assert_eq!("4200", client.ask("m10,2"));
+        assert_eq!("OK", client.ask("M10,2:55aa"));
+        assert_eq!("55aa", client.ask("m10,2"));
+        assert_eq!("OK", client.ask("z0,204,1"));
+        assert_eq!(SIGTRAP, client.ask("s"));
+        assert_eq!("ab", client.ask("m11,1"));
+        assert_eq!("OK", client.ask("P0=99"));
+        assert_eq!("99", client.ask("p0"));
+        assert_eq!("E01", client.ask("p9"));
+        assert_eq!("", client.ask("vMustReplyEmpty"));
+
+        // Run the loop until interrupted.
+        client.send("c");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(SIGINT, client.reply());
+        assert_eq!(SIGINT, client.ask("?"));
+
+        assert_eq!("OK", client.ask("D"));
+        server.join().unwrap().unwrap();
+    }
+}
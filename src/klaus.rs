@@ -0,0 +1,219 @@
+// Klaus Dormann's test suites
+// The de facto standard functional tests for the 6502 and 65C02, from
+// https://github.com/Klaus2m5/6502_65C02_functional_tests. They aren't
+// distributed with the model, see test_suites for how to run them.
+//
+// Each test runs until it reaches a trap: an instruction that jumps or
+// branches to itself. Reaching the suite's success trap is a pass. Any other
+// trap is a failure, and its address can be looked up in the suite's listing
+// to find the failing test. The decimal test ends the same way whether it
+// passes or not, with its result in memory.
+use crate::bus::Bus;
+use crate::memory_map::MemoryMap;
+use crate::runner::{Runner, Stop};
+use crate::{CpuVariant, Registers};
+
+// A limit on each run, well past what the longest suite needs.
+const MAX_CYCLES: u64 = 200_000_000;
+
+// How a suite reports success.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Success {
+    // Trapping at an address.
+    Trap(u16),
+    // Stopping with zero at an address, as the decimal test's ERROR flag.
+    Zero(u16),
+}
+
+pub struct Suite {
+    pub file: &'static str,
+    // Where the binary is loaded, and where the test starts.
+    pub load: u16,
+    pub start: u16,
+    pub success: Success,
+    pub variant: CpuVariant,
+}
+
+// Addresses are for the binaries as published. Rebuilt binaries can move the
+// success trap, which is in the listing as "success".
+pub const FUNCTIONAL: Suite = Suite {
+    file: "6502_functional_test.bin",
+    load: 0x0000,
+    start: 0x0400,
+    success: Success::Trap(0x3469),
+    variant: CpuVariant::W65C02S,
+};
+
+pub const EXTENDED_OPCODES: Suite = Suite {
+    file: "65C02_extended_opcodes_test.bin",
+    load: 0x0000,
+    start: 0x0400,
+    success: Success::Trap(0x24F1),
+    variant: CpuVariant::W65C02S,
+};
+
+// Only published as source, so this is for 6502_decimal_test.a65 assembled
+// with its defaults, which leave the result in ERROR at $0B.
+pub const DECIMAL: Suite = Suite {
+    file: "6502_decimal_test.bin",
+    load: 0x0200,
+    start: 0x0200,
+    success: Success::Zero(0x000B),
+    variant: CpuVariant::W65C02S,
+};
+
+// How a run ended: the address it trapped or stopped at, and whether that
+// was a pass.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outcome {
+    pub addr: u16,
+    pub passed: bool,
+    pub cycles: u64,
+}
+
+// Run a whole instruction at a time until one leaves pc where it was, or the
+// cpu stops at a STP or JAM, returning the address. BRK is run like any other
+// instruction, as the suites test it through their interrupt handler. Errors
+// are for the model failing, or not trapping within max_cycles.
+pub fn run_to_trap<B: Bus>(runner: &mut Runner<B>, max_cycles: u64) -> Result<u16, String> {
+    let start = runner.cycles();
+    while runner.cycles() - start < max_cycles {
+        let pc = runner.cpu().registers().pc;
+        match runner.step_instruction() {
+            None => (),
+            Some(Stop::Error(e)) => return Err(e),
+            Some(Stop::Stp(addr) | Stop::Jam(addr)) => return Ok(addr),
+            Some(_) => (),
+        }
+        if runner.cpu().registers().pc == pc {
+            return Ok(pc);
+        }
+    }
+    Err(format!("No trap after {max_cycles} cycles"))
+}
+
+// Run a suite from its binary.
+pub fn run_suite(suite: &Suite, binary: &[u8]) -> Result<Outcome, String> {
+    let mut image = vec![0; 0x10000];
+    let end = suite.load as usize + binary.len();
+    if end > image.len() {
+        return Err(format!("{} is too big to load at ${:04X}", suite.file, suite.load));
+    }
+    image[suite.load as usize .. end].copy_from_slice(binary);
+    let mut runner = Runner::with_variant(MemoryMap::from_image(&image)?, suite.variant);
+    // Finish reset, then start the test, with interrupts disabled.
+    if let Some(Stop::Error(e)) = runner.step_instruction() {
+        return Err(e);
+    }
+    runner.cpu_mut().set_registers(&Registers {
+        pc: suite.start,
+        acc: 0,
+        x: 0,
+        y: 0,
+        sp: 0xFF,
+        flags: 0x24,
+    });
+    let start = runner.cycles();
+    let addr = run_to_trap(&mut runner, MAX_CYCLES)?;
+    let passed = match suite.success {
+        Success::Trap(success) => addr == success,
+        Success::Zero(flag) => runner.bus().peek(flag) == 0,
+    };
+    Ok(Outcome { addr, passed, cycles: runner.cycles() - start })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_run_suite() {
+        // A stand in for a suite, which fails if $10 isn't zero.
+        let image = assemble("
+            org $0400
+              lda $10
+              bne .fail
+              jmp *
+            .fail:
+              bne .fail
+        ").unwrap();
+        let suite = Suite {
+            file: "test",
+            load: 0,
+            start: 0x0400,
+            success: Success::Trap(0x0404),
+            variant: CpuVariant::W65C02S,
+        };
+        let outcome = run_suite(&suite, &image).unwrap();
+        assert_eq!(Outcome { addr: 0x0404, passed: true, cycles: 3 + 2 + 3 }, outcome);
+
+        let mut failing = image.clone();
+        failing[0x10] = 1;
+        let outcome = run_suite(&suite, &failing).unwrap();
+        assert_eq!((0x0407, false), (outcome.addr, outcome.passed));
+
+        let decimal = Suite { success: Success::Zero(0x10), ..suite };
+        let mut stopping = image.clone();
+        stopping[0x0404] = 0xDB;  // stp
+        assert!(run_suite(&decimal, &stopping).unwrap().passed);
+        let too_big = Suite { load: 0x0100, ..decimal };
+        assert!(run_suite(&too_big, &image).is_err());
+    }
+
+    #[test]
+    fn test_run_suite_brk() {
+        // BRK goes through the handler and carries on, rather than ending
+        // the run. It returns past its signature byte, to the jmp.
+        let image = assemble("
+            org $0400
+              nop
+              brk
+              nop
+              jmp *
+            org $0500
+            .irq:
+              rti
+            org $FFFE
+              dw .irq
+        ").unwrap();
+        let suite = Suite {
+            file: "test",
+            load: 0,
+            start: 0x0400,
+            success: Success::Trap(0x0403),
+            variant: CpuVariant::W65C02S,
+        };
+        let outcome = run_suite(&suite, &image).unwrap();
+        assert_eq!((0x0403, true), (outcome.addr, outcome.passed));
+    }
+
+    // The suites aren't distributed with the model. To run them, put the
+    // binaries in a directory and run:
+    // KLAUS_DIR=path/to/binaries cargo test -- --ignored test_suites
+    fn check_suite(suite: &Suite) {
+        let dir = std::env::var("KLAUS_DIR").unwrap_or("klaus".to_string());
+        let binary = std::fs::read(format!("{dir}/{}", suite.file)).unwrap();
+        let outcome = run_suite(suite, &binary).unwrap();
+        assert!(outcome.passed, "{} trapped at ${:04X} after {} cycles",
+                suite.file, outcome.addr, outcome.cycles);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_suites_functional() {
+        check_suite(&FUNCTIONAL);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_suites_extended_opcodes() {
+        check_suite(&EXTENDED_OPCODES);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_suites_decimal() {
+        check_suite(&DECIMAL);
+    }
+}
@@ -7,9 +7,32 @@
 // Representing Signals in Rust:
 // - Buses can be represented by the unsigned int of appropriate size.
 // - Tri state pins are represented by Option<bool>, and None indicates floating / HighZ.
-use std::collections::VecDeque;
+pub mod bus;
+pub mod memory_map;
+pub mod via;
+pub mod acia;
+pub mod runner;
+pub mod opcodes;
+pub mod assembler;
+pub mod disasm;
+pub mod symbols;
+pub mod nes;
+pub mod klaus;
+pub mod debugger;
+pub mod gdb;
+mod operations;
+mod decode;
+mod step;
+mod snapshot;
+mod rewind;
+pub mod trace_log;
+pub mod trace_tests;
+pub mod trace_report;
 
-mod trace_tests;
+use bus::Bus;
+pub use opcodes::CpuVariant;
+pub use operations::Unstable;
+use operations::{Operation, FLAG_B, FLAG_D, FLAG_I, FLAG_UNUSED, FLAG_V};
 
 // Small internal instructions that perform the work for each
 // cycle of a user-facing instruction.
@@ -17,29 +40,84 @@ mod trace_tests;
 // Many instructions have very similar behavior. By examining the cpu
 // busses at each cycle, most instructions can be decomposed into a small
 // set of simple micro operations.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum UOp {
     Nop,
     Fetch,
     ResetRegs,
-    ReadPC{first: bool, addr: u16},
+    // Set the low (first) or high byte of pc from the byte read.
+    ReadPC{first: bool, src: Source},
     Read{src: Source, reg: Register},
     Write{dst: Source, val: Register},
+    // Write to the top of the stack, then decrement sp.
+    Push{val: Register},
+    PushPc{high: bool},
+    // A read whose data is ignored. Skipped entirely unless when holds.
+    Dummy{src: Source, when: When},
+    // Read one byte of the effective address, then optionally index it.
+    ReadEa{src: Source, high: bool, index: Option<Register>},
+    // The NMOS read-modify-write cycle: the unmodified scratch1 is written
+    // back, while applying an operation to it.
+    WriteModify{dst: Source, op: Operation},
+    // Write a register ANDed with the high byte of the effective address
+    // plus one, for the unstable NMOS stores. See store_high.
+    WriteHigh{val: Register},
+    // A dummy read, while adding an index to the zero page address in scratch2.
+    AddIndex{src: Source, reg: Register},
+    // Read an operand and apply an operation to it.
+    ReadOp{src: Source, op: Operation},
+    // A dummy read, while applying an operation to scratch1.
+    Modify{src: Source, op: Operation},
+    // A dummy read of pc, while applying an operation to a register.
+    Implied{op: Operation, reg: Register},
+    // Increment sp, then read the top of the stack.
+    Pull{reg: Register},
+    // Pull the high byte of pc, taking the low byte from scratch1.
+    PullPc,
+    // Read the high byte of pc, taking the low byte from scratch1.
+    Jump{src: Source},
+    // A dummy read of pc, then increment it.
+    IncPc,
+    // Repeats until an interrupt is requested.
+    Wait,
+    // Repeats until reset.
+    Stop,
 }
-#[derive(Clone, Copy, Debug)]
+
+// When a Dummy uop takes a cycle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum When {
+    Always,
+    // Indexing or a branch moved to another page.
+    PageCrossed,
+    BranchTaken,
+    // adc and sbc take a cycle longer in decimal mode.
+    Decimal,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum Register {
     Acc,
     X,
     Y,
+    Sp,
+    Flags,
     // Fake scratch registers, used as work space for
     // uops.
     Scratch1,
+    Scratch2,
+    // Reads as zero, for stz.
+    Zero,
+    // Reads as acc & x, for the undocumented NMOS stores.
+    AccAndX,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum Source {
-    // A direct address, known at the time of decoding the address.
+    // A fixed address, such as a vector.
     Address(u16),
+    // An offset from the address of the opcode, for reading operand bytes.
+    Operand(u16),
     // RegVal allows uops to use the register value at the time of 
     // usage, rather than when the opcode was initially decoded.
     // Consider a zero page instruction:
@@ -48,9 +126,27 @@ enum Source {
     // Step 2 would like to be able to use the result of #1. By reading 1
     // into a register, step 2 can use Source::RegVal as its input to use that value.
     RegVal(Register),
+    // The zero page address after RegVal, wrapping within the zero page.
+    RegValNext(Register),
+    // The effective address, and the address after it.
+    Ea,
+    EaNext,
+    // The byte after the effective address, without carrying into the high
+    // byte. The NMOS jmp (abs) reads its target from here.
+    EaNextInPage,
+    // The effective address before the carry from indexing was added to the
+    // high byte, which is read during the NMOS fix up cycle.
+    EaUnfixed,
+    // The target of a branch before the carry was added to the high byte.
+    BranchUnfixed,
+    // The top of the stack.
+    Stack,
 }
 
-struct W6502 {
+#[derive(Clone)]
+pub struct W6502 {
+    variant: CpuVariant,
+    unstable: Unstable,
     outputs: Outputs,
     prev_clk: bool,
 
@@ -60,11 +156,15 @@ struct W6502 {
     // execution.
     //
 
-    // Most instructions take several cycles. The queue
-    // holds remaining steps for the last fetched
-    // instruction.
-    queue: VecDeque<UOp>,
+    // Most instructions take several cycles. The program
+    // holds the steps for the last fetched instruction, and
+    // step is the index of the next one to run.
+    program: &'static [UOp],
+    step: usize,
     active_uop: UOp,
+    // The opcode being executed, and the address it was fetched from.
+    opcode: u8,
+    opcode_addr: u16,
 
     //
     // Registers
@@ -78,22 +178,87 @@ struct W6502 {
     flags: u8,    // NZCIDV
     // scratch registers for uops
     scratch1: u8,
+    scratch2: u8,
+    // The effective address of the operand, built up by ReadEa.
+    ea: u16,
+    // Whether the last indexing or branch crossed a page, and whether the
+    // last branch was taken. These decide which conditional uops run.
+    page_crossed: bool,
+    branch_taken: bool,
+
+    // The irq line as sampled during the last cycle. An interrupt is
+    // taken at the next instruction boundary if it is still requested.
+    irq_pending: bool,
+    // nmi is edge triggered, so a falling edge is remembered until
+    // the interrupt is taken.
+    nmi_pending: bool,
+    // Levels of the edge triggered inputs during the last cycle.
+    prev_n_nmi: bool,
+    prev_n_so: bool,
 }
 
+// Addresses of the low byte of the interrupt vectors.
+const NMI_VECTOR: u16 = 0xFFFA;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
 // Pins read by the 6502
 #[derive(Clone, Copy)]
-struct Inputs {
-    clk: bool,
-    n_reset: bool,    // active low reset
-    data: u8,
+pub struct Inputs {
+    pub clk: bool,
+    pub n_reset: bool,    // active low reset
+    pub n_irq: bool,      // active low interrupt request
+    pub n_nmi: bool,      // active low non maskable interrupt, taken on the falling edge
+    pub rdy: bool,        // when low, the cpu holds the current cycle
+    pub n_so: bool,       // active low set overflow, sets V on the falling edge
+    pub data: u8,
+}
+
+// All pins inactive: out of reset, no interrupts, and ready.
+impl Default for Inputs {
+    fn default() -> Self {
+        Inputs {
+            clk: false,
+            n_reset: true,
+            n_irq: true,
+            n_nmi: true,
+            rdy: true,
+            n_so: true,
+            data: 0,
+        }
+    }
 }
 
 // Pins set by the 6502.
-struct Outputs {
-    address: u16,
-    data: Option<u8>,   // None if reading, Some if writing.
-    rwb: bool,          // true for read, false for write
-    sync: bool,         // true for the cycle of fetching the opcode byte.
+#[derive(Clone)]
+pub struct Outputs {
+    pub address: u16,
+    pub data: Option<u8>,   // None if reading, Some if writing.
+    pub rwb: bool,          // true for read, false for write
+    pub sync: bool,         // true for the cycle of fetching the opcode byte.
+}
+
+// A copy of the programmer visible registers.
+// pc is only meaningful between instructions, as the model advances it
+// when decoding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub acc: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub flags: u8,
+}
+
+impl std::fmt::Display for Registers {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Flags are shown as letters, capitalized when set.
+        let flags : String = "NV-BDIZC".chars().enumerate().map(|(i, c)| {
+            if self.flags & (0x80 >> i) != 0 { c } else { c.to_ascii_lowercase() }
+        }).collect();
+        write!(f, "PC={:04X} A={:02X} X={:02X} Y={:02X} SP={:02X} P={:02X} {flags}",
+               self.pc, self.acc, self.x, self.y, self.sp, self.flags)
+    }
 }
 
 impl Outputs {
@@ -111,13 +276,24 @@ impl Outputs {
     }
 }
 
+impl Default for W6502 {
+    fn default() -> Self {
+        W6502::new(CpuVariant::default())
+    }
+}
+
 impl W6502 {
-    pub fn new() -> W6502 {
+    pub fn new(variant: CpuVariant) -> W6502 {
         W6502 {
+            variant,
+            unstable: Unstable::default(),
             outputs: Outputs::new(),
             prev_clk: false,
-            queue: VecDeque::new(),
+            program: &[],
+            step: 0,
             active_uop: UOp::Nop,
+            opcode: 0xEA,
+            opcode_addr: 0xcafe,
 
             // "random" nonzero values before reset
             pc: 0xcafe,
@@ -128,6 +304,14 @@ impl W6502 {
             y: 0xca,
 
             scratch1: 0,
+            scratch2: 0,
+            ea: 0,
+            page_crossed: false,
+            branch_taken: false,
+            irq_pending: false,
+            nmi_pending: false,
+            prev_n_nmi: true,
+            prev_n_so: true,
         }
     }
 
@@ -142,38 +326,74 @@ impl W6502 {
         Ok(())
     }
 
+    // Run one cycle against a bus, rather than driving the pins directly.
+    // The data pins are sampled in the cycle after the address is placed on the bus,
+    // so reads are serviced for the address left by the previous cycle. Writes
+    // are passed to the bus as soon as the cpu drives them.
+    pub fn cycle_bus(&mut self, bus: &mut impl Bus) -> Result<(), String> {
+        let data = if self.outputs.rwb {
+            bus.read(self.outputs.address)
+        } else {
+            self.outputs.data.unwrap_or(0)
+        };
+        self.cycle(&Inputs {
+            data,
+            n_irq: !bus.irq(),
+            ..Inputs::default()
+        })?;
+        if let Some(val) = self.outputs.data {
+            bus.write(self.outputs.address, val);
+        }
+        bus.tick();
+        Ok(())
+    }
+
     pub fn tick(&mut self, inputs: &Inputs) -> Result<(), String> {
         if !inputs.n_reset {
             // unspecified behavior for 6 cycles, then
             // read the reset vector, then set pc
-            self.queue.clear();
-            for i in 0 .. 6 {
-                self.queue.push_back(UOp::Nop);
-            }
-            self.queue.push_back(UOp::ReadPC{first: true, addr: 0xFFFC});
-            self.queue.push_back(UOp::ReadPC{first: false, addr: 0xFFFD});
+            self.start_program(&decode::RESET);
+            // Reset also ends wai and stp.
+            self.active_uop = UOp::Nop;
+            self.flags |= FLAG_I;
+            self.clear_decimal();
+            self.irq_pending = false;
+            self.nmi_pending = false;
             return Ok(());
         }
 
         let posedge =!self.prev_clk && inputs.clk; 
+        if !inputs.rdy {
+            // Hold the current cycle, leaving the bus unchanged. Data is
+            // latched once rdy is raised again.
+            if !posedge {
+                self.sample_pins(inputs);
+            }
+            self.prev_clk = inputs.clk;
+            return Ok(());
+        }
         // start a new uop each positive clock edge.
         let op = if posedge {
-            if self.queue.len() > 0 {
+            // Every cycle is a read unless the uop drives the data bus.
+            self.outputs.zero();
+            let waiting = self.irq_pending || self.nmi_pending;
+            let next = match self.active_uop {
+                UOp::Stop => Some(UOp::Stop),
+                UOp::Wait if !waiting => Some(UOp::Wait),
+                _ => self.next_uop(),
+            };
+            if let Some(op) = next {
                 self.outputs.sync = false;
-                self.queue.pop_front().unwrap()
+                op
             } else {
-                // reset outputs
-                self.outputs.zero();
                 self.outputs.sync = true;
-                UOp::Fetch
+                self.start_instruction()
             }
         } else {
             self.active_uop
         };
         self.active_uop = op;
 
-        println!("uop={op:?} c={}", posedge as u8);
-
         // Execute uops.
         match op {
             UOp::Nop => {
@@ -183,13 +403,14 @@ impl W6502 {
             UOp::Write{dst, val} => {
                 let dst = self.source(dst);
                 self.set_addr(dst);
-                let val = *self.mut_reg(val);
-                self.set_data(val);
+                self.set_data(self.reg(val));
             },
             UOp::Fetch => {
                 if posedge {
                     self.set_addr(self.pc);
                 } else {
+                    self.opcode = inputs.data;
+                    self.opcode_addr = self.pc;
                     self.decode_op(inputs.data)?;
                 }
             },
@@ -201,11 +422,31 @@ impl W6502 {
                     *self.mut_reg(reg) = inputs.data;
                 }
             },
+            UOp::Push{val} => self.push(self.reg(val)),
+            UOp::WriteHigh{val} => {
+                let (addr, val) = self.store_high(val, self.ea, self.page_crossed);
+                self.set_addr(addr);
+                self.set_data(val);
+            },
+            UOp::WriteModify{dst, op} => {
+                if posedge {
+                    let dst = self.source(dst);
+                    self.set_addr(dst);
+                    self.set_data(self.scratch1);
+                } else {
+                    self.modify(op, Register::Scratch1);
+                }
+            },
+            UOp::PushPc{high} => {
+                let val = if high { self.pc >> 8 } else { self.pc & 0xFF };
+                self.push(val as u8);
+            },
             UOp::ResetRegs => {
                 // TODO: initialize registers for reset
             },
-            UOp::ReadPC{first, addr} => {
+            UOp::ReadPC{first, src} => {
                 if posedge {
+                    let addr = self.source(src);
                     self.set_addr(addr);
                 } else {
                     if first {
@@ -215,92 +456,202 @@ impl W6502 {
                     }
                 }
             },
+            // The remaining uops place their address at the start of the
+            // cycle, and act on the data at the end of it.
+            _ if posedge => {
+                let addr = self.uop_address(op);
+                self.set_addr(addr);
+            },
+            UOp::Dummy{..} | UOp::Wait | UOp::Stop => {},
+            UOp::ReadEa{high, index, ..} => {
+                let data = inputs.data as u16;
+                self.ea = if high { (self.ea & 0x00FF) | (data << 8) } else { (self.ea & 0xFF00) | data };
+                if let Some(reg) = index {
+                    let base = self.ea;
+                    self.ea = base.wrapping_add(self.reg(reg) as u16);
+                    self.page_crossed = (base ^ self.ea) & 0xFF00 != 0;
+                }
+            },
+            UOp::AddIndex{reg, ..} => self.scratch2 = self.scratch2.wrapping_add(self.reg(reg)),
+            UOp::ReadOp{op, ..} => self.operate(op, inputs.data),
+            UOp::Modify{op, ..} => self.modify(op, Register::Scratch1),
+            UOp::Implied{op, reg} => self.modify(op, reg),
+            UOp::Pull{reg} => {
+                self.sp = self.sp.wrapping_add(1);
+                *self.mut_reg(reg) = inputs.data;
+                if matches!(reg, Register::Acc | Register::X | Register::Y) {
+                    self.operate(Operation::Load(reg), inputs.data);
+                }
+            },
+            UOp::PullPc | UOp::Jump{..} => {
+                if matches!(op, UOp::PullPc) {
+                    self.sp = self.sp.wrapping_add(1);
+                }
+                self.pc = ((inputs.data as u16) << 8) | self.scratch1 as u16;
+            },
+            UOp::IncPc => self.pc = self.pc.wrapping_add(1),
         }
 
+        if !posedge {
+            self.sample_pins(inputs);
+        }
         self.prev_clk = inputs.clk;
         Ok(())
     }
+
+    // Pop the next uop for the current instruction, passing over conditional
+    // uops whose condition doesn't hold.
+    fn next_uop(&mut self) -> Option<UOp> {
+        while let Some(&op) = self.program.get(self.step) {
+            self.step += 1;
+            let skip = match op {
+                UOp::Dummy{when: When::PageCrossed, ..} => !self.page_crossed,
+                UOp::Dummy{when: When::BranchTaken, ..} => !self.branch_taken,
+                UOp::Dummy{when: When::Decimal, ..} => self.flags & FLAG_D == 0,
+                _ => false,
+            };
+            if !skip {
+                return Some(op);
+            }
+        }
+        None
+    }
+
+    // The address placed on the bus by uops that read and then act on the data.
+    fn uop_address(&self, op: UOp) -> u16 {
+        match op {
+            UOp::Dummy{src, ..} | UOp::ReadEa{src, ..} | UOp::AddIndex{src, ..} |
+            UOp::ReadOp{src, ..} | UOp::Modify{src, ..} | UOp::Jump{src} => self.source(src),
+            UOp::Pull{..} | UOp::PullPc => 0x0100 | self.sp.wrapping_add(1) as u16,
+            _ => self.pc,
+        }
+    }
+
+    // Sample the interrupt and set overflow pins, once per cycle.
+    fn sample_pins(&mut self, inputs: &Inputs) {
+        self.irq_pending = !inputs.n_irq;
+        if self.prev_n_nmi && !inputs.n_nmi {
+            self.nmi_pending = true;
+        }
+        if self.prev_n_so && !inputs.n_so {
+            self.flags |= FLAG_V;
+        }
+        self.prev_n_nmi = inputs.n_nmi;
+        self.prev_n_so = inputs.n_so;
+    }
+
+    // At an instruction boundary, start the next instruction: an interrupt if
+    // one is pending, otherwise an opcode fetch. Returns the uop for this cycle.
+    fn start_instruction(&mut self) -> UOp {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.start_interrupt(&decode::NMI);
+            UOp::Nop
+        } else if self.irq_pending && self.flags & FLAG_I == 0 {
+            self.start_interrupt(&decode::IRQ);
+            UOp::Nop
+        } else {
+            UOp::Fetch
+        }
+    }
+
+    fn start_program(&mut self, program: &'static [UOp]) {
+        self.program = program;
+        self.step = 0;
+    }
+
+    // Start the interrupt sequence, in place of an opcode fetch. pc and flags
+    // are pushed and the new pc is read from the vector.
+    fn start_interrupt(&mut self, program: &'static [UOp]) {
+        // flags are pushed as they were before the interrupt, with the break bit clear.
+        self.scratch1 = (self.flags | FLAG_UNUSED) & !FLAG_B;
+        self.flags |= FLAG_I;
+        self.clear_decimal();
+        self.start_program(program);
+    }
+
+    // The CMOS parts clear decimal mode on reset and interrupts. The NMOS
+    // 6502 leaves it as it was.
+    fn clear_decimal(&mut self) {
+        if !self.variant.is_nmos() {
+            self.flags &= !FLAG_D;
+        }
+    }
+
+    pub fn variant(&self) -> CpuVariant {
+        self.variant
+    }
+
+    // Change how the unstable NMOS opcodes behave.
+    pub fn set_unstable(&mut self, unstable: Unstable) {
+        self.unstable = unstable;
+    }
+
+    // The address and value for sha, shx, shy and tas, given the indexed
+    // effective address. The value is ANDed with the high byte of the base
+    // address plus one, and when indexing crossed a page, it also replaces the
+    // high byte of the address. tas first sets sp to acc & x.
+    fn store_high(&mut self, reg: Register, ea: u16, page_crossed: bool) -> (u16, u8) {
+        if reg == Register::Sp {
+            self.sp = self.acc & self.x;
+        }
+        let mut val = self.reg(reg);
+        if self.unstable.and_high {
+            let base_high = ((ea >> 8) as u8).wrapping_sub(page_crossed as u8);
+            val &= base_high.wrapping_add(1);
+        }
+        let addr = if page_crossed { ((val as u16) << 8) | (ea & 0x00FF) } else { ea };
+        (addr, val)
+    }
     pub fn outputs(&self) -> &Outputs {
         &self.outputs
     }
+    // Address and opcode of the instruction being executed.
+    pub fn instruction(&self) -> (u16, u8) {
+        (self.opcode_addr, self.opcode)
+    }
+    // Describe the uop being executed, and the uops queued after it, for debugging.
+    pub fn uop_state(&self) -> (String, String) {
+        (format!("{:x?}", self.active_uop), format!("{:x?}", &self.program[self.step ..]))
+    }
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            acc: self.acc,
+            x: self.x,
+            y: self.y,
+            sp: self.sp,
+            flags: self.flags,
+        }
+    }
+
+    // Replace the programmer visible registers, such as to start a program
+    // somewhere other than the reset vector. Only meaningful between
+    // instructions, where pc is the address of the next opcode.
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.pc = registers.pc;
+        self.acc = registers.acc;
+        self.x = registers.x;
+        self.y = registers.y;
+        self.sp = registers.sp;
+        self.flags = registers.flags;
+        if self.outputs.sync {
+            self.set_addr(self.pc);
+        }
+    }
 
     // decode_op is called at the end of a fetch, when the
     // cpu has just read the opcode for the next byte.
     //
     // This function is responsible for decoding the opcode byte,
-    // and setting up the queue to execute the rest of the instruction.
-    // After decoding, PC should point to the next instruction.
+    // and starting the program to execute the rest of the instruction.
+    // After decoding, PC should point to the next instruction. Programs
+    // are built from the opcode table shared with the disassembler, see decode.rs.
     fn decode_op(&mut self, opcode: u8) -> Result<(), String> {
-        assert_eq!(0, self.queue.len());
-        let mut q = |op: UOp| { self.queue.push_back(op); };
-        // TODO: Much repetition across opcodes allows this to be refactored.
-        match opcode {
-            0x4C => {
-                // jmp abs
-                q(UOp::ReadPC{first: true, addr: self.pc+1});
-                q(UOp::ReadPC{first: false, addr: self.pc+2});
-                self.pc += 3;
-            },
-            0x84 => {
-                // sty zpg
-                q(UOp::Read{src: Source::Address(self.pc+1), reg: Register::Scratch1});
-                q(UOp::Write{dst: Source::RegVal(Register::Scratch1), val: Register::Y});
-                self.pc += 2;
-            },
-            0x85 => {
-                // sta zpg
-                q(UOp::Read{src: Source::Address(self.pc+1), reg: Register::Scratch1});
-                q(UOp::Write{dst: Source::RegVal(Register::Scratch1), val: Register::Acc});
-                self.pc += 2;
-            },
-            0x86 => {
-                // stx zpg
-                q(UOp::Read{src: Source::Address(self.pc+1), reg: Register::Scratch1});
-                q(UOp::Write{dst: Source::RegVal(Register::Scratch1), val: Register::X});
-                self.pc += 2;
-            },
-            0xA0 => {
-                // ldy imm
-                q(UOp::Read{src: Source::Address(self.pc+1), reg: Register::Y});
-                self.pc += 2;
-            },
-            0xA2 => {
-                // ldx immediate
-                q(UOp::Read{src: Source::Address(self.pc+1), reg: Register::X});
-                self.pc += 2;
-            },
-            0xA4 => {
-                // ldy zpg
-                q(UOp::Read{src: Source::Address(self.pc+1), reg: Register::Scratch1});
-                q(UOp::Read{src: Source::RegVal(Register::Scratch1), reg: Register::Y});
-                self.pc += 2;
-            },
-            0xA5 => {
-                // lda zero page
-                q(UOp::Read{src: Source::Address(self.pc+1), reg: Register::Acc});
-                q(UOp::Read{src: Source::RegVal(Register::Acc), reg: Register::Acc});
-                self.pc += 2;
-            },
-            0xA6 => {
-                // ldx zero page
-                q(UOp::Read{src: Source::Address(self.pc+1), reg: Register::X});
-                q(UOp::Read{src: Source::RegVal(Register::X), reg: Register::X});
-                self.pc += 2;
-            },
-            0xA9 => {
-                // lda immediate
-                q(UOp::Read{src: Source::Address(self.pc+1), reg: Register::Acc});
-                self.pc += 2;
-            },
-            0xEA => {
-                q(UOp::Nop);
-                // nop
-                self.pc += 1;
-            },
-            _ => {
-                return Err(format!("Unsupported opcode: 0x{opcode:2X}"));
-            },
-        }
+        assert_eq!(self.program.len(), self.step);
+        let program = decode::program(self.variant, opcode);
+        self.start_program(&program.uops);
+        self.pc = self.pc.wrapping_add(program.length);
         Ok(())
     }
 
@@ -311,20 +662,57 @@ impl W6502 {
         self.outputs.data = Some(value);
         self.outputs.rwb = false;
     }
+    // Writes happen on both halves of the cycle, so sp is only
+    // decremented once the cycle is complete.
+    fn push(&mut self, val: u8) {
+        self.set_addr(0x0100 | self.sp as u16);
+        self.set_data(val);
+        if self.prev_clk {
+            self.sp = self.sp.wrapping_sub(1);
+        }
+    }
     fn mut_reg(&mut self, reg: Register) -> &mut u8{
         match reg {
             Register::Acc => &mut self.acc,
             Register::X => &mut self.x,
             Register::Y => &mut self.y,
+            Register::Sp => &mut self.sp,
+            Register::Flags => &mut self.flags,
             Register::Scratch1 => &mut self.scratch1,
+            Register::Scratch2 => &mut self.scratch2,
+            Register::Zero | Register::AccAndX => unreachable!("{reg:?} can't be written"),
+        }
+    }
+    fn reg(&self, reg: Register) -> u8 {
+        match reg {
+            Register::Acc => self.acc,
+            Register::X => self.x,
+            Register::Y => self.y,
+            Register::Sp => self.sp,
+            Register::Flags => self.flags,
+            Register::Scratch1 => self.scratch1,
+            Register::Scratch2 => self.scratch2,
+            Register::Zero => 0,
+            Register::AccAndX => self.acc & self.x,
         }
     }
 
     // Evaluate the source based on the current state of the cpu.
-    fn source(&mut self, src: Source) -> u16 {
+    fn source(&self, src: Source) -> u16 {
         match src {
             Source::Address(v) => v,
-            Source::RegVal(reg) => *self.mut_reg(reg) as u16,
+            Source::Operand(offset) => self.opcode_addr.wrapping_add(offset),
+            Source::RegVal(reg) => self.reg(reg) as u16,
+            Source::RegValNext(reg) => self.reg(reg).wrapping_add(1) as u16,
+            Source::Ea => self.ea,
+            Source::EaNext => self.ea.wrapping_add(1),
+            Source::EaNextInPage => (self.ea & 0xFF00) | (self.ea.wrapping_add(1) & 0x00FF),
+            Source::EaUnfixed if self.page_crossed => self.ea.wrapping_sub(0x0100),
+            Source::EaUnfixed => self.ea,
+            Source::BranchUnfixed => {
+                (self.opcode_addr.wrapping_add(2) & 0xFF00) | (self.pc & 0x00FF)
+            },
+            Source::Stack => 0x0100 | self.sp as u16,
         }
     }
 }
@@ -346,14 +734,14 @@ mod test {
         // Reset involves clocking the chip with n_reset held low for two cycles. After 6 cycles,
         // the reset vector will be read from 0xFFFC and 0xFFFD, then the chip will execute
         // from that address.
-        let mut cpu = W6502::new();
+        let mut cpu = W6502::new(CpuVariant::W65C02S);
         const RESET_CYCLES : usize = 2;
         const PRE_VECTOR_CYCLES : usize = 6;
 
         let mut inputs = Inputs {
             data: 0xFF,
             n_reset: false,
-            clk: false,
+            ..Inputs::default()
         };
 
         for i in 0 .. RESET_CYCLES {
@@ -382,4 +770,89 @@ mod test {
         cpu.cycle(&inputs).unwrap();
         assert_eq!(0xDEAD, cpu.outputs().address);
     }
+
+    #[test]
+    fn test_irq() {
+        // nops from 0x0200, with the irq handler at 0x0400.
+        let mut mem = vec![0xEA; 0x10000];
+        mem[0xFFFC..].copy_from_slice(&[0x00, 0x02, 0x00, 0x04]);
+        let mut cpu = W6502::new(CpuVariant::W65C02S);
+        let mut inputs = Inputs {
+            n_reset: false,
+            ..Inputs::default()
+        };
+        cpu.cycle(&inputs).unwrap();
+        inputs.n_reset = true;
+        let mut run = |cpu: &mut W6502, n_irq: bool| {
+            inputs.data = mem[cpu.outputs().address as usize];
+            inputs.n_irq = n_irq;
+            cpu.cycle(&inputs).unwrap();
+            if let Some(val) = cpu.outputs().data {
+                mem[cpu.outputs().address as usize] = val;
+            }
+        };
+        // run up to the first nop, and allow interrupts.
+        for _ in 0 .. 9 {
+            run(&mut cpu, true);
+        }
+        assert_eq!(0x0200, cpu.outputs().address);
+        cpu.flags = 0;
+        cpu.sp = 0xFF;
+
+        // the irq is noticed at the end of the following nop.
+        run(&mut cpu, false);
+        run(&mut cpu, false);
+        assert_eq!(0x0201, cpu.outputs().address);
+        assert!(cpu.outputs().sync);
+        let mut addrs = Vec::new();
+        for _ in 0 .. 6 {
+            run(&mut cpu, false);
+            addrs.push(cpu.outputs().address);
+        }
+        assert_eq!(vec![0x0201, 0x01FF, 0x01FE, 0x01FD, 0xFFFE, 0xFFFF], addrs);
+        run(&mut cpu, false);
+        assert_eq!(0x0400, cpu.outputs().address);
+        // pc and flags (with the unused bit set) were pushed.
+        assert_eq!(&[0x20, 0x01, 0x02], &mem[0x01FD ..= 0x01FF]);
+        assert_eq!(FLAG_I, cpu.flags & FLAG_I);
+    }
+
+    #[test]
+    fn test_nmi_rdy_so() {
+        // nops from 0x0200, with the nmi handler at 0x0500.
+        let mut mem = vec![0xEA; 0x10000];
+        mem[0xFFFA..].copy_from_slice(&[0x00, 0x05, 0x00, 0x02, 0x00, 0x04]);
+        let mut cpu = W6502::new(CpuVariant::W65C02S);
+        cpu.cycle(&Inputs { n_reset: false, ..Inputs::default() }).unwrap();
+        let mut run = |cpu: &mut W6502, pins: Inputs| {
+            cpu.cycle(&Inputs { data: mem[cpu.outputs().address as usize], ..pins }).unwrap();
+            if let Some(val) = cpu.outputs().data {
+                mem[cpu.outputs().address as usize] = val;
+            }
+            cpu.outputs().address
+        };
+        for _ in 0 .. 9 {
+            run(&mut cpu, Inputs::default());
+        }
+        assert_eq!(0x0200, cpu.outputs().address);
+
+        // rdy low holds the opcode fetch.
+        let not_ready = Inputs { rdy: false, ..Inputs::default() };
+        assert_eq!(0x0200, run(&mut cpu, not_ready));
+        assert_eq!(0x0200, run(&mut cpu, not_ready));
+        assert_eq!(0x0201, run(&mut cpu, Inputs::default()));
+        assert_eq!(0x0201, run(&mut cpu, Inputs::default()));
+
+        // so sets overflow on its falling edge.
+        cpu.flags &= !FLAG_V;
+        run(&mut cpu, Inputs { n_so: false, ..Inputs::default() });
+        assert_eq!(FLAG_V, cpu.flags & FLAG_V);
+
+        // nmi is taken despite I being set, and only once while held low.
+        assert_eq!(FLAG_I, cpu.flags & FLAG_I);
+        let nmi = Inputs { n_nmi: false, ..Inputs::default() };
+        let addrs : Vec<u16> = (0 .. 10).map(|_| run(&mut cpu, nmi)).collect();
+        assert_eq!(vec![0x0202, 0x0202, 0x01FC, 0x01FB, 0x01FA,
+                        0xFFFA, 0xFFFB, 0x0500, 0x0501, 0x0501], addrs);
+    }
 }
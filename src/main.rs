@@ -1,3 +1,626 @@
-fn main() {
-    println!("Hello, world!");
+// Command line front end for the model.
+//
+// run: Loads a program into a memory map, resets the model, and runs it until it
+// stops, then reports the final state. This is used for running firmware smoke
+// tests headlessly, so the exit code reflects how the run went:
+//   0 - stopped normally (cycle limit, trap, brk, stp or jam, and --pass was met)
+//   1 - the model failed, or --pass was given and the run stopped elsewhere
+//   2 - bad arguments, or the program could not be loaded
+//
+// verify-trace: Checks the model against a chiplab trace, the same way the
+// trace tests do. The exit code mirrors TraceFailure:
+//   0 - the model matches the trace
+//   1 - Incorrect, the model diverged from the trace
+//   2 - BadSetup, e.g. a bad signature or input checksum, or bad arguments
+//
+// record-trace: Runs the model on a program the way the chiplab does, and writes
+// the trace it produces in the chiplab log format.
+//   0 - the trace was written
+//   1 - the model failed partway, the trace up to the failure was written
+//   2 - bad arguments, or the program or output could not be opened
+//
+// scoreboard: Shows how far the model gets through each trace in a directory,
+// failing_traces/ by default. Traces that now pass are flagged so that they can
+// be moved to passing_traces/.
+//   0 - the scoreboard was shown
+//   2 - bad arguments, or the directory could not be read
+//
+// debug: Loads a program as for run, and starts an interactive debugger on it.
+//   0 - the debugger was quit
+//   2 - bad arguments, or the program could not be loaded
+//
+// gdb: Loads a program as for run, and serves a gdb client on localhost.
+//   0 - the client detached or disconnected
+//   1 - the connection failed
+//   2 - bad arguments, or the program could not be loaded
+//
+// assemble: Builds a 64K image from 65C02 assembly source.
+//   0 - the image was written
+//   1 - the source has errors
+//   2 - bad arguments, or a file could not be read or written
+use std::cell::RefCell;
+use std::io::Write;
+use std::net::TcpListener;
+use std::process::ExitCode;
+use std::rc::Rc;
+
+use model_6502::acia::Acia;
+use model_6502::assembler::assemble;
+use model_6502::bus::Bus;
+use model_6502::debugger::{hexdump, Debugger};
+use model_6502::disasm::disassemble;
+use model_6502::gdb;
+use model_6502::memory_map::{MemoryMap, MemoryMapBuilder};
+use model_6502::runner::{Runner, Stepping, Stop};
+use model_6502::symbols::Symbols;
+use model_6502::trace_tests::{record_trace, score_traces, verify_trace, Score, TraceFailure,
+                              CONTEXT_CYCLES, TRACE_CYCLES};
+use model_6502::via::Via;
+use model_6502::CpuVariant;
+use pki_util::trace::TraceChecker;
+
+// The key chiplab uses to sign traces.
+const TRACE_PUBLIC_KEY: &[u8] = include_bytes!("../chiplab_trace_signing.bin.pub");
+
+const USAGE: &str = "\
+usage: model_6502 run [options]
+       model_6502 debug [options]
+       model_6502 gdb [options] [--port N]
+       model_6502 verify-trace LOG BIN [--key PUBLIC_KEY] [--context N]
+       model_6502 record-trace BIN [--cycles N] [--output LOG] [--variant NAME]
+       model_6502 scoreboard [DIR] [--key PUBLIC_KEY]
+       model_6502 assemble ASM --output BIN
+
+verify-trace checks the model against a signed chiplab trace LOG, recorded
+while running BIN. The chiplab signing key is built in, but can be overridden.
+If the model diverges, N cycles either side of the divergence are shown (default 5).
+
+record-trace runs BIN the way the chiplab does, and writes the model's trace in
+the same format (unsigned), for N cycles (default 100), to LOG or stdout.
+
+scoreboard shows how many cycles of each trace in DIR (default failing_traces)
+the model matches, and where it diverges.
+
+assemble builds a 64K image from 65C02 assembly, in the dialect used by the
+traces' .asm files.
+
+run and record-trace model a W65C02S, unless --variant gives another cpu:
+6502 (NMOS), w65c02s, r65c02, 65sc02 or 2a03 (NES).
+
+Memory map (addresses are hex):
+  --image FILE        load a 64K image as RAM
+  --ram START-END     add RAM
+  --rom ADDR:FILE     add ROM at ADDR, loaded from FILE
+  --via ADDR          add a 6522 VIA occupying ADDR to ADDR+F
+  --acia ADDR         add a 6551 ACIA occupying ADDR to ADDR+3, connected to stdin/stdout
+
+Running:
+  --cycles N          stop after N cycles
+  --trap ADDR         stop before executing the instruction at ADDR (repeatable)
+  --pass ADDR         fail unless the run stops at trap ADDR (implies --trap ADDR)
+  --symbols FILE      load symbols from a ca65/ld65 .dbg or label (-Ln) file, or
+                      the labels of a .asm file (repeatable). They label
+                      disassembly, and --trap and --pass can name them
+  --dump START-END    print a range of memory after stopping (repeatable)
+  --fast              run whole instructions at a time, rather than each cycle.
+                      Registers and memory are the same, but devices don't see
+                      dummy reads, and --cycles may be overrun by an instruction
+  --variant NAME      the cpu to model
+  --back N            after stopping, step back N instructions before showing
+                      the registers and memory. Devices aren't stepped back.
+                      Keeping the history runs each cycle, so --fast is ignored
+
+Runs stop on the cycle limit, a trap, or a BRK, STP or JAM (on the 6502) instruction.
+
+debug loads the memory map the same way, and prompts for commands to step, set
+breakpoints and watchpoints, and look at registers and memory. Traps become
+breakpoints, and the other running options don't apply. Type help at the prompt
+for the commands.
+
+gdb serves the gdb remote protocol on localhost port N (default 1234), for gdb,
+lldb or an IDE to debug the program, e.g. with 'target remote :1234' in gdb.
+Traps become breakpoints, and --variant applies, as for debug.
+";
+
+const EXIT_FAILED: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+
+const DEFAULT_GDB_PORT: u16 = 1234;
+
+// The most instructions --back can step back. Each keeps up to 8 cycles of
+// history.
+const MAX_BACK: u64 = 10_000_000;
+
+struct RunArgs {
+    map: MemoryMapBuilder,
+    cycles: Option<u64>,
+    traps: Vec<u16>,
+    pass: Option<u16>,
+    dumps: Vec<(u16, u16)>,
+    stepping: Stepping,
+    variant: CpuVariant,
+    back: Option<u64>,
+    symbols: Symbols,
+}
+
+// Parse a hex address, optionally prefixed with $ or 0x.
+fn parse_addr(s: &str) -> Result<u16, String> {
+    let digits = s.strip_prefix('$')
+        .or(s.strip_prefix("0x"))
+        .unwrap_or(s);
+    u16::from_str_radix(digits, 16).or(Err(format!("Bad address: '{s}'")))
+}
+
+fn parse_variant(s: &str) -> Result<CpuVariant, String> {
+    CpuVariant::from_name(s).ok_or(format!("Unknown variant: '{s}'"))
+}
+
+// Parse START-END into an inclusive range.
+fn parse_range(s: &str) -> Result<(u16, u16), String> {
+    let (start, end) = s.split_once('-').ok_or(format!("Bad range: '{s}'"))?;
+    let (start, end) = (parse_addr(start)?, parse_addr(end)?);
+    if start > end {
+        return Err(format!("Bad range: '{s}'"));
+    }
+    Ok((start, end))
+}
+
+fn parse_run_args(args: &[String]) -> Result<RunArgs, String> {
+    let mut run = RunArgs {
+        map: MemoryMapBuilder::new(),
+        cycles: None,
+        traps: Vec::new(),
+        pass: None,
+        dumps: Vec::new(),
+        stepping: Stepping::Cycle,
+        variant: CpuVariant::default(),
+        back: None,
+        symbols: Symbols::new(),
+    };
+    // Traps are resolved once all the symbols are loaded.
+    let mut traps = Vec::new();
+    let mut pass = None;
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {flag}"));
+        match flag.as_str() {
+            "--image" => {
+                let path = value()?;
+                let image = std::fs::read(path)
+                    .or(Err(format!("Failed to read image: '{path}'")))?;
+                if image.len() != 0x10000 {
+                    return Err(format!("Expected a 64K image, '{path}' has {} bytes", image.len()));
+                }
+                run.map = run.map.ram_image(0, &image)?;
+            },
+            "--ram" => {
+                let (start, end) = parse_range(value()?)?;
+                run.map = run.map.ram(start ..= end);
+            },
+            "--rom" => {
+                let arg = value()?;
+                let (addr, path) = arg.split_once(':').ok_or(format!("Bad rom: '{arg}'"))?;
+                run.map = run.map.rom_file(parse_addr(addr)?, path)?;
+            },
+            "--via" => {
+                let arg = value()?;
+                let addr = parse_addr(arg)?;
+                let end = addr.checked_add(0xF).ok_or(format!("Bad via address: '{arg}'"))?;
+                run.map = run.map.device(addr ..= end, Box::new(Via::new()));
+            },
+            "--acia" => {
+                let arg = value()?;
+                let addr = parse_addr(arg)?;
+                let end = addr.checked_add(0x3).ok_or(format!("Bad acia address: '{arg}'"))?;
+                let mut acia = Acia::new(std::io::stdin(), std::io::stdout());
+                acia.set_translate_newlines(true);
+                run.map = run.map.device(addr ..= end, Box::new(Rc::new(RefCell::new(acia))));
+            },
+            "--cycles" => {
+                let n = value()?;
+                run.cycles = Some(n.parse().or(Err(format!("Bad cycle count: '{n}'")))?);
+            },
+            "--trap" => traps.push(value()?),
+            "--pass" => {
+                let addr = value()?;
+                traps.push(addr);
+                pass = Some(addr);
+            },
+            "--symbols" => run.symbols.load(value()?)?,
+            "--dump" => run.dumps.push(parse_range(value()?)?),
+            "--fast" => run.stepping = Stepping::Instruction,
+            "--variant" => run.variant = parse_variant(value()?)?,
+            "--back" => {
+                let n = value()?;
+                run.back = Some(n.parse().ok()
+                    .filter(|&n| n <= MAX_BACK)
+                    .ok_or(format!("Bad instruction count: '{n}', at most {MAX_BACK}"))?);
+            },
+            _ => return Err(format!("Unknown option: '{flag}'")),
+        }
+    }
+    for trap in traps {
+        run.traps.push(run.symbols.resolve(trap)?);
+    }
+    run.pass = pass.map(|addr| run.symbols.resolve(addr)).transpose()?;
+    Ok(run)
+}
+
+fn run(args: &[String]) -> ExitCode {
+    let run = match parse_run_args(args) {
+        Ok(run) => run,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        },
+    };
+    let map : MemoryMap = match run.map.build() {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Bad memory map: {e}");
+            return ExitCode::from(EXIT_USAGE);
+        },
+    };
+
+    let mut runner = Runner::with_variant(map, run.variant);
+    runner.set_stepping(run.stepping);
+    for trap in run.traps {
+        runner.add_trap(trap);
+    }
+    if let Some(back) = run.back {
+        // Enough history for back instructions of up to 8 cycles.
+        const INTERVAL: u64 = 1000;
+        runner.enable_rewind(INTERVAL, (back * 8 / INTERVAL) as usize + 2);
+    }
+    let stop = runner.run(run.cycles);
+
+    println!("stopped: {stop} after {} cycles", runner.cycles());
+    for _ in 0 .. run.back.unwrap_or(0) {
+        if let Err(e) = runner.step_back_instruction() {
+            println!("can't step back further: {e}");
+            break;
+        }
+    }
+    if run.back.is_some() {
+        println!("stepped back to {} cycles", runner.cycles());
+    }
+    println!("{}", runner.cpu().registers());
+    let pc = runner.cpu().registers().pc;
+    let bytes : Vec<u8> = (0 .. 3).map(|i| runner.bus().peek(pc.wrapping_add(i))).collect();
+    if let Some(next) = disassemble(&bytes, pc) {
+        let location = match run.symbols.label(pc) {
+            Some(label) => format!("${pc:04X} {label}"),
+            None => format!("${pc:04X}"),
+        };
+        println!("next: {location}: {}", next.symbolic(&run.symbols));
+    }
+    for (start, end) in run.dumps {
+        println!("{}", hexdump(runner.bus(), start, end));
+    }
+    for violation in runner.bus().violations() {
+        println!("rom write: ${:04X} = {:02X}", violation.addr, violation.val);
+    }
+    let unlisted = runner.bus().violation_count() - runner.bus().violations().len() as u64;
+    if unlisted > 0 {
+        println!("rom write: {unlisted} more");
+    }
+
+    let passed = match (&stop, run.pass) {
+        (Stop::Error(_), _) => false,
+        (_, None) => true,
+        (stop, Some(addr)) => *stop == Stop::Trap(addr),
+    };
+    if passed {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_FAILED)
+    }
+}
+
+// The run options that apply to debugging: the memory map, traps and variant.
+fn parse_debug_args(args: &[String], command: &str) -> Result<RunArgs, String> {
+    let run = parse_run_args(args)?;
+    if run.cycles.is_some() || run.pass.is_some() || !run.dumps.is_empty()
+            || run.stepping != Stepping::Cycle || run.back.is_some() {
+        return Err(format!("Only the memory map, --trap and --variant apply to {command}"));
+    }
+    Ok(run)
+}
+
+fn debug(args: &[String]) -> ExitCode {
+    let run = match parse_debug_args(args, "debug") {
+        Ok(run) => run,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        },
+    };
+    let map : MemoryMap = match run.map.build() {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Bad memory map: {e}");
+            return ExitCode::from(EXIT_USAGE);
+        },
+    };
+
+    let mut debugger = Debugger::new(map, run.variant);
+    debugger.set_symbols(run.symbols);
+    for trap in run.traps {
+        debugger.add_breakpoint(trap);
+    }
+    if let Err(e) = debugger.repl(&mut std::io::stdin().lock(), &mut std::io::stdout()) {
+        eprintln!("{e}");
+    }
+    ExitCode::SUCCESS
+}
+
+fn gdb(args: &[String]) -> ExitCode {
+    let mut port = DEFAULT_GDB_PORT;
+    let mut run_args = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg != "--port" {
+            run_args.push(arg.clone());
+            continue;
+        }
+        match args.next().map(|n| n.parse()) {
+            Some(Ok(n)) => port = n,
+            _ => {
+                eprintln!("Bad or missing port\n\n{USAGE}");
+                return ExitCode::from(EXIT_USAGE);
+            },
+        }
+    }
+    let run = match parse_debug_args(&run_args, "gdb") {
+        Ok(run) => run,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        },
+    };
+    let map : MemoryMap = match run.map.build() {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Bad memory map: {e}");
+            return ExitCode::from(EXIT_USAGE);
+        },
+    };
+
+    let mut runner = Runner::with_variant(map, run.variant);
+    for trap in run.traps {
+        runner.add_trap(trap);
+    }
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen on port {port}: {e}");
+            return ExitCode::from(EXIT_FAILED);
+        },
+    };
+    eprintln!("waiting for gdb on 127.0.0.1:{port}");
+    match gdb::serve(&mut runner, &listener) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(EXIT_FAILED)
+        },
+    }
+}
+
+struct VerifyArgs {
+    log_path: String,
+    input_path: String,
+    key: Vec<u8>,
+    context: usize,
+}
+
+fn parse_verify_args(args: &[String]) -> Result<VerifyArgs, String> {
+    let mut paths = Vec::new();
+    let mut key = TRACE_PUBLIC_KEY.to_vec();
+    let mut context = CONTEXT_CYCLES;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--key" => {
+                let path = value()?;
+                key = std::fs::read(path).or(Err(format!("Failed to read key: '{path}'")))?;
+            },
+            "--context" => {
+                let n = value()?;
+                context = n.parse().or(Err(format!("Bad context: '{n}'")))?;
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: '{arg}'")),
+            _ => paths.push(arg.clone()),
+        }
+    }
+    let [log_path, input_path] = <[String; 2]>::try_from(paths)
+        .or(Err("Expected a log and a bin file"))?;
+    Ok(VerifyArgs { log_path, input_path, key, context })
+}
+
+fn verify(args: &[String]) -> ExitCode {
+    let args = match parse_verify_args(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        },
+    };
+    let checker = TraceChecker::new(&args.key);
+    match verify_trace(&checker, &args.log_path, &args.input_path, args.context) {
+        Ok(()) => {
+            println!("pass");
+            ExitCode::SUCCESS
+        },
+        Err(TraceFailure::Incorrect(report)) => {
+            println!("model diverged from trace: {report}");
+            ExitCode::from(EXIT_FAILED)
+        },
+        Err(TraceFailure::BadSetup(e)) => {
+            eprintln!("bad setup: {e}");
+            ExitCode::from(EXIT_USAGE)
+        },
+    }
+}
+
+struct RecordArgs {
+    input_path: String,
+    cycles: usize,
+    output: Option<String>,
+    variant: CpuVariant,
+}
+
+fn parse_record_args(args: &[String]) -> Result<RecordArgs, String> {
+    let mut input_path = None;
+    let mut cycles = TRACE_CYCLES;
+    let mut output = None;
+    let mut variant = CpuVariant::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--cycles" => {
+                let n = value()?;
+                cycles = n.parse().or(Err(format!("Bad cycle count: '{n}'")))?;
+            },
+            "--output" => output = Some(value()?.clone()),
+            "--variant" => variant = parse_variant(value()?)?,
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: '{arg}'")),
+            _ if input_path.is_none() => input_path = Some(arg.clone()),
+            _ => return Err(format!("Unexpected argument: '{arg}'")),
+        }
+    }
+    let input_path = input_path.ok_or("Expected a bin file")?;
+    Ok(RecordArgs { input_path, cycles, output, variant })
+}
+
+fn record(args: &[String]) -> ExitCode {
+    let args = match parse_record_args(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        },
+    };
+    let Ok(input) = std::fs::read(&args.input_path) else {
+        eprintln!("Failed to read input file: '{}'", args.input_path);
+        return ExitCode::from(EXIT_USAGE);
+    };
+    if input.len() != 0x10000 {
+        eprintln!("Expected a 64K input, '{}' has {} bytes", args.input_path, input.len());
+        return ExitCode::from(EXIT_USAGE);
+    }
+    let mut out : Box<dyn Write> = match &args.output {
+        None => Box::new(std::io::stdout().lock()),
+        Some(path) => match std::fs::File::create(path) {
+            Ok(file) => Box::new(std::io::BufWriter::new(file)),
+            Err(e) => {
+                eprintln!("Failed to create '{path}': {e}");
+                return ExitCode::from(EXIT_USAGE);
+            },
+        },
+    };
+    let result = record_trace(&input, args.variant, args.cycles, &mut out);
+    let _ = out.flush();
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("model failed: {e}");
+            ExitCode::from(EXIT_FAILED)
+        },
+    }
+}
+
+fn parse_scoreboard_args(args: &[String]) -> Result<(String, Vec<u8>), String> {
+    let mut directory = None;
+    let mut key = TRACE_PUBLIC_KEY.to_vec();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key" => {
+                let path = args.next().ok_or(format!("Missing value for {arg}"))?;
+                key = std::fs::read(path).or(Err(format!("Failed to read key: '{path}'")))?;
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: '{arg}'")),
+            _ if directory.is_none() => directory = Some(arg.clone()),
+            _ => return Err(format!("Unexpected argument: '{arg}'")),
+        }
+    }
+    Ok((directory.unwrap_or("failing_traces".to_string()), key))
+}
+
+fn scoreboard(args: &[String]) -> ExitCode {
+    let (directory, key) = match parse_scoreboard_args(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        },
+    };
+    let scores = match score_traces(&TraceChecker::new(&key), &directory) {
+        Ok(scores) => scores,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(EXIT_USAGE);
+        },
+    };
+
+    println!("{:<40}{:>8}  {:<8}instruction", "trace", "matched", "signal");
+    for (path, score) in scores {
+        let name = path.rsplit('/').next().unwrap_or(&path);
+        match score {
+            Score::Passing => println!("{name:<40}{:>8}  passes, move to passing_traces/", "all"),
+            Score::Diverged(report) => {
+                println!("{name:<40}{:>8}  {:<8}{}", report.matched_cycles,
+                         report.signal.unwrap_or("model"), report.instruction);
+            },
+            Score::BadSetup(e) => println!("{name:<40}{:>8}  bad setup: {e}", "-"),
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn assemble_file(args: &[String]) -> ExitCode {
+    let [source_path, flag, output_path] = args else {
+        eprint!("{USAGE}");
+        return ExitCode::from(EXIT_USAGE);
+    };
+    if flag != "--output" {
+        eprint!("{USAGE}");
+        return ExitCode::from(EXIT_USAGE);
+    }
+    let Ok(source) = std::fs::read_to_string(source_path) else {
+        eprintln!("Failed to read source: '{source_path}'");
+        return ExitCode::from(EXIT_USAGE);
+    };
+    let image = match assemble(&source) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("{source_path}: {e}");
+            return ExitCode::from(EXIT_FAILED);
+        },
+    };
+    if let Err(e) = std::fs::write(output_path, image) {
+        eprintln!("Failed to write '{output_path}': {e}");
+        return ExitCode::from(EXIT_USAGE);
+    }
+    ExitCode::SUCCESS
+}
+
+fn main() -> ExitCode {
+    let args : Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(|s| s.as_str()) {
+        Some("run") => run(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("gdb") => gdb(&args[1..]),
+        Some("verify-trace") => verify(&args[1..]),
+        Some("record-trace") => record(&args[1..]),
+        Some("scoreboard") => scoreboard(&args[1..]),
+        Some("assemble") => assemble_file(&args[1..]),
+        _ => {
+            eprint!("{USAGE}");
+            ExitCode::from(EXIT_USAGE)
+        },
+    }
 }
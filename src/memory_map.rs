@@ -0,0 +1,434 @@
+// Memory Map
+// A Bus assembled from regions of RAM, ROM and memory mapped devices.
+//
+// A typical hobby board might be described as:
+//
+//   let map = MemoryMapBuilder::new()
+//       .ram(0x0000..=0x7FFF)
+//       .device(0x6000..=0x600F, Box::new(via))
+//       .rom_file(0xC000, "firmware.bin")?
+//       .build()?;
+//
+// Regions may not overlap, except that a device may sit on top of RAM or
+// ROM, in which case the device wins. This matches the common practice of
+// decoding I/O out of a hole in a larger memory chip.
+//
+// Reads of unmapped addresses return whatever value was last on the data bus
+// ("open bus"), as the real data lines would still hold it. Writes to ROM are
+// ignored like on real hardware, but counted, and the first few recorded so
+// that callers can report them.
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use crate::bus::Bus;
+
+// How many rom writes are kept. A program stuck writing rom would otherwise
+// grow the list without bound.
+const MAX_VIOLATIONS: usize = 16;
+
+// Something other than plain memory which responds to a range of addresses.
+pub trait Device {
+    // offset is relative to the start of the device's region.
+    fn read(&mut self, offset: u16) -> u8;
+    fn write(&mut self, offset: u16, val: u8);
+    // The value a read would return, if it can be known without
+    // disturbing the device. Most devices have read side effects.
+    fn peek(&self, _offset: u16) -> Option<u8> {
+        None
+    }
+    // Called once per cpu cycle.
+    fn tick(&mut self) {}
+    // True while the device is requesting an interrupt.
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+// Devices are owned by the map, so a shared handle lets the caller keep
+// access to the device's pins (e.g. to wire a VIA port to something else).
+impl<D: Device> Device for Rc<RefCell<D>> {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.borrow_mut().read(offset)
+    }
+    fn write(&mut self, offset: u16, val: u8) {
+        self.borrow_mut().write(offset, val)
+    }
+    fn peek(&self, offset: u16) -> Option<u8> {
+        self.borrow().peek(offset)
+    }
+    fn tick(&mut self) {
+        self.borrow_mut().tick()
+    }
+    fn irq(&self) -> bool {
+        self.borrow().irq()
+    }
+}
+
+// A write to a read only region.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WriteViolation {
+    pub addr: u16,
+    pub val: u8,
+}
+
+enum Backing {
+    Ram(Vec<u8>),
+    Rom(Vec<u8>),
+    Device(Box<dyn Device>),
+    // Accesses are redirected to the given range, repeating as many
+    // times as needed to fill the region.
+    Mirror(RangeInclusive<u16>),
+}
+
+struct Region {
+    range: RangeInclusive<u16>,
+    backing: Backing,
+}
+
+// Zero for a reversed range, which build() then rejects.
+fn range_len(range: &RangeInclusive<u16>) -> usize {
+    if range.is_empty() {
+        return 0;
+    }
+    *range.end() as usize - *range.start() as usize + 1
+}
+
+fn overlaps(a: &RangeInclusive<u16>, b: &RangeInclusive<u16>) -> bool {
+    a.start() <= b.end() && b.start() <= a.end()
+}
+
+#[derive(Default)]
+pub struct MemoryMapBuilder {
+    regions: Vec<Region>,
+}
+
+impl MemoryMapBuilder {
+    pub fn new() -> MemoryMapBuilder {
+        MemoryMapBuilder::default()
+    }
+
+    // RAM covering the whole range, initially zeroed.
+    pub fn ram(self, range: RangeInclusive<u16>) -> MemoryMapBuilder {
+        let len = range_len(&range);
+        self.region(range, Backing::Ram(vec![0; len]))
+    }
+
+    // RAM starting at start, with initial contents taken from data.
+    pub fn ram_image(self, start: u16, data: &[u8]) -> Result<MemoryMapBuilder, String> {
+        let range = image_range(start, data)?;
+        Ok(self.region(range, Backing::Ram(data.to_vec())))
+    }
+
+    // ROM starting at start, holding data.
+    pub fn rom(self, start: u16, data: &[u8]) -> Result<MemoryMapBuilder, String> {
+        let range = image_range(start, data)?;
+        Ok(self.region(range, Backing::Rom(data.to_vec())))
+    }
+
+    // ROM starting at start, loaded from a binary file.
+    pub fn rom_file(self, start: u16, path: &str) -> Result<MemoryMapBuilder, String> {
+        let data = std::fs::read(path)
+            .or(Err(format!("Failed to read rom file: '{path}'")))?;
+        self.rom(start, &data)
+    }
+
+    // Device responding to the whole range. The device sees offsets relative
+    // to the start of the range.
+    pub fn device(self, range: RangeInclusive<u16>, device: Box<dyn Device>) -> MemoryMapBuilder {
+        self.region(range, Backing::Device(device))
+    }
+
+    // Make range an alias for target. If range is larger than target, the
+    // target repeats. For example, mirror(0x0800..=0x1FFF, 0x0000..=0x07FF)
+    // gives three more copies of the first 2K.
+    pub fn mirror(self, range: RangeInclusive<u16>, target: RangeInclusive<u16>) -> MemoryMapBuilder {
+        self.region(range, Backing::Mirror(target))
+    }
+
+    fn region(mut self, range: RangeInclusive<u16>, backing: Backing) -> MemoryMapBuilder {
+        self.regions.push(Region { range, backing });
+        self
+    }
+
+    pub fn build(mut self) -> Result<MemoryMap, String> {
+        for (i, a) in self.regions.iter().enumerate() {
+            if a.range.is_empty() {
+                return Err(format!("Empty region: {:04X}-{:04X}", a.range.start(), a.range.end()));
+            }
+            for b in &self.regions[i + 1 ..] {
+                let is_device = |r: &Region| matches!(r.backing, Backing::Device(_));
+                if overlaps(&a.range, &b.range) && is_device(a) == is_device(b) {
+                    return Err(format!(
+                        "Overlapping regions: {:04X}-{:04X} and {:04X}-{:04X}",
+                        a.range.start(), a.range.end(), b.range.start(), b.range.end()));
+                }
+            }
+            if let Backing::Mirror(target) = &a.backing {
+                let ok = !target.is_empty() && self.regions.iter().any(|r| {
+                    !matches!(r.backing, Backing::Mirror(_))
+                        && r.range.start() <= target.start() && target.end() <= r.range.end()
+                });
+                if !ok {
+                    return Err(format!(
+                        "Mirror target {:04X}-{:04X} is not mapped", target.start(), target.end()));
+                }
+            }
+        }
+        // Devices are checked first, so they may shadow memory.
+        self.regions.sort_by_key(|r| !matches!(r.backing, Backing::Device(_)));
+        Ok(MemoryMap {
+            regions: self.regions,
+            open_bus: 0,
+            violations: Vec::new(),
+            violation_count: 0,
+        })
+    }
+}
+
+fn image_range(start: u16, data: &[u8]) -> Result<RangeInclusive<u16>, String> {
+    let end = start as usize + data.len();
+    if data.is_empty() || end > 0x10000 {
+        return Err(format!(
+            "Image of {} bytes does not fit at 0x{start:04X}", data.len()));
+    }
+    Ok(start ..= (end - 1) as u16)
+}
+
+pub struct MemoryMap {
+    regions: Vec<Region>,
+    // Last value seen on the data bus, returned for unmapped reads.
+    open_bus: u8,
+    violations: Vec<WriteViolation>,
+    violation_count: u64,
+}
+
+impl MemoryMap {
+    // A single RAM region covering all 64K, as used by the trace tests.
+    pub fn from_image(image: &[u8]) -> Result<MemoryMap, String> {
+        if image.len() != 0x10000 {
+            return Err(format!("Expected a 64K image, had {} bytes", image.len()));
+        }
+        MemoryMapBuilder::new().ram_image(0, image)?.build()
+    }
+
+    // The first writes to ROM seen so far.
+    pub fn violations(&self) -> &[WriteViolation] {
+        &self.violations
+    }
+
+    // All writes to ROM seen so far, including those not kept.
+    pub fn violation_count(&self) -> u64 {
+        self.violation_count
+    }
+
+    pub fn take_violations(&mut self) -> Vec<WriteViolation> {
+        self.violation_count = 0;
+        std::mem::take(&mut self.violations)
+    }
+
+    // Returns the region index and offset within that region for addr,
+    // following mirrors.
+    fn resolve(&self, addr: u16) -> Option<(usize, usize)> {
+        let find = |addr: u16| self.regions.iter().position(|r| r.range.contains(&addr));
+        let index = find(addr)?;
+        let region = &self.regions[index];
+        let offset = (addr - region.range.start()) as usize;
+        match &region.backing {
+            Backing::Mirror(target) => {
+                let addr = *target.start() as usize + offset % range_len(target);
+                let index = find(addr as u16)?;
+                let region = &self.regions[index];
+                Some((index, addr - *region.range.start() as usize))
+            },
+            _ => Some((index, offset)),
+        }
+    }
+}
+
+impl Bus for MemoryMap {
+    fn read(&mut self, addr: u16) -> u8 {
+        let val = match self.resolve(addr) {
+            None => self.open_bus,
+            Some((index, offset)) => match &mut self.regions[index].backing {
+                Backing::Ram(data) | Backing::Rom(data) => data[offset],
+                Backing::Device(dev) => dev.read(offset as u16),
+                Backing::Mirror(_) => self.open_bus,
+            },
+        };
+        self.open_bus = val;
+        val
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.open_bus = val;
+        let Some((index, offset)) = self.resolve(addr) else {
+            return;
+        };
+        match &mut self.regions[index].backing {
+            Backing::Ram(data) => data[offset] = val,
+            Backing::Rom(_) => {
+                self.violation_count += 1;
+                if self.violations.len() < MAX_VIOLATIONS {
+                    self.violations.push(WriteViolation { addr, val });
+                }
+            },
+            Backing::Device(dev) => dev.write(offset as u16, val),
+            Backing::Mirror(_) => {},
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        let Some((index, offset)) = self.resolve(addr) else {
+            return self.open_bus;
+        };
+        match &self.regions[index].backing {
+            Backing::Ram(data) | Backing::Rom(data) => data[offset],
+            Backing::Device(dev) => dev.peek(offset as u16).unwrap_or(self.open_bus),
+            Backing::Mirror(_) => self.open_bus,
+        }
+    }
+
+    fn tick(&mut self) {
+        for region in &mut self.regions {
+            if let Backing::Device(dev) = &mut region.backing {
+                dev.tick();
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.regions.iter().any(|r| match &r.backing {
+            Backing::Device(dev) => dev.irq(),
+            _ => false,
+        })
+    }
+
+    fn is_memory(&self, addr: u16) -> bool {
+        self.resolve(addr).is_some_and(|(index, _)| {
+            matches!(self.regions[index].backing, Backing::Ram(_) | Backing::Rom(_))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Remembers the last write, and reads back offset + 1.
+    struct TestDevice {
+        last_write: (u16, u8),
+    }
+    impl Device for TestDevice {
+        fn read(&mut self, offset: u16) -> u8 {
+            offset as u8 + 1
+        }
+        fn write(&mut self, offset: u16, val: u8) {
+            self.last_write = (offset, val);
+        }
+    }
+
+    #[test]
+    fn test_ram_rom_open_bus() {
+        let mut map = MemoryMapBuilder::new()
+            .ram(0x0000..=0x7FFF)
+            .rom(0xC000, &[0xEA; 0x4000]).unwrap()
+            .build().unwrap();
+        map.write(0x1234, 0x56);
+        assert_eq!(0x56, map.read(0x1234));
+        assert_eq!(0xEA, map.read(0xFFFF));
+        // unmapped reads see the last value on the bus.
+        assert_eq!(0xEA, map.read(0x9000));
+        map.write(0x0000, 0x12);
+        assert_eq!(0x12, map.read(0x9000));
+    }
+
+    #[test]
+    fn test_rom_write_violation() {
+        let mut map = MemoryMapBuilder::new()
+            .rom(0xC000, &[0x00; 0x4000]).unwrap()
+            .build().unwrap();
+        map.write(0xC010, 0x42);
+        assert_eq!(0x00, map.read(0xC010));
+        assert_eq!(&[WriteViolation { addr: 0xC010, val: 0x42 }], map.violations());
+        map.take_violations();
+        assert!(map.violations().is_empty());
+
+        // only the first few are kept, but all are counted.
+        for _ in 0 .. 1000 {
+            map.write(0xC000, 0x01);
+        }
+        assert_eq!(MAX_VIOLATIONS, map.violations().len());
+        assert_eq!(1000, map.violation_count());
+        map.take_violations();
+        assert_eq!(0, map.violation_count());
+    }
+
+    #[test]
+    fn test_mirror_and_device() {
+        let dev = Rc::new(RefCell::new(TestDevice { last_write: (0, 0) }));
+        let mut map = MemoryMapBuilder::new()
+            .ram(0x0000..=0x07FF)
+            .mirror(0x0800..=0x1FFF, 0x0000..=0x07FF)
+            .ram(0x4000..=0x7FFF)
+            .device(0x6000..=0x600F, Box::new(dev.clone()))
+            .build().unwrap();
+        map.write(0x1805, 0x77);
+        assert_eq!(0x77, map.read(0x0005));
+        assert_eq!(0x77, map.read(0x0805));
+
+        // the device shadows the ram beneath it.
+        assert_eq!(0x04, map.read(0x6003));
+        map.write(0x600F, 0x99);
+        assert_eq!((0x0F, 0x99), dev.borrow().last_write);
+        map.write(0x6010, 0x98);
+        assert_eq!(0x98, map.read(0x6010));
+    }
+
+    #[test]
+    fn test_cpu_on_map() {
+        use crate::{CpuVariant, W6502, Inputs};
+        // lda #$42; sta $10; jmp $0204
+        let program = [0xA9, 0x42, 0x85, 0x10, 0x4C, 0x04, 0x02];
+        let mut vectors = [0u8; 6];
+        vectors[2..4].copy_from_slice(&[0x00, 0x02]);  // reset -> 0x0200
+        let mut map = MemoryMapBuilder::new()
+            .ram(0x0000..=0x01FF)
+            .ram_image(0x0200, &program).unwrap()
+            .rom(0xFFFA, &vectors).unwrap()
+            .build().unwrap();
+
+        let mut cpu = W6502::new(CpuVariant::W65C02S);
+        let reset = Inputs { n_reset: false, ..Inputs::default() };
+        for _ in 0 .. 2 {
+            cpu.cycle(&reset).unwrap();
+        }
+        for _ in 0 .. 20 {
+            cpu.cycle_bus(&mut map).unwrap();
+        }
+        assert_eq!(0x42, map.read(0x0010));
+        assert!(map.violations().is_empty());
+    }
+
+    #[test]
+    fn test_overlap_rejected() {
+        let result = MemoryMapBuilder::new()
+            .ram(0x0000..=0x7FFF)
+            .ram(0x7000..=0x8FFF)
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[allow(clippy::reversed_empty_ranges)]
+    fn test_reversed_range_rejected() {
+        let result = MemoryMapBuilder::new()
+            .ram(0x8000..=0x7FFF)
+            .build();
+        assert!(result.is_err());
+        let result = MemoryMapBuilder::new()
+            .ram(0x0000..=0x07FF)
+            .mirror(0x0800..=0x0FFF, 0x0100..=0x00FF)
+            .build();
+        assert!(result.is_err());
+    }
+}
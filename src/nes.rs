@@ -0,0 +1,186 @@
+// NES
+// Enough of the NES to run cpu tests on the Ricoh 2A03 variant: 2K of RAM,
+// and a cartridge using the NROM mapper (iNES mapper 0).
+//
+// The PPU and APU aren't modelled. Their registers read as zero and writes to
+// them are ignored, which is enough for nestest.nes. Its automated mode starts
+// at $C000 and logs the registers before each instruction, and the model is
+// checked against the golden log from that, see test_nestest.
+use crate::bus::Bus;
+use crate::runner::{Runner, Stop};
+use crate::{CpuVariant, Registers};
+
+const INES_HEADER: usize = 16;
+const INES_TRAINER: usize = 512;
+const PRG_BANK: usize = 0x4000;
+
+pub struct Nrom {
+    ram: [u8; 0x800],
+    // 16K or 32K of program rom at $8000, with 16K mirrored to fill the space.
+    prg: Vec<u8>,
+}
+
+impl Nrom {
+    // Load the program rom from an iNES file. The character rom is only used
+    // by the PPU, so is ignored.
+    pub fn from_ines(data: &[u8]) -> Result<Nrom, String> {
+        if data.len() < INES_HEADER || &data[0 .. 4] != b"NES\x1A" {
+            return Err("Not an iNES file".to_string());
+        }
+        let mapper = (data[6] >> 4) | (data[7] & 0xF0);
+        if mapper != 0 {
+            return Err(format!("Unsupported mapper: {mapper}, only NROM (0) is"));
+        }
+        let banks = data[4] as usize;
+        if !(1 ..= 2).contains(&banks) {
+            return Err(format!("NROM has 1 or 2 program banks, not {banks}"));
+        }
+        let start = INES_HEADER + if data[6] & 0x04 != 0 { INES_TRAINER } else { 0 };
+        let prg = data.get(start .. start + banks * PRG_BANK)
+            .ok_or("iNES file is shorter than its program rom")?;
+        Ok(Nrom { ram: [0; 0x800], prg: prg.to_vec() })
+    }
+}
+
+impl Bus for Nrom {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        if addr < 0x2000 {
+            self.ram[addr as usize & 0x7FF] = val;
+        }
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000 ..= 0x1FFF => self.ram[addr as usize & 0x7FF],
+            0x8000 ..= 0xFFFF => self.prg[(addr as usize - 0x8000) % self.prg.len()],
+            _ => 0,
+        }
+    }
+    fn is_memory(&self, addr: u16) -> bool {
+        matches!(addr, 0x0000 ..= 0x1FFF | 0x8000 ..= 0xFFFF)
+    }
+}
+
+// The state nestest.log records before each instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogState {
+    pub registers: Registers,
+    // Cycles since power on, which nestest counts from 7 at $C000.
+    pub cycles: u64,
+}
+
+impl LogState {
+    // Parse a line of nestest.log, such as:
+    // C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+    // The instruction bytes, disassembly and ppu position are ignored.
+    pub fn parse(line: &str) -> Result<LogState, String> {
+        let bad = || format!("Bad nestest log line: '{line}'");
+        let field = |name: &str| -> Result<&str, String> {
+            let start = line.find(name).ok_or_else(bad)? + name.len();
+            line[start ..].split_whitespace().next().ok_or_else(bad)
+        };
+        let hex = |name: &str| -> Result<u8, String> {
+            u8::from_str_radix(field(name)?, 16).or(Err(bad()))
+        };
+        let pc = line.get(0 .. 4).and_then(|pc| u16::from_str_radix(pc, 16).ok()).ok_or_else(bad)?;
+        Ok(LogState {
+            registers: Registers {
+                pc,
+                acc: hex("A:")?,
+                x: hex("X:")?,
+                y: hex("Y:")?,
+                sp: hex("SP:")?,
+                flags: hex("P:")?,
+            },
+            cycles: field("CYC:")?.parse().or(Err(bad()))?,
+        })
+    }
+}
+
+impl std::fmt::Display for LogState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let r = &self.registers;
+        write!(f, "{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+               r.pc, r.acc, r.x, r.y, r.flags, r.sp, self.cycles)
+    }
+}
+
+// Run nestest.nes in its automated mode for a number of instructions, a whole
+// instruction at a time, returning the state before each one.
+pub fn run_nestest(ines: &[u8], instructions: usize) -> Result<Vec<LogState>, String> {
+    let mut runner = Runner::with_variant(Nrom::from_ines(ines)?, CpuVariant::Ricoh2A03);
+    // Finish reset, then start at $C000 in the state the log expects.
+    if let Some(Stop::Error(e)) = runner.step_instruction() {
+        return Err(e);
+    }
+    runner.cpu_mut().set_registers(&Registers {
+        pc: 0xC000,
+        acc: 0,
+        x: 0,
+        y: 0,
+        sp: 0xFD,
+        flags: 0x24,
+    });
+    let start = runner.cycles();
+    let mut states = Vec::with_capacity(instructions);
+    for _ in 0 .. instructions {
+        let mut registers = runner.cpu().registers();
+        // The log shows the flags as php would push them, without B.
+        registers.flags = (registers.flags | 0x20) & !0x10;
+        states.push(LogState { registers, cycles: 7 + runner.cycles() - start });
+        if let Some(Stop::Error(e)) = runner.step_instruction() {
+            return Err(e);
+        }
+    }
+    Ok(states)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let line = "C72A  D0 E0     BNE $C70C                       A:00 X:00 Y:00 P:27 SP:FB PPU: 30, 94 CYC:3180";
+        let state = LogState::parse(line).unwrap();
+        assert_eq!("C72A A:00 X:00 Y:00 P:27 SP:FB CYC:3180", state.to_string());
+        assert!(LogState::parse("C72A  D0 E0     BNE $C70C").is_err());
+    }
+
+    #[test]
+    fn test_run_nestest() {
+        // A stand in for nestest.nes: lda #$80, jmp $C000.
+        let mut ines = b"NES\x1A\x01\x01".to_vec();
+        ines.resize(INES_HEADER + PRG_BANK, 0);
+        ines[INES_HEADER .. INES_HEADER + 5].copy_from_slice(&[0xA9, 0x80, 0x4C, 0x00, 0xC0]);
+        ines[INES_HEADER + 0x3FFC .. INES_HEADER + 0x3FFE].copy_from_slice(&[0x04, 0xC0]);
+        let states : Vec<String> = run_nestest(&ines, 3).unwrap().iter()
+            .map(|state| state.to_string())
+            .collect();
+        assert_eq!(vec![
+            "C000 A:00 X:00 Y:00 P:24 SP:FD CYC:7",
+            "C002 A:80 X:00 Y:00 P:A4 SP:FD CYC:9",
+            "C000 A:80 X:00 Y:00 P:A4 SP:FD CYC:12",
+        ], states);
+    }
+
+    // nestest.nes and its golden log aren't distributed with the model. To run:
+    // NESTEST_DIR=path/to/nestest cargo test -- --ignored test_nestest
+    #[test]
+    #[ignore]
+    fn test_nestest() {
+        let dir = std::env::var("NESTEST_DIR").unwrap_or("nestest".to_string());
+        let ines = std::fs::read(format!("{dir}/nestest.nes")).unwrap();
+        let log = std::fs::read_to_string(format!("{dir}/nestest.log")).unwrap();
+        let want : Vec<LogState> = log.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| LogState::parse(line).unwrap())
+            .collect();
+        let have = run_nestest(&ines, want.len()).unwrap();
+        for (line, (want, have)) in want.iter().zip(&have).enumerate() {
+            assert_eq!(want, have, "line {}\nwant {want}\nhave {have}", line + 1);
+        }
+    }
+}
@@ -0,0 +1,548 @@
+// Opcodes
+// The 65C02 instruction set, as a table indexed by opcode.
+//
+// This holds what is needed to assemble and disassemble instructions, and the
+// base cycle counts from the W65C02S datasheet. Page crossings and taken
+// branches add cycles on top of these.
+//
+// Opcodes the datasheet leaves unassigned behave as NOPs of various sizes and
+// timings, and are marked as undocumented.
+//
+// The other variants' tables are derived from this one, see CpuVariant.
+
+// The chips that can be modelled. They differ in their instruction sets, and in
+// some details of their bus behavior, such as dummy cycles.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CpuVariant {
+    // The original NMOS 6502.
+    Nmos6502,
+    // The WDC W65C02S, which the chiplab uses.
+    #[default]
+    W65C02S,
+    // The Rockwell R65C02: the W65C02S without wai and stp.
+    R65C02,
+    // The 65SC02: the R65C02 without the bit instructions (rmb, smb, bbr, bbs).
+    Sc65C02,
+    // The Ricoh 2A03 in the NES: an NMOS 6502 whose D flag has no effect on
+    // adc and sbc.
+    Ricoh2A03,
+}
+
+impl CpuVariant {
+    pub const ALL: [CpuVariant; 5] = [
+        CpuVariant::Nmos6502, CpuVariant::W65C02S, CpuVariant::R65C02, CpuVariant::Sc65C02,
+        CpuVariant::Ricoh2A03,
+    ];
+
+    // Name used on the command line and in trace logs.
+    pub fn name(self) -> &'static str {
+        match self {
+            CpuVariant::Nmos6502 => "6502",
+            CpuVariant::W65C02S => "w65c02s",
+            CpuVariant::R65C02 => "r65c02",
+            CpuVariant::Sc65C02 => "65sc02",
+            CpuVariant::Ricoh2A03 => "2a03",
+        }
+    }
+
+    // The variant with the given name, ignoring case.
+    pub fn from_name(name: &str) -> Option<CpuVariant> {
+        CpuVariant::ALL.into_iter().find(|variant| variant.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn opcodes(self) -> &'static [Opcode; 256] {
+        match self {
+            CpuVariant::Nmos6502 | CpuVariant::Ricoh2A03 => &NMOS_OPCODES,
+            CpuVariant::W65C02S => &OPCODES,
+            CpuVariant::R65C02 => &R65C02_OPCODES,
+            CpuVariant::Sc65C02 => &SC65C02_OPCODES,
+        }
+    }
+
+    pub fn is_nmos(self) -> bool {
+        matches!(self, CpuVariant::Nmos6502 | CpuVariant::Ricoh2A03)
+    }
+
+    // Whether adc and sbc work in decimal when the D flag is set.
+    pub fn has_decimal(self) -> bool {
+        self != CpuVariant::Ricoh2A03
+    }
+}
+
+impl std::fmt::Display for CpuVariant {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// How an instruction finds its operand, and so how many bytes follow the opcode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Implied,
+    Accumulator,        // asl a
+    Immediate,          // lda #$10
+    ZeroPage,           // lda $10
+    ZeroPageX,          // lda $10,x
+    ZeroPageY,          // ldx $10,y
+    ZeroPageIndirect,   // lda ($10)
+    IndirectX,          // lda ($10,x)
+    IndirectY,          // lda ($10),y
+    Absolute,           // lda $1234
+    AbsoluteX,          // lda $1234,x
+    AbsoluteY,          // lda $1234,y
+    Indirect,           // jmp ($1234)
+    AbsoluteIndirectX,  // jmp ($1234,x)
+    Relative,           // bne .label
+    ZeroPageRelative,   // bbr0 $10,.label
+}
+
+impl Mode {
+    // Size of the instruction in bytes, including the opcode.
+    pub fn size(self) -> u16 {
+        use Mode::*;
+        match self {
+            Implied | Accumulator => 1,
+            Immediate | ZeroPage | ZeroPageX | ZeroPageY | ZeroPageIndirect
+                | IndirectX | IndirectY | Relative => 2,
+            Absolute | AbsoluteX | AbsoluteY | Indirect | AbsoluteIndirectX
+                | ZeroPageRelative => 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Opcode {
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    // Cycles taken, before any page crossing or branch penalties.
+    pub cycles: u8,
+    pub documented: bool,
+}
+
+const fn op(mnemonic: &'static str, mode: Mode, cycles: u8) -> Opcode {
+    Opcode { mnemonic, mode, cycles, documented: true }
+}
+const fn undocumented(mode: Mode, cycles: u8) -> Opcode {
+    Opcode { mnemonic: "NOP", mode, cycles, documented: false }
+}
+
+use Mode::*;
+pub const OPCODES: [Opcode; 256] = [
+    /* 0x00 */ op("BRK", Implied, 7),
+    /* 0x01 */ op("ORA", IndirectX, 6),
+    /* 0x02 */ undocumented(Immediate, 2),
+    /* 0x03 */ undocumented(Implied, 1),
+    /* 0x04 */ op("TSB", ZeroPage, 5),
+    /* 0x05 */ op("ORA", ZeroPage, 3),
+    /* 0x06 */ op("ASL", ZeroPage, 5),
+    /* 0x07 */ op("RMB0", ZeroPage, 5),
+    /* 0x08 */ op("PHP", Implied, 3),
+    /* 0x09 */ op("ORA", Immediate, 2),
+    /* 0x0A */ op("ASL", Accumulator, 2),
+    /* 0x0B */ undocumented(Implied, 1),
+    /* 0x0C */ op("TSB", Absolute, 6),
+    /* 0x0D */ op("ORA", Absolute, 4),
+    /* 0x0E */ op("ASL", Absolute, 6),
+    /* 0x0F */ op("BBR0", ZeroPageRelative, 5),
+    /* 0x10 */ op("BPL", Relative, 2),
+    /* 0x11 */ op("ORA", IndirectY, 5),
+    /* 0x12 */ op("ORA", ZeroPageIndirect, 5),
+    /* 0x13 */ undocumented(Implied, 1),
+    /* 0x14 */ op("TRB", ZeroPage, 5),
+    /* 0x15 */ op("ORA", ZeroPageX, 4),
+    /* 0x16 */ op("ASL", ZeroPageX, 6),
+    /* 0x17 */ op("RMB1", ZeroPage, 5),
+    /* 0x18 */ op("CLC", Implied, 2),
+    /* 0x19 */ op("ORA", AbsoluteY, 4),
+    /* 0x1A */ op("INC", Accumulator, 2),
+    /* 0x1B */ undocumented(Implied, 1),
+    /* 0x1C */ op("TRB", Absolute, 6),
+    /* 0x1D */ op("ORA", AbsoluteX, 4),
+    /* 0x1E */ op("ASL", AbsoluteX, 6),
+    /* 0x1F */ op("BBR1", ZeroPageRelative, 5),
+    /* 0x20 */ op("JSR", Absolute, 6),
+    /* 0x21 */ op("AND", IndirectX, 6),
+    /* 0x22 */ undocumented(Immediate, 2),
+    /* 0x23 */ undocumented(Implied, 1),
+    /* 0x24 */ op("BIT", ZeroPage, 3),
+    /* 0x25 */ op("AND", ZeroPage, 3),
+    /* 0x26 */ op("ROL", ZeroPage, 5),
+    /* 0x27 */ op("RMB2", ZeroPage, 5),
+    /* 0x28 */ op("PLP", Implied, 4),
+    /* 0x29 */ op("AND", Immediate, 2),
+    /* 0x2A */ op("ROL", Accumulator, 2),
+    /* 0x2B */ undocumented(Implied, 1),
+    /* 0x2C */ op("BIT", Absolute, 4),
+    /* 0x2D */ op("AND", Absolute, 4),
+    /* 0x2E */ op("ROL", Absolute, 6),
+    /* 0x2F */ op("BBR2", ZeroPageRelative, 5),
+    /* 0x30 */ op("BMI", Relative, 2),
+    /* 0x31 */ op("AND", IndirectY, 5),
+    /* 0x32 */ op("AND", ZeroPageIndirect, 5),
+    /* 0x33 */ undocumented(Implied, 1),
+    /* 0x34 */ op("BIT", ZeroPageX, 4),
+    /* 0x35 */ op("AND", ZeroPageX, 4),
+    /* 0x36 */ op("ROL", ZeroPageX, 6),
+    /* 0x37 */ op("RMB3", ZeroPage, 5),
+    /* 0x38 */ op("SEC", Implied, 2),
+    /* 0x39 */ op("AND", AbsoluteY, 4),
+    /* 0x3A */ op("DEC", Accumulator, 2),
+    /* 0x3B */ undocumented(Implied, 1),
+    /* 0x3C */ op("BIT", AbsoluteX, 4),
+    /* 0x3D */ op("AND", AbsoluteX, 4),
+    /* 0x3E */ op("ROL", AbsoluteX, 6),
+    /* 0x3F */ op("BBR3", ZeroPageRelative, 5),
+    /* 0x40 */ op("RTI", Implied, 6),
+    /* 0x41 */ op("EOR", IndirectX, 6),
+    /* 0x42 */ undocumented(Immediate, 2),
+    /* 0x43 */ undocumented(Implied, 1),
+    /* 0x44 */ undocumented(ZeroPage, 3),
+    /* 0x45 */ op("EOR", ZeroPage, 3),
+    /* 0x46 */ op("LSR", ZeroPage, 5),
+    /* 0x47 */ op("RMB4", ZeroPage, 5),
+    /* 0x48 */ op("PHA", Implied, 3),
+    /* 0x49 */ op("EOR", Immediate, 2),
+    /* 0x4A */ op("LSR", Accumulator, 2),
+    /* 0x4B */ undocumented(Implied, 1),
+    /* 0x4C */ op("JMP", Absolute, 3),
+    /* 0x4D */ op("EOR", Absolute, 4),
+    /* 0x4E */ op("LSR", Absolute, 6),
+    /* 0x4F */ op("BBR4", ZeroPageRelative, 5),
+    /* 0x50 */ op("BVC", Relative, 2),
+    /* 0x51 */ op("EOR", IndirectY, 5),
+    /* 0x52 */ op("EOR", ZeroPageIndirect, 5),
+    /* 0x53 */ undocumented(Implied, 1),
+    /* 0x54 */ undocumented(ZeroPageX, 4),
+    /* 0x55 */ op("EOR", ZeroPageX, 4),
+    /* 0x56 */ op("LSR", ZeroPageX, 6),
+    /* 0x57 */ op("RMB5", ZeroPage, 5),
+    /* 0x58 */ op("CLI", Implied, 2),
+    /* 0x59 */ op("EOR", AbsoluteY, 4),
+    /* 0x5A */ op("PHY", Implied, 3),
+    /* 0x5B */ undocumented(Implied, 1),
+    /* 0x5C */ undocumented(Absolute, 8),
+    /* 0x5D */ op("EOR", AbsoluteX, 4),
+    /* 0x5E */ op("LSR", AbsoluteX, 6),
+    /* 0x5F */ op("BBR5", ZeroPageRelative, 5),
+    /* 0x60 */ op("RTS", Implied, 6),
+    /* 0x61 */ op("ADC", IndirectX, 6),
+    /* 0x62 */ undocumented(Immediate, 2),
+    /* 0x63 */ undocumented(Implied, 1),
+    /* 0x64 */ op("STZ", ZeroPage, 3),
+    /* 0x65 */ op("ADC", ZeroPage, 3),
+    /* 0x66 */ op("ROR", ZeroPage, 5),
+    /* 0x67 */ op("RMB6", ZeroPage, 5),
+    /* 0x68 */ op("PLA", Implied, 4),
+    /* 0x69 */ op("ADC", Immediate, 2),
+    /* 0x6A */ op("ROR", Accumulator, 2),
+    /* 0x6B */ undocumented(Implied, 1),
+    /* 0x6C */ op("JMP", Indirect, 6),
+    /* 0x6D */ op("ADC", Absolute, 4),
+    /* 0x6E */ op("ROR", Absolute, 6),
+    /* 0x6F */ op("BBR6", ZeroPageRelative, 5),
+    /* 0x70 */ op("BVS", Relative, 2),
+    /* 0x71 */ op("ADC", IndirectY, 5),
+    /* 0x72 */ op("ADC", ZeroPageIndirect, 5),
+    /* 0x73 */ undocumented(Implied, 1),
+    /* 0x74 */ op("STZ", ZeroPageX, 4),
+    /* 0x75 */ op("ADC", ZeroPageX, 4),
+    /* 0x76 */ op("ROR", ZeroPageX, 6),
+    /* 0x77 */ op("RMB7", ZeroPage, 5),
+    /* 0x78 */ op("SEI", Implied, 2),
+    /* 0x79 */ op("ADC", AbsoluteY, 4),
+    /* 0x7A */ op("PLY", Implied, 4),
+    /* 0x7B */ undocumented(Implied, 1),
+    /* 0x7C */ op("JMP", AbsoluteIndirectX, 6),
+    /* 0x7D */ op("ADC", AbsoluteX, 4),
+    /* 0x7E */ op("ROR", AbsoluteX, 6),
+    /* 0x7F */ op("BBR7", ZeroPageRelative, 5),
+    /* 0x80 */ op("BRA", Relative, 3),
+    /* 0x81 */ op("STA", IndirectX, 6),
+    /* 0x82 */ undocumented(Immediate, 2),
+    /* 0x83 */ undocumented(Implied, 1),
+    /* 0x84 */ op("STY", ZeroPage, 3),
+    /* 0x85 */ op("STA", ZeroPage, 3),
+    /* 0x86 */ op("STX", ZeroPage, 3),
+    /* 0x87 */ op("SMB0", ZeroPage, 5),
+    /* 0x88 */ op("DEY", Implied, 2),
+    /* 0x89 */ op("BIT", Immediate, 2),
+    /* 0x8A */ op("TXA", Implied, 2),
+    /* 0x8B */ undocumented(Implied, 1),
+    /* 0x8C */ op("STY", Absolute, 4),
+    /* 0x8D */ op("STA", Absolute, 4),
+    /* 0x8E */ op("STX", Absolute, 4),
+    /* 0x8F */ op("BBS0", ZeroPageRelative, 5),
+    /* 0x90 */ op("BCC", Relative, 2),
+    /* 0x91 */ op("STA", IndirectY, 6),
+    /* 0x92 */ op("STA", ZeroPageIndirect, 5),
+    /* 0x93 */ undocumented(Implied, 1),
+    /* 0x94 */ op("STY", ZeroPageX, 4),
+    /* 0x95 */ op("STA", ZeroPageX, 4),
+    /* 0x96 */ op("STX", ZeroPageY, 4),
+    /* 0x97 */ op("SMB1", ZeroPage, 5),
+    /* 0x98 */ op("TYA", Implied, 2),
+    /* 0x99 */ op("STA", AbsoluteY, 5),
+    /* 0x9A */ op("TXS", Implied, 2),
+    /* 0x9B */ undocumented(Implied, 1),
+    /* 0x9C */ op("STZ", Absolute, 4),
+    /* 0x9D */ op("STA", AbsoluteX, 5),
+    /* 0x9E */ op("STZ", AbsoluteX, 5),
+    /* 0x9F */ op("BBS1", ZeroPageRelative, 5),
+    /* 0xA0 */ op("LDY", Immediate, 2),
+    /* 0xA1 */ op("LDA", IndirectX, 6),
+    /* 0xA2 */ op("LDX", Immediate, 2),
+    /* 0xA3 */ undocumented(Implied, 1),
+    /* 0xA4 */ op("LDY", ZeroPage, 3),
+    /* 0xA5 */ op("LDA", ZeroPage, 3),
+    /* 0xA6 */ op("LDX", ZeroPage, 3),
+    /* 0xA7 */ op("SMB2", ZeroPage, 5),
+    /* 0xA8 */ op("TAY", Implied, 2),
+    /* 0xA9 */ op("LDA", Immediate, 2),
+    /* 0xAA */ op("TAX", Implied, 2),
+    /* 0xAB */ undocumented(Implied, 1),
+    /* 0xAC */ op("LDY", Absolute, 4),
+    /* 0xAD */ op("LDA", Absolute, 4),
+    /* 0xAE */ op("LDX", Absolute, 4),
+    /* 0xAF */ op("BBS2", ZeroPageRelative, 5),
+    /* 0xB0 */ op("BCS", Relative, 2),
+    /* 0xB1 */ op("LDA", IndirectY, 5),
+    /* 0xB2 */ op("LDA", ZeroPageIndirect, 5),
+    /* 0xB3 */ undocumented(Implied, 1),
+    /* 0xB4 */ op("LDY", ZeroPageX, 4),
+    /* 0xB5 */ op("LDA", ZeroPageX, 4),
+    /* 0xB6 */ op("LDX", ZeroPageY, 4),
+    /* 0xB7 */ op("SMB3", ZeroPage, 5),
+    /* 0xB8 */ op("CLV", Implied, 2),
+    /* 0xB9 */ op("LDA", AbsoluteY, 4),
+    /* 0xBA */ op("TSX", Implied, 2),
+    /* 0xBB */ undocumented(Implied, 1),
+    /* 0xBC */ op("LDY", AbsoluteX, 4),
+    /* 0xBD */ op("LDA", AbsoluteX, 4),
+    /* 0xBE */ op("LDX", AbsoluteY, 4),
+    /* 0xBF */ op("BBS3", ZeroPageRelative, 5),
+    /* 0xC0 */ op("CPY", Immediate, 2),
+    /* 0xC1 */ op("CMP", IndirectX, 6),
+    /* 0xC2 */ undocumented(Immediate, 2),
+    /* 0xC3 */ undocumented(Implied, 1),
+    /* 0xC4 */ op("CPY", ZeroPage, 3),
+    /* 0xC5 */ op("CMP", ZeroPage, 3),
+    /* 0xC6 */ op("DEC", ZeroPage, 5),
+    /* 0xC7 */ op("SMB4", ZeroPage, 5),
+    /* 0xC8 */ op("INY", Implied, 2),
+    /* 0xC9 */ op("CMP", Immediate, 2),
+    /* 0xCA */ op("DEX", Implied, 2),
+    /* 0xCB */ op("WAI", Implied, 3),
+    /* 0xCC */ op("CPY", Absolute, 4),
+    /* 0xCD */ op("CMP", Absolute, 4),
+    /* 0xCE */ op("DEC", Absolute, 6),
+    /* 0xCF */ op("BBS4", ZeroPageRelative, 5),
+    /* 0xD0 */ op("BNE", Relative, 2),
+    /* 0xD1 */ op("CMP", IndirectY, 5),
+    /* 0xD2 */ op("CMP", ZeroPageIndirect, 5),
+    /* 0xD3 */ undocumented(Implied, 1),
+    /* 0xD4 */ undocumented(ZeroPageX, 4),
+    /* 0xD5 */ op("CMP", ZeroPageX, 4),
+    /* 0xD6 */ op("DEC", ZeroPageX, 6),
+    /* 0xD7 */ op("SMB5", ZeroPage, 5),
+    /* 0xD8 */ op("CLD", Implied, 2),
+    /* 0xD9 */ op("CMP", AbsoluteY, 4),
+    /* 0xDA */ op("PHX", Implied, 3),
+    /* 0xDB */ op("STP", Implied, 3),
+    /* 0xDC */ undocumented(Absolute, 4),
+    /* 0xDD */ op("CMP", AbsoluteX, 4),
+    /* 0xDE */ op("DEC", AbsoluteX, 7),
+    /* 0xDF */ op("BBS5", ZeroPageRelative, 5),
+    /* 0xE0 */ op("CPX", Immediate, 2),
+    /* 0xE1 */ op("SBC", IndirectX, 6),
+    /* 0xE2 */ undocumented(Immediate, 2),
+    /* 0xE3 */ undocumented(Implied, 1),
+    /* 0xE4 */ op("CPX", ZeroPage, 3),
+    /* 0xE5 */ op("SBC", ZeroPage, 3),
+    /* 0xE6 */ op("INC", ZeroPage, 5),
+    /* 0xE7 */ op("SMB6", ZeroPage, 5),
+    /* 0xE8 */ op("INX", Implied, 2),
+    /* 0xE9 */ op("SBC", Immediate, 2),
+    /* 0xEA */ op("NOP", Implied, 2),
+    /* 0xEB */ undocumented(Implied, 1),
+    /* 0xEC */ op("CPX", Absolute, 4),
+    /* 0xED */ op("SBC", Absolute, 4),
+    /* 0xEE */ op("INC", Absolute, 6),
+    /* 0xEF */ op("BBS6", ZeroPageRelative, 5),
+    /* 0xF0 */ op("BEQ", Relative, 2),
+    /* 0xF1 */ op("SBC", IndirectY, 5),
+    /* 0xF2 */ op("SBC", ZeroPageIndirect, 5),
+    /* 0xF3 */ undocumented(Implied, 1),
+    /* 0xF4 */ undocumented(ZeroPageX, 4),
+    /* 0xF5 */ op("SBC", ZeroPageX, 4),
+    /* 0xF6 */ op("INC", ZeroPageX, 6),
+    /* 0xF7 */ op("SMB7", ZeroPage, 5),
+    /* 0xF8 */ op("SED", Implied, 2),
+    /* 0xF9 */ op("SBC", AbsoluteY, 4),
+    /* 0xFA */ op("PLX", Implied, 4),
+    /* 0xFB */ undocumented(Implied, 1),
+    /* 0xFC */ undocumented(Absolute, 4),
+    /* 0xFD */ op("SBC", AbsoluteX, 4),
+    /* 0xFE */ op("INC", AbsoluteX, 7),
+    /* 0xFF */ op("BBS7", ZeroPageRelative, 5),
+];
+
+const fn illegal(mnemonic: &'static str, mode: Mode, cycles: u8) -> Opcode {
+    Opcode { mnemonic, mode, cycles, documented: false }
+}
+
+// The NMOS 6502's undocumented opcodes, in the place of the CMOS additions.
+// Mnemonics are as in the "NMOS 6510 Unintended Opcodes" document. jam locks
+// up the cpu until reset.
+const NMOS_UNDOCUMENTED: [(u8, Opcode); 105] = [
+    (0x02, illegal("JAM", Implied, 3)), (0x12, illegal("JAM", Implied, 3)),
+    (0x22, illegal("JAM", Implied, 3)), (0x32, illegal("JAM", Implied, 3)),
+    (0x42, illegal("JAM", Implied, 3)), (0x52, illegal("JAM", Implied, 3)),
+    (0x62, illegal("JAM", Implied, 3)), (0x72, illegal("JAM", Implied, 3)),
+    (0x92, illegal("JAM", Implied, 3)), (0xB2, illegal("JAM", Implied, 3)),
+    (0xD2, illegal("JAM", Implied, 3)), (0xF2, illegal("JAM", Implied, 3)),
+
+    (0x03, illegal("SLO", IndirectX, 8)), (0x07, illegal("SLO", ZeroPage, 5)),
+    (0x0F, illegal("SLO", Absolute, 6)), (0x13, illegal("SLO", IndirectY, 8)),
+    (0x17, illegal("SLO", ZeroPageX, 6)), (0x1B, illegal("SLO", AbsoluteY, 7)),
+    (0x1F, illegal("SLO", AbsoluteX, 7)),
+    (0x23, illegal("RLA", IndirectX, 8)), (0x27, illegal("RLA", ZeroPage, 5)),
+    (0x2F, illegal("RLA", Absolute, 6)), (0x33, illegal("RLA", IndirectY, 8)),
+    (0x37, illegal("RLA", ZeroPageX, 6)), (0x3B, illegal("RLA", AbsoluteY, 7)),
+    (0x3F, illegal("RLA", AbsoluteX, 7)),
+    (0x43, illegal("SRE", IndirectX, 8)), (0x47, illegal("SRE", ZeroPage, 5)),
+    (0x4F, illegal("SRE", Absolute, 6)), (0x53, illegal("SRE", IndirectY, 8)),
+    (0x57, illegal("SRE", ZeroPageX, 6)), (0x5B, illegal("SRE", AbsoluteY, 7)),
+    (0x5F, illegal("SRE", AbsoluteX, 7)),
+    (0x63, illegal("RRA", IndirectX, 8)), (0x67, illegal("RRA", ZeroPage, 5)),
+    (0x6F, illegal("RRA", Absolute, 6)), (0x73, illegal("RRA", IndirectY, 8)),
+    (0x77, illegal("RRA", ZeroPageX, 6)), (0x7B, illegal("RRA", AbsoluteY, 7)),
+    (0x7F, illegal("RRA", AbsoluteX, 7)),
+    (0xC3, illegal("DCP", IndirectX, 8)), (0xC7, illegal("DCP", ZeroPage, 5)),
+    (0xCF, illegal("DCP", Absolute, 6)), (0xD3, illegal("DCP", IndirectY, 8)),
+    (0xD7, illegal("DCP", ZeroPageX, 6)), (0xDB, illegal("DCP", AbsoluteY, 7)),
+    (0xDF, illegal("DCP", AbsoluteX, 7)),
+    (0xE3, illegal("ISC", IndirectX, 8)), (0xE7, illegal("ISC", ZeroPage, 5)),
+    (0xEF, illegal("ISC", Absolute, 6)), (0xF3, illegal("ISC", IndirectY, 8)),
+    (0xF7, illegal("ISC", ZeroPageX, 6)), (0xFB, illegal("ISC", AbsoluteY, 7)),
+    (0xFF, illegal("ISC", AbsoluteX, 7)),
+
+    (0x83, illegal("SAX", IndirectX, 6)), (0x87, illegal("SAX", ZeroPage, 3)),
+    (0x8F, illegal("SAX", Absolute, 4)), (0x97, illegal("SAX", ZeroPageY, 4)),
+    (0xA3, illegal("LAX", IndirectX, 6)), (0xA7, illegal("LAX", ZeroPage, 3)),
+    (0xAF, illegal("LAX", Absolute, 4)), (0xB3, illegal("LAX", IndirectY, 5)),
+    (0xB7, illegal("LAX", ZeroPageY, 4)), (0xBF, illegal("LAX", AbsoluteY, 4)),
+    (0xBB, illegal("LAS", AbsoluteY, 4)),
+
+    (0x0B, illegal("ANC", Immediate, 2)), (0x2B, illegal("ANC", Immediate, 2)),
+    (0x4B, illegal("ALR", Immediate, 2)), (0x6B, illegal("ARR", Immediate, 2)),
+    (0xCB, illegal("SBX", Immediate, 2)), (0xEB, illegal("SBC", Immediate, 2)),
+
+    // Unstable: the result depends on the chip, see Unstable.
+    (0x8B, illegal("ANE", Immediate, 2)), (0xAB, illegal("LXA", Immediate, 2)),
+    (0x93, illegal("SHA", IndirectY, 6)), (0x9F, illegal("SHA", AbsoluteY, 5)),
+    (0x9E, illegal("SHX", AbsoluteY, 5)), (0x9C, illegal("SHY", AbsoluteX, 5)),
+    (0x9B, illegal("TAS", AbsoluteY, 5)),
+
+    (0x1A, undocumented(Implied, 2)), (0x3A, undocumented(Implied, 2)),
+    (0x5A, undocumented(Implied, 2)), (0x7A, undocumented(Implied, 2)),
+    (0xDA, undocumented(Implied, 2)), (0xFA, undocumented(Implied, 2)),
+    (0x80, undocumented(Immediate, 2)), (0x82, undocumented(Immediate, 2)),
+    (0x89, undocumented(Immediate, 2)), (0xC2, undocumented(Immediate, 2)),
+    (0xE2, undocumented(Immediate, 2)),
+    (0x04, undocumented(ZeroPage, 3)), (0x44, undocumented(ZeroPage, 3)),
+    (0x64, undocumented(ZeroPage, 3)),
+    (0x14, undocumented(ZeroPageX, 4)), (0x34, undocumented(ZeroPageX, 4)),
+    (0x54, undocumented(ZeroPageX, 4)), (0x74, undocumented(ZeroPageX, 4)),
+    (0xD4, undocumented(ZeroPageX, 4)), (0xF4, undocumented(ZeroPageX, 4)),
+    (0x0C, undocumented(Absolute, 4)),
+    (0x1C, undocumented(AbsoluteX, 4)), (0x3C, undocumented(AbsoluteX, 4)),
+    (0x5C, undocumented(AbsoluteX, 4)), (0x7C, undocumented(AbsoluteX, 4)),
+    (0xDC, undocumented(AbsoluteX, 4)), (0xFC, undocumented(AbsoluteX, 4)),
+];
+
+// The NMOS 6502 has its undocumented opcodes in place of the CMOS additions.
+//
+// Its base timings differ in two places: jmp (abs) is a cycle shorter, and
+// shifts and rotates with abs,x always take the extra indexing cycle.
+const NMOS_OPCODES: [Opcode; 256] = {
+    let mut table = OPCODES;
+    let mut i = 0;
+    while i < NMOS_UNDOCUMENTED.len() {
+        let (opcode, op) = NMOS_UNDOCUMENTED[i];
+        table[opcode as usize] = op;
+        i += 1;
+    }
+    table[0x6C].cycles = 5;
+    table[0x1E].cycles = 7;
+    table[0x3E].cycles = 7;
+    table[0x5E].cycles = 7;
+    table[0x7E].cycles = 7;
+    table
+};
+
+// On the Rockwell part, wai and stp are single cycle nops like the rest of
+// their column.
+const R65C02_OPCODES: [Opcode; 256] = {
+    let mut table = OPCODES;
+    table[0xCB] = undocumented(Implied, 1);
+    table[0xDB] = undocumented(Implied, 1);
+    table
+};
+
+// The 65SC02 also has single cycle nops in place of the bit instructions.
+const SC65C02_OPCODES: [Opcode; 256] = {
+    let mut table = R65C02_OPCODES;
+    let mut opcode = 0x07;
+    while opcode < 256 {
+        table[opcode] = undocumented(Implied, 1);
+        opcode += 8;
+    }
+    table
+};
+
+// Find the documented opcode for a mnemonic and addressing mode.
+// The mnemonic is not case sensitive.
+pub fn encode(mnemonic: &str, mode: Mode) -> Option<u8> {
+    OPCODES.iter()
+        .position(|op| op.documented && op.mode == mode
+                  && op.mnemonic.eq_ignore_ascii_case(mnemonic))
+        .map(|opcode| opcode as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(Some(0xA9), encode("lda", Immediate));
+        assert_eq!(Some(0xEA), encode("NOP", Implied));
+        assert_eq!(Some(0x7C), encode("jmp", AbsoluteIndirectX));
+        assert_eq!(None, encode("stx", AbsoluteX));
+        // Every documented opcode can be found from its mnemonic and mode.
+        for (opcode, op) in OPCODES.iter().enumerate() {
+            if op.documented {
+                assert_eq!(Some(opcode as u8), encode(op.mnemonic, op.mode));
+            }
+        }
+    }
+
+    #[test]
+    fn test_variants() {
+        let documented = |variant: CpuVariant| {
+            variant.opcodes().iter().filter(|op| op.documented).count()
+        };
+        assert_eq!(151, documented(CpuVariant::Nmos6502));
+        assert_eq!(212, documented(CpuVariant::W65C02S));
+        assert_eq!(210, documented(CpuVariant::R65C02));
+        assert_eq!(178, documented(CpuVariant::Sc65C02));
+        assert_eq!(("JMP", 5), (NMOS_OPCODES[0x6C].mnemonic, NMOS_OPCODES[0x6C].cycles));
+        // With no repeats, the undocumented opcodes fill the rest of the table.
+        let mut seen = [false; 256];
+        for (opcode, _) in NMOS_UNDOCUMENTED {
+            assert!(!seen[opcode as usize], "{opcode:02X}");
+            seen[opcode as usize] = true;
+        }
+        assert_eq!(Some(CpuVariant::Sc65C02), CpuVariant::from_name("65SC02"));
+    }
+}
@@ -0,0 +1,500 @@
+// Operations
+// What instructions do to registers and flags, separate from the bus cycles
+// they take. The decoder pairs each operation with the uops for an addressing
+// mode, and the uops apply the operation once its operand has been read.
+use crate::{Register, W6502};
+
+// Bits within the flags register.
+pub(crate) const FLAG_N: u8 = 0x80;
+pub(crate) const FLAG_V: u8 = 0x40;
+// Not a real flag, but always reads as 1 when flags are pushed.
+pub(crate) const FLAG_UNUSED: u8 = 0x20;
+// Only exists on the stack, set when flags are pushed by brk or php.
+pub(crate) const FLAG_B: u8 = 0x10;
+pub(crate) const FLAG_D: u8 = 0x08;
+pub(crate) const FLAG_I: u8 = 0x04;
+pub(crate) const FLAG_Z: u8 = 0x02;
+pub(crate) const FLAG_C: u8 = 0x01;
+
+// How the unstable NMOS opcodes behave, which varies between chips, and with
+// temperature. The defaults are those seen on most chips.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Unstable {
+    // ane and lxa OR the accumulator with a constant before masking it.
+    pub ane_magic: u8,
+    pub lxa_magic: u8,
+    // Whether sha, shx, shy and tas AND the value stored with the high byte
+    // of the address plus one. Some chips drop this when rdy is low.
+    pub and_high: bool,
+}
+
+impl Default for Unstable {
+    fn default() -> Self {
+        Unstable {
+            ane_magic: 0xEE,
+            lxa_magic: 0xEE,
+            and_high: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Condition {
+    Always,
+    FlagSet(u8),
+    FlagClear(u8),
+    // Bits of scratch1, for bbr and bbs.
+    BitSet(u8),
+    BitClear(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Operation {
+    // Operations on a byte read from memory.
+    Load(Register),
+    Ora,
+    And,
+    Eor,
+    Adc,
+    Sbc,
+    Compare(Register),
+    Bit,
+    BitImmediate,
+    // The byte read is the offset to branch by.
+    Branch(Condition),
+    // Undocumented NMOS operations on a byte read.
+    Lax,
+    Las,
+    Anc,
+    Alr,
+    Arr,
+    Sbx,
+    Ane,
+    Lxa,
+
+    // Operations on a register, which is scratch1 for memory operands.
+    Asl,
+    Lsr,
+    Rol,
+    Ror,
+    Inc,
+    Dec,
+    Tsb,
+    Trb,
+    Rmb(u8),
+    Smb(u8),
+    // Copy from another register.
+    Transfer(Register),
+    SetFlag(u8),
+    ClearFlag(u8),
+    // Place the flags to push for php into scratch1.
+    SaveFlags,
+    // Start of brk: save the flags to push, mask interrupts, and skip the
+    // signature byte.
+    Break,
+    // Undocumented NMOS operations, which modify scratch1 and then combine
+    // the result with the accumulator.
+    Slo,
+    Rla,
+    Sre,
+    Rra,
+    Dcp,
+    Isc,
+}
+
+impl W6502 {
+    fn set_flag(&mut self, flag: u8, set: bool) {
+        if set {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    fn set_nz(&mut self, val: u8) {
+        self.set_flag(FLAG_Z, val == 0);
+        self.set_flag(FLAG_N, val & 0x80 != 0);
+    }
+
+    fn condition(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::Always => true,
+            Condition::FlagSet(flag) => self.flags & flag != 0,
+            Condition::FlagClear(flag) => self.flags & flag == 0,
+            Condition::BitSet(bit) => self.scratch1 & (1 << bit) != 0,
+            Condition::BitClear(bit) => self.scratch1 & (1 << bit) == 0,
+        }
+    }
+
+    // Whether adc, sbc and arr work in decimal.
+    fn decimal(&self) -> bool {
+        self.flags & FLAG_D != 0 && self.variant.has_decimal()
+    }
+
+    fn add(&mut self, val: u8) {
+        let carry = self.flags & FLAG_C;
+        let acc = self.acc;
+        let sum = acc as u16 + val as u16 + carry as u16;
+        self.set_flag(FLAG_V, (!(acc ^ val) & (acc ^ sum as u8)) & 0x80 != 0);
+        if !self.decimal() {
+            self.set_flag(FLAG_C, sum > 0xFF);
+            self.acc = sum as u8;
+        } else if self.variant.is_nmos() {
+            // NMOS decimal mode, where Z reflects the binary sum, and N and V
+            // the sum before the high digit is adjusted.
+            let binary = sum as u8;
+            let mut low = (acc & 0x0F) + (val & 0x0F) + carry;
+            if low >= 0x0A {
+                low = ((low + 0x06) & 0x0F) + 0x10;
+            }
+            let mut sum = (acc & 0xF0) as u16 + (val & 0xF0) as u16 + low as u16;
+            self.set_flag(FLAG_N, sum & 0x80 != 0);
+            self.set_flag(FLAG_V, (!(acc ^ val) & (acc ^ sum as u8)) & 0x80 != 0);
+            self.set_flag(FLAG_Z, binary == 0);
+            if sum >= 0xA0 {
+                sum += 0x60;
+            }
+            self.set_flag(FLAG_C, sum > 0xFF);
+            self.acc = sum as u8;
+            return;
+        } else {
+            // 65C02 decimal mode, where N and Z reflect the decimal result.
+            let mut low = (acc & 0x0F) + (val & 0x0F) + carry;
+            if low >= 0x0A {
+                low = ((low + 0x06) & 0x0F) + 0x10;
+            }
+            let mut sum = (acc & 0xF0) as u16 + (val & 0xF0) as u16 + low as u16;
+            let signed = (acc & 0xF0) as i8 as i16 + (val & 0xF0) as i8 as i16 + low as i16;
+            self.set_flag(FLAG_V, !(-128 ..= 127).contains(&signed));
+            if sum >= 0xA0 {
+                sum += 0x60;
+            }
+            self.set_flag(FLAG_C, sum > 0xFF);
+            self.acc = sum as u8;
+        }
+        self.set_nz(self.acc);
+    }
+
+    fn subtract(&mut self, val: u8) {
+        if !self.decimal() {
+            self.add(!val);
+            return;
+        }
+        let borrow = 1 - (self.flags & FLAG_C) as i16;
+        let acc = self.acc;
+        if self.variant.is_nmos() {
+            // NMOS decimal mode, where all the flags are as for binary.
+            let mut low = (acc & 0x0F) as i16 - (val & 0x0F) as i16 - borrow;
+            if low < 0 {
+                low = ((low - 0x06) & 0x0F) - 0x10;
+            }
+            let mut result = (acc & 0xF0) as i16 - (val & 0xF0) as i16 + low;
+            if result < 0 {
+                result -= 0x60;
+            }
+            self.flags &= !FLAG_D;
+            self.add(!val);
+            self.flags |= FLAG_D;
+            self.acc = result as u8;
+            return;
+        }
+        // 65C02 decimal mode. Carry and overflow are as for binary.
+        let low = (acc & 0x0F) as i16 - (val & 0x0F) as i16 - borrow;
+        let mut result = acc as i16 - val as i16 - borrow;
+        let binary = result;
+        self.set_flag(FLAG_V, ((acc ^ val) & (acc ^ binary as u8)) & 0x80 != 0);
+        self.set_flag(FLAG_C, binary >= 0);
+        if result < 0 {
+            result -= 0x60;
+        }
+        if low < 0 {
+            result -= 0x06;
+        }
+        self.acc = result as u8;
+        self.set_nz(self.acc);
+    }
+
+    // Apply an operation to a byte read from memory.
+    pub(crate) fn operate(&mut self, op: Operation, val: u8) {
+        match op {
+            Operation::Load(reg) => {
+                *self.mut_reg(reg) = val;
+                self.set_nz(val);
+            },
+            Operation::Ora => {
+                self.acc |= val;
+                self.set_nz(self.acc);
+            },
+            Operation::And => {
+                self.acc &= val;
+                self.set_nz(self.acc);
+            },
+            Operation::Eor => {
+                self.acc ^= val;
+                self.set_nz(self.acc);
+            },
+            Operation::Adc => self.add(val),
+            Operation::Sbc => self.subtract(val),
+            Operation::Compare(reg) => {
+                let reg = self.reg(reg);
+                self.set_flag(FLAG_C, reg >= val);
+                self.set_nz(reg.wrapping_sub(val));
+            },
+            Operation::Bit => {
+                self.set_flag(FLAG_Z, self.acc & val == 0);
+                self.set_flag(FLAG_N, val & 0x80 != 0);
+                self.set_flag(FLAG_V, val & 0x40 != 0);
+            },
+            Operation::BitImmediate => self.set_flag(FLAG_Z, self.acc & val == 0),
+            Operation::Branch(condition) => {
+                self.branch_taken = self.condition(condition);
+                self.page_crossed = false;
+                if self.branch_taken {
+                    let target = self.pc.wrapping_add(val as i8 as u16);
+                    self.page_crossed = (target ^ self.pc) & 0xFF00 != 0;
+                    self.pc = target;
+                }
+            },
+            Operation::Lax => {
+                self.acc = val;
+                self.x = val;
+                self.set_nz(val);
+            },
+            Operation::Las => {
+                let val = val & self.sp;
+                self.acc = val;
+                self.x = val;
+                self.sp = val;
+                self.set_nz(val);
+            },
+            Operation::Anc => {
+                self.operate(Operation::And, val);
+                self.set_flag(FLAG_C, self.acc & 0x80 != 0);
+            },
+            Operation::Alr => {
+                self.operate(Operation::And, val);
+                self.modify(Operation::Lsr, Register::Acc);
+            },
+            Operation::Arr => self.arr(val),
+            Operation::Sbx => {
+                let reg = self.acc & self.x;
+                self.set_flag(FLAG_C, reg >= val);
+                self.x = reg.wrapping_sub(val);
+                self.set_nz(self.x);
+            },
+            Operation::Ane => {
+                self.acc = (self.acc | self.unstable.ane_magic) & self.x & val;
+                self.set_nz(self.acc);
+            },
+            Operation::Lxa => {
+                let val = (self.acc | self.unstable.lxa_magic) & val;
+                self.operate(Operation::Lax, val);
+            },
+            _ => unreachable!("{op:?} doesn't take an operand"),
+        }
+    }
+
+    // and, then ror the accumulator, with carry and overflow from bits 6 and 5
+    // of the result. In decimal mode, each digit is then adjusted.
+    fn arr(&mut self, val: u8) {
+        let and = self.acc & val;
+        let carry = self.flags & FLAG_C;
+        self.acc = (and >> 1) | (carry << 7);
+        self.set_nz(self.acc);
+        if !self.decimal() {
+            self.set_flag(FLAG_C, self.acc & 0x40 != 0);
+            self.set_flag(FLAG_V, (self.acc ^ (self.acc << 1)) & 0x40 != 0);
+            return;
+        }
+        self.set_flag(FLAG_V, (and ^ self.acc) & 0x40 != 0);
+        if (and & 0x0F) + (and & 0x01) > 0x05 {
+            self.acc = (self.acc & 0xF0) | (self.acc.wrapping_add(0x06) & 0x0F);
+        }
+        let high_adjust = (and & 0xF0) as u16 + (and & 0x10) as u16 > 0x50;
+        self.set_flag(FLAG_C, high_adjust);
+        if high_adjust {
+            self.acc = self.acc.wrapping_add(0x60);
+        }
+    }
+
+    // Apply an operation to a register.
+    pub(crate) fn modify(&mut self, op: Operation, reg: Register) {
+        let val = self.reg(reg);
+        let result = match op {
+            Operation::Asl => {
+                self.set_flag(FLAG_C, val & 0x80 != 0);
+                val << 1
+            },
+            Operation::Lsr => {
+                self.set_flag(FLAG_C, val & 0x01 != 0);
+                val >> 1
+            },
+            Operation::Rol => {
+                let carry = self.flags & FLAG_C;
+                self.set_flag(FLAG_C, val & 0x80 != 0);
+                (val << 1) | carry
+            },
+            Operation::Ror => {
+                let carry = self.flags & FLAG_C;
+                self.set_flag(FLAG_C, val & 0x01 != 0);
+                (val >> 1) | (carry << 7)
+            },
+            Operation::Inc => val.wrapping_add(1),
+            Operation::Dec => val.wrapping_sub(1),
+            Operation::Tsb | Operation::Trb => {
+                self.set_flag(FLAG_Z, self.acc & val == 0);
+                let result = if op == Operation::Tsb { val | self.acc } else { val & !self.acc };
+                *self.mut_reg(reg) = result;
+                return;
+            },
+            Operation::Rmb(bit) => {
+                *self.mut_reg(reg) = val & !(1 << bit);
+                return;
+            },
+            Operation::Smb(bit) => {
+                *self.mut_reg(reg) = val | (1 << bit);
+                return;
+            },
+            Operation::Transfer(from) => {
+                let val = self.reg(from);
+                *self.mut_reg(reg) = val;
+                // txs is the only transfer that leaves the flags alone.
+                if reg != Register::Sp {
+                    self.set_nz(val);
+                }
+                return;
+            },
+            Operation::SetFlag(flag) => {
+                self.flags |= flag;
+                return;
+            },
+            Operation::ClearFlag(flag) => {
+                self.flags &= !flag;
+                return;
+            },
+            Operation::SaveFlags => {
+                self.scratch1 = self.flags | FLAG_B | FLAG_UNUSED;
+                return;
+            },
+            Operation::Break => {
+                self.scratch1 = self.flags | FLAG_B | FLAG_UNUSED;
+                self.flags |= FLAG_I;
+                self.clear_decimal();
+                self.pc = self.pc.wrapping_add(1);
+                return;
+            },
+            Operation::Slo | Operation::Rla | Operation::Sre |
+            Operation::Rra | Operation::Dcp | Operation::Isc => {
+                let (first, then) = match op {
+                    Operation::Slo => (Operation::Asl, Operation::Ora),
+                    Operation::Rla => (Operation::Rol, Operation::And),
+                    Operation::Sre => (Operation::Lsr, Operation::Eor),
+                    Operation::Rra => (Operation::Ror, Operation::Adc),
+                    Operation::Dcp => (Operation::Dec, Operation::Compare(Register::Acc)),
+                    _ => (Operation::Inc, Operation::Sbc),
+                };
+                self.modify(first, reg);
+                self.operate(then, self.reg(reg));
+                return;
+            },
+            _ => unreachable!("{op:?} needs an operand"),
+        };
+        *self.mut_reg(reg) = result;
+        self.set_nz(result);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CpuVariant;
+
+    fn cpu(acc: u8, flags: u8) -> W6502 {
+        let mut cpu = W6502::new(CpuVariant::W65C02S);
+        cpu.acc = acc;
+        cpu.flags = flags;
+        cpu
+    }
+
+    #[test]
+    fn test_adc_sbc() {
+        // (acc, operand, flags in, acc out, flags out) for binary and decimal.
+        let adc = [
+            (0x50, 0x50, 0, 0xA0, FLAG_N | FLAG_V),
+            (0xFF, 0x01, 0, 0x00, FLAG_Z | FLAG_C),
+            (0x09, 0x01, FLAG_D, 0x10, FLAG_D),
+            (0x99, 0x01, FLAG_D, 0x00, FLAG_D | FLAG_Z | FLAG_C),
+            // V in decimal mode comes from adding the high digits as signed values.
+            (0x58, 0x46, FLAG_D | FLAG_C, 0x05, FLAG_D | FLAG_C | FLAG_V),
+        ];
+        for (acc, val, flags, want_acc, want_flags) in adc {
+            let mut cpu = cpu(acc, flags);
+            cpu.operate(Operation::Adc, val);
+            assert_eq!((want_acc, want_flags), (cpu.acc, cpu.flags), "adc {acc:02X} {val:02X}");
+        }
+        let sbc = [
+            (0x50, 0xB0, FLAG_C, 0xA0, FLAG_N | FLAG_V),
+            (0x05, 0x05, FLAG_C, 0x00, FLAG_Z | FLAG_C),
+            (0x10, 0x01, FLAG_D | FLAG_C, 0x09, FLAG_D | FLAG_C),
+            (0x00, 0x01, FLAG_D | FLAG_C, 0x99, FLAG_D | FLAG_N),
+        ];
+        for (acc, val, flags, want_acc, want_flags) in sbc {
+            let mut cpu = cpu(acc, flags);
+            cpu.operate(Operation::Sbc, val);
+            assert_eq!((want_acc, want_flags), (cpu.acc, cpu.flags), "sbc {acc:02X} {val:02X}");
+        }
+    }
+
+    #[test]
+    fn test_nmos_decimal() {
+        // The NMOS 6502 sets Z from the binary sum, and N from the unadjusted one.
+        let mut cpu = cpu(0x99, FLAG_D);
+        cpu.variant = CpuVariant::Nmos6502;
+        cpu.operate(Operation::Adc, 0x01);
+        assert_eq!((0x00, FLAG_D | FLAG_N | FLAG_C), (cpu.acc, cpu.flags));
+        cpu.flags = FLAG_D;
+        cpu.acc = 0x00;
+        cpu.operate(Operation::Sbc, 0x00);
+        assert_eq!((0x99, FLAG_D | FLAG_N), (cpu.acc, cpu.flags));
+
+        // The 2A03 ignores D.
+        cpu.variant = CpuVariant::Ricoh2A03;
+        cpu.acc = 0x09;
+        cpu.operate(Operation::Adc, 0x01);
+        assert_eq!((0x0A, FLAG_D), (cpu.acc, cpu.flags));
+    }
+
+    #[test]
+    fn test_undocumented() {
+        let mut cpu = cpu(0xF0, FLAG_C);
+        cpu.variant = CpuVariant::Nmos6502;
+        cpu.scratch1 = 0x41;
+        cpu.modify(Operation::Slo, Register::Scratch1);
+        assert_eq!((0x82, 0xF2, FLAG_N), (cpu.scratch1, cpu.acc, cpu.flags));
+        cpu.scratch1 = 0xF3;
+        cpu.modify(Operation::Dcp, Register::Scratch1);
+        assert_eq!((0xF2, FLAG_Z | FLAG_C), (cpu.scratch1, cpu.flags));
+        cpu.x = 0x0F;
+        cpu.operate(Operation::Sbx, 0x03);
+        assert_eq!((0xF2, 0xFF, FLAG_N), (cpu.acc, cpu.x, cpu.flags));
+        cpu.operate(Operation::Arr, 0xC0);
+        assert_eq!((0x60, FLAG_C), (cpu.acc, cpu.flags));
+        cpu.unstable.lxa_magic = 0x00;
+        cpu.operate(Operation::Lxa, 0xFF);
+        assert_eq!((0x60, 0x60), (cpu.acc, cpu.x));
+    }
+
+    #[test]
+    fn test_modify() {
+        let mut cpu = cpu(0x0F, FLAG_C);
+        cpu.scratch1 = 0x81;
+        cpu.modify(Operation::Ror, Register::Scratch1);
+        assert_eq!((0xC0, FLAG_N | FLAG_C), (cpu.scratch1, cpu.flags));
+        cpu.modify(Operation::Tsb, Register::Scratch1);
+        assert_eq!((0xCF, FLAG_N | FLAG_C | FLAG_Z), (cpu.scratch1, cpu.flags));
+        cpu.modify(Operation::Rmb(7), Register::Scratch1);
+        assert_eq!(0x4F, cpu.scratch1);
+        cpu.modify(Operation::Transfer(Register::Acc), Register::Sp);
+        assert_eq!((0x0F, FLAG_N | FLAG_C | FLAG_Z), (cpu.sp, cpu.flags));
+    }
+}
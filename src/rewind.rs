@@ -0,0 +1,160 @@
+// Rewind
+// Stepping backwards, by keeping the history of a run. Every so many cycles a
+// snapshot of the cpu is kept, and for every cycle, the inputs the bus gave
+// the cpu and the value each write replaced. To step back to an earlier cycle,
+// writes since then are undone, and the cpu is restored from the last snapshot
+// before it and run forward on the recorded inputs.
+//
+// Only memory is rewound (see Bus::is_memory). Devices keep their state, and
+// writes to them aren't undone, so rewinding past device accesses gives an
+// approximation at best.
+use std::cell::Cell;
+use std::collections::VecDeque;
+use crate::bus::Bus;
+use crate::{Inputs, W6502};
+
+// What happened on the bus during a cycle.
+struct Recorded {
+    // The data read, if the cpu read.
+    read: Option<u8>,
+    irq: bool,
+    // The address written and the value it held before, if the cpu wrote
+    // to memory.
+    write: Option<(u16, u8)>,
+    // Whether the cycle ended at an instruction boundary.
+    sync: bool,
+}
+
+// Passes accesses through to a bus, recording them for a cycle.
+struct Recorder<'a, B: Bus> {
+    bus: &'a mut B,
+    read: Option<u8>,
+    irq: Cell<bool>,
+    write: Option<(u16, u8)>,
+}
+
+impl<B: Bus> Bus for Recorder<'_, B> {
+    fn read(&mut self, addr: u16) -> u8 {
+        let val = self.bus.read(addr);
+        self.read = Some(val);
+        val
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        if self.bus.is_memory(addr) {
+            self.write = Some((addr, self.bus.peek(addr)));
+        }
+        self.bus.write(addr, val);
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+    fn tick(&mut self) {
+        self.bus.tick();
+    }
+    fn irq(&self) -> bool {
+        let irq = self.bus.irq();
+        self.irq.set(irq);
+        irq
+    }
+    fn is_memory(&self, addr: u16) -> bool {
+        self.bus.is_memory(addr)
+    }
+}
+
+pub(crate) struct History {
+    // Cycles between snapshots, and how many snapshots to keep.
+    interval: u64,
+    capacity: usize,
+    // Snapshots, oldest first, with the cycle they were taken at.
+    snapshots: VecDeque<(u64, W6502)>,
+    // Each cycle since the oldest snapshot.
+    cycles: VecDeque<Recorded>,
+}
+
+impl History {
+    pub(crate) fn new(interval: u64, capacity: usize) -> History {
+        History {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            snapshots: VecDeque::new(),
+            cycles: VecDeque::new(),
+        }
+    }
+
+    // The cycle of the oldest snapshot, which is as far back as rewinding goes.
+    pub(crate) fn oldest(&self) -> Option<u64> {
+        self.snapshots.front().map(|(cycle, _)| *cycle)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.snapshots.clear();
+        self.cycles.clear();
+    }
+
+    // Run and record a cycle, given the number of cycles run before it.
+    pub(crate) fn cycle(&mut self, cpu: &mut W6502, bus: &mut impl Bus, cycle: u64)
+        -> Result<(), String> {
+        let due = match self.snapshots.back() {
+            None => true,
+            Some((last, _)) => cycle.is_multiple_of(self.interval) && *last < cycle,
+        };
+        if due {
+            self.snapshots.push_back((cycle, cpu.clone()));
+            if self.snapshots.len() > self.capacity {
+                let (dropped, _) = self.snapshots.pop_front().unwrap();
+                let oldest = self.snapshots[0].0;
+                self.cycles.drain(.. (oldest - dropped) as usize);
+            }
+        }
+        let mut recorder = Recorder { bus, read: None, irq: Cell::new(false), write: None };
+        cpu.cycle_bus(&mut recorder)?;
+        self.cycles.push_back(Recorded {
+            read: recorder.read,
+            irq: recorder.irq.get(),
+            write: recorder.write,
+            sync: cpu.outputs().sync,
+        });
+        Ok(())
+    }
+
+    // The latest cycle before `now` that ended at an instruction boundary.
+    pub(crate) fn previous_boundary(&self, now: u64) -> Option<u64> {
+        let (oldest, first) = self.snapshots.front()?;
+        let oldest = *oldest;
+        (oldest + 1 .. now).rev()
+            .find(|&cycle| self.cycles[(cycle - oldest - 1) as usize].sync)
+            .or((oldest < now && first.outputs().sync).then_some(oldest))
+    }
+
+    // Go back from cycle `now` to cycle `target`, undoing writes to memory,
+    // and returning the cpu as it was then. History after the target is
+    // dropped.
+    pub(crate) fn rewind(&mut self, bus: &mut impl Bus, now: u64, target: u64)
+        -> Result<W6502, String> {
+        let oldest = self.oldest().ok_or("No history to rewind")?;
+        if target < oldest || target > now {
+            return Err(format!("Cycle {target} isn't in the history, which starts at {oldest}"));
+        }
+        let kept = (target - oldest) as usize;
+        for recorded in self.cycles.drain(kept ..).rev() {
+            if let Some((addr, old)) = recorded.write {
+                if bus.peek(addr) != old {
+                    bus.write(addr, old);
+                }
+            }
+        }
+        while self.snapshots.back().is_some_and(|(cycle, _)| *cycle > target) {
+            self.snapshots.pop_back();
+        }
+        let (start, cpu) = self.snapshots.back().unwrap();
+        let mut cpu = cpu.clone();
+        let start = (start - oldest) as usize;
+        for recorded in self.cycles.range(start .. kept) {
+            let outputs = cpu.outputs();
+            // As cycle_bus would have given the cpu.
+            let data = if outputs.rwb { recorded.read.unwrap_or(0) } else { outputs.data.unwrap_or(0) };
+            cpu.cycle(&Inputs { data, n_irq: !recorded.irq, ..Inputs::default() })?;
+        }
+        Ok(cpu)
+    }
+}
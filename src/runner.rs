@@ -0,0 +1,364 @@
+// Runner
+// Drives the model against a bus until something interesting happens:
+// a cycle limit, a trap address, or an instruction that stops execution.
+//
+// This is the library side of the command line runner, and is useful
+// anywhere a program should be run to completion headlessly, such as
+// firmware smoke tests.
+use crate::bus::Bus;
+use crate::rewind::History;
+use crate::{CpuVariant, Inputs, W6502};
+
+// Why a run stopped. Addresses are those of the instruction about to be
+// fetched, which has not been executed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stop {
+    CycleLimit,
+    Trap(u16),
+    Brk(u16),
+    Stp(u16),
+    // An NMOS jam, which locks up the cpu like stp.
+    Jam(u16),
+    // The model could not continue, e.g. due to an unsupported opcode.
+    Error(String),
+}
+
+impl std::fmt::Display for Stop {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Stop::CycleLimit => write!(f, "cycle limit"),
+            Stop::Trap(addr) => write!(f, "trap at ${addr:04X}"),
+            Stop::Brk(addr) => write!(f, "brk at ${addr:04X}"),
+            Stop::Stp(addr) => write!(f, "stp at ${addr:04X}"),
+            Stop::Jam(addr) => write!(f, "jam at ${addr:04X}"),
+            Stop::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
+
+// How the runner advances the cpu.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stepping {
+    // A cycle at a time, as the chip would.
+    Cycle,
+    // A whole instruction at a time, see W6502::step_instruction. This is
+    // faster, but a run can go past its cycle limit to finish an instruction.
+    Instruction,
+}
+
+pub struct Runner<B: Bus> {
+    cpu: W6502,
+    bus: B,
+    // Cycles since reset.
+    cycles: u64,
+    traps: Vec<u16>,
+    stepping: Stepping,
+    // Kept while rewinding is enabled.
+    history: Option<History>,
+}
+
+impl<B: Bus> Runner<B> {
+    // Create a runner, with the cpu reset and about to read the reset vector.
+    pub fn new(bus: B) -> Runner<B> {
+        Runner::with_variant(bus, CpuVariant::default())
+    }
+
+    pub fn with_variant(bus: B, variant: CpuVariant) -> Runner<B> {
+        let mut runner = Runner {
+            cpu: W6502::new(variant),
+            bus,
+            cycles: 0,
+            traps: Vec::new(),
+            stepping: Stepping::Cycle,
+            history: None,
+        };
+        runner.reset();
+        runner
+    }
+
+    // Hold reset for two cycles, as done by a typical reset circuit.
+    pub fn reset(&mut self) {
+        let inputs = Inputs {
+            n_reset: false,
+            ..Inputs::default()
+        };
+        for _ in 0 .. 2 {
+            // Nothing is decoded during reset, so this can't fail.
+            self.cpu.cycle(&inputs).unwrap();
+        }
+        self.cycles = 0;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    // Stop before fetching an instruction from addr.
+    pub fn add_trap(&mut self, addr: u16) {
+        self.traps.push(addr);
+    }
+
+    // Returns whether there was a trap at addr.
+    pub fn remove_trap(&mut self, addr: u16) -> bool {
+        let len = self.traps.len();
+        self.traps.retain(|&trap| trap != addr);
+        self.traps.len() != len
+    }
+
+    pub fn traps(&self) -> &[u16] {
+        &self.traps
+    }
+
+    // Switch between cycle and instruction stepping. Either way, the switch
+    // happens at an instruction boundary: instruction stepping finishes any
+    // instruction in progress one cycle at a time.
+    pub fn set_stepping(&mut self, stepping: Stepping) {
+        self.stepping = stepping;
+    }
+
+    // Keep the history needed to step backwards, see rewind.rs. A snapshot is
+    // kept every `interval` cycles, and the last `snapshots` of them are kept,
+    // so it is possible to go back at least interval * (snapshots - 1) cycles.
+    //
+    // While rewinding is enabled, instructions are run a cycle at a time, so
+    // that every cycle can be stepped back to.
+    pub fn enable_rewind(&mut self, interval: u64, snapshots: usize) {
+        self.history = Some(History::new(interval, snapshots));
+    }
+
+    // Go back to an earlier cycle, counted since reset.
+    pub fn rewind_to(&mut self, cycle: u64) -> Result<(), String> {
+        let history = self.history.as_mut().ok_or("Rewinding isn't enabled")?;
+        self.cpu = history.rewind(&mut self.bus, self.cycles, cycle)?;
+        self.cycles = cycle;
+        Ok(())
+    }
+
+    // Undo the last cycle.
+    pub fn step_back_cycle(&mut self) -> Result<(), String> {
+        let cycle = self.cycles.checked_sub(1).ok_or("At the start of the run")?;
+        self.rewind_to(cycle)
+    }
+
+    // Go back to the start of the previous instruction.
+    pub fn step_back_instruction(&mut self) -> Result<(), String> {
+        let history = self.history.as_ref().ok_or("Rewinding isn't enabled")?;
+        let cycle = history.previous_boundary(self.cycles)
+            .ok_or("No earlier instruction in the history")?;
+        self.rewind_to(cycle)
+    }
+
+    pub fn cpu(&self) -> &W6502 {
+        &self.cpu
+    }
+    pub fn cpu_mut(&mut self) -> &mut W6502 {
+        &mut self.cpu
+    }
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Run a single cycle, returning the reason to stop, if any.
+    pub fn step_cycle(&mut self) -> Option<Stop> {
+        let result = match &mut self.history {
+            None => self.cpu.cycle_bus(&mut self.bus),
+            Some(history) => history.cycle(&mut self.cpu, &mut self.bus, self.cycles),
+        };
+        if let Err(e) = result {
+            return Some(Stop::Error(e));
+        }
+        self.cycles += 1;
+        self.check_stop()
+    }
+
+    // Run a single instruction, or finish the one in progress.
+    pub fn step_instruction(&mut self) -> Option<Stop> {
+        if self.history.is_some() {
+            loop {
+                if let Some(stop) = self.step_cycle() {
+                    return Some(stop);
+                }
+                if self.cpu.outputs().sync || self.cpu.halted() {
+                    return None;
+                }
+            }
+        }
+        match self.cpu.step_instruction(&mut self.bus) {
+            Ok(cycles) => self.cycles += cycles as u64,
+            Err(e) => return Some(Stop::Error(e)),
+        }
+        self.check_stop()
+    }
+
+    // Run until stopped, or until max_cycles more cycles have run.
+    pub fn run(&mut self, max_cycles: Option<u64>) -> Stop {
+        let start = self.cycles;
+        loop {
+            if max_cycles.is_some_and(|max| self.cycles - start >= max) {
+                return Stop::CycleLimit;
+            }
+            let stop = match self.stepping {
+                Stepping::Cycle => self.step_cycle(),
+                Stepping::Instruction => self.step_instruction(),
+            };
+            if let Some(stop) = stop {
+                return stop;
+            }
+        }
+    }
+
+    // Instructions are checked as their opcode address is placed on the bus,
+    // which is before the cpu has read or acted on the opcode.
+    fn check_stop(&self) -> Option<Stop> {
+        let outputs = self.cpu.outputs();
+        if !outputs.sync {
+            return None;
+        }
+        let addr = outputs.address;
+        if self.traps.contains(&addr) {
+            return Some(Stop::Trap(addr));
+        }
+        let opcode = self.bus.peek(addr);
+        match self.cpu.variant().opcodes()[opcode as usize].mnemonic {
+            "BRK" => Some(Stop::Brk(addr)),
+            "STP" => Some(Stop::Stp(addr)),
+            "JAM" => Some(Stop::Jam(addr)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory_map::MemoryMap;
+
+    #[test]
+    fn test_run_to_trap() {
+        let image = std::fs::read("passing_traces/load_store_regs_basic.bin").unwrap();
+        let mut runner = Runner::new(MemoryMap::from_image(&image).unwrap());
+        // the program ends in a jmp to itself at .loop
+        runner.add_trap(0x031E);
+        assert_eq!(Stop::Trap(0x031E), runner.run(Some(1000)));
+        let regs = runner.cpu().registers();
+        assert_eq!((0x031E, 0xAD, 0xFE, 0xDE), (regs.pc, regs.acc, regs.x, regs.y));
+        assert_eq!(0xAD, runner.bus().peek(0x0010));
+
+        // continuing runs past the trap, and around the loop back to it.
+        assert_eq!(Stop::Trap(0x031E), runner.run(Some(1000)));
+        assert_eq!(Stop::CycleLimit, runner.run(Some(2)));
+    }
+
+    #[test]
+    fn test_instruction_stepping() {
+        // Stepping by instruction stops in the same place, after the same
+        // number of cycles, and the two can be switched between.
+        let image = std::fs::read("passing_traces/load_store_regs_basic.bin").unwrap();
+        let mut cycle = Runner::new(MemoryMap::from_image(&image).unwrap());
+        let mut instruction = Runner::new(MemoryMap::from_image(&image).unwrap());
+        instruction.set_stepping(Stepping::Instruction);
+        for runner in [&mut cycle, &mut instruction] {
+            runner.add_trap(0x031E);
+            assert_eq!(Stop::Trap(0x031E), runner.run(Some(1000)));
+        }
+        assert_eq!(cycle.cycles(), instruction.cycles());
+        assert_eq!(cycle.cpu().registers(), instruction.cpu().registers());
+
+        instruction.set_stepping(Stepping::Cycle);
+        cycle.set_stepping(Stepping::Instruction);
+        for runner in [&mut cycle, &mut instruction] {
+            assert_eq!(Stop::Trap(0x031E), runner.run(Some(1000)));
+        }
+        assert_eq!(cycle.cycles(), instruction.cycles());
+    }
+
+    #[test]
+    fn test_rewind() {
+        // Stepping back gives the same state as when going forward, and then
+        // running forward again does the same as before.
+        let image = std::fs::read("passing_traces/load_store_regs_basic.bin").unwrap();
+        let mut runner = Runner::new(MemoryMap::from_image(&image).unwrap());
+        runner.enable_rewind(16, 4);
+        let mut states = Vec::new();
+        for _ in 0 .. 100 {
+            states.push((runner.cpu().registers(), runner.cpu().outputs().address, runner.bus().peek(0x10)));
+            runner.step_cycle();
+        }
+        for cycle in [99, 98, 80, 60, 52] {
+            runner.rewind_to(cycle).unwrap();
+            let state = (runner.cpu().registers(), runner.cpu().outputs().address, runner.bus().peek(0x10));
+            assert_eq!(states[cycle as usize], state, "cycle {cycle}");
+        }
+        // Only 3 intervals back are kept.
+        assert!(runner.rewind_to(20).is_err());
+
+        runner.step_back_instruction().unwrap();
+        assert!(runner.cpu().outputs().sync);
+        let boundary = runner.cycles();
+        runner.step_instruction();
+        runner.step_back_cycle().unwrap();
+        assert!(runner.cycles() > boundary);
+        runner.add_trap(0x031E);
+        assert_eq!(Stop::Trap(0x031E), runner.run(Some(1000)));
+        assert_eq!(0xAD, runner.bus().peek(0x0010));
+    }
+
+    #[test]
+    fn test_rewind_devices() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use crate::assembler::assemble;
+        use crate::memory_map::{Device, MemoryMapBuilder};
+
+        // Counts the writes to it.
+        struct Writes(u32);
+        impl Device for Writes {
+            fn read(&mut self, _offset: u16) -> u8 {
+                0
+            }
+            fn write(&mut self, _offset: u16, _val: u8) {
+                self.0 += 1;
+            }
+        }
+
+        // Rewinding undoes the write to memory, but leaves the device alone.
+        let image = assemble("
+            org $0200
+              lda #$42
+              sta $6000
+              sta $10
+              jmp *
+            org $FFFC
+              dw $0200
+        ").unwrap();
+        let writes = Rc::new(RefCell::new(Writes(0)));
+        let map = MemoryMapBuilder::new()
+            .ram_image(0, &image).unwrap()
+            .device(0x6000 ..= 0x600F, Box::new(writes.clone()))
+            .build().unwrap();
+        let mut runner = Runner::new(map);
+        runner.step_instruction();
+        runner.enable_rewind(16, 4);
+        let start = runner.cycles();
+        runner.add_trap(0x0207);
+        assert_eq!(Stop::Trap(0x0207), runner.run(Some(100)));
+        assert_eq!(0x42, runner.bus().peek(0x10));
+        runner.rewind_to(start).unwrap();
+        assert_eq!(0x00, runner.bus().peek(0x10));
+        assert_eq!(1, writes.borrow().0);
+    }
+
+    #[test]
+    fn test_run_to_brk() {
+        let mut image = vec![0xEA; 0x10000];
+        image[0xFFFC..].copy_from_slice(&[0x00, 0x02, 0x00, 0x00]);
+        image[0x0210] = 0x00;
+        let mut runner = Runner::new(MemoryMap::from_image(&image).unwrap());
+        assert_eq!(Stop::Brk(0x0210), runner.run(None));
+    }
+}
@@ -0,0 +1,281 @@
+// Snapshots
+// Save and restore the complete state of the cpu as bytes, including an
+// instruction part way through, for save states, debuggers and rewinding. A
+// restored cpu behaves exactly as the original would have from that point.
+//
+// The format is a header followed by each field in a fixed order. The version
+// is bumped whenever the fields change, and snapshots from other versions are
+// rejected rather than converted.
+//
+// Uop programs are static, so rather than saving their uops, a snapshot saves
+// what the program was built for, and the position within it.
+use crate::decode;
+use crate::opcodes::CpuVariant;
+use crate::{Outputs, UOp, Unstable, W6502};
+
+const MAGIC: &[u8] = b"W65S";
+const VERSION: u8 = 1;
+
+// The running program.
+const PROGRAM_NONE: u8 = 0;
+const PROGRAM_RESET: u8 = 1;
+const PROGRAM_IRQ: u8 = 2;
+const PROGRAM_NMI: u8 = 3;
+const PROGRAM_OPCODE: u8 = 4;
+
+// Where the uop for the current cycle came from.
+const ACTIVE_FETCH: u8 = 0;
+const ACTIVE_NOP: u8 = 1;
+const ACTIVE_PROGRAM: u8 = 2;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Result<u8, String> {
+        let val = *self.data.get(self.pos).ok_or("Snapshot is truncated")?;
+        self.pos += 1;
+        Ok(val)
+    }
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+    fn bool(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            val => Err(format!("Bad bool in snapshot: {val}")),
+        }
+    }
+}
+
+impl W6502 {
+    // Which program is running, from the static programs it could be.
+    fn program_id(&self) -> u8 {
+        let is = |program: &[UOp]| std::ptr::eq(self.program, program);
+        if self.program.is_empty() {
+            PROGRAM_NONE
+        } else if is(&decode::RESET) {
+            PROGRAM_RESET
+        } else if is(&decode::IRQ) {
+            PROGRAM_IRQ
+        } else if is(&decode::NMI) {
+            PROGRAM_NMI
+        } else if is(&decode::program(self.variant, self.opcode).uops) {
+            PROGRAM_OPCODE
+        } else {
+            // Instruction stepping leaves the last program in place without
+            // updating the opcode, but only once it has finished.
+            assert_eq!(self.step, self.program.len(), "Unknown program running");
+            PROGRAM_NONE
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        let u16 = |out: &mut Vec<u8>, val: u16| out.extend(val.to_le_bytes());
+        out.push(VERSION);
+        out.push(CpuVariant::ALL.iter().position(|&v| v == self.variant).unwrap() as u8);
+        out.extend([self.unstable.ane_magic, self.unstable.lxa_magic, self.unstable.and_high as u8]);
+
+        u16(&mut out, self.outputs.address);
+        out.extend([self.outputs.data.is_some() as u8, self.outputs.data.unwrap_or(0)]);
+        out.extend([self.outputs.rwb as u8, self.outputs.sync as u8, self.prev_clk as u8]);
+
+        let program = self.program_id();
+        let active = if self.active_uop == UOp::Fetch {
+            ACTIVE_FETCH
+        } else if program != PROGRAM_NONE && self.step > 0
+                && self.program[self.step - 1] == self.active_uop {
+            ACTIVE_PROGRAM
+        } else {
+            assert_eq!(UOp::Nop, self.active_uop, "Active uop isn't from the program");
+            ACTIVE_NOP
+        };
+        let step = if program == PROGRAM_NONE { 0 } else { self.step };
+        out.extend([program, step as u8, active, self.opcode]);
+        u16(&mut out, self.opcode_addr);
+
+        u16(&mut out, self.pc);
+        out.extend([self.acc, self.x, self.y, self.sp, self.flags, self.scratch1, self.scratch2]);
+        u16(&mut out, self.ea);
+        out.extend([self.page_crossed, self.branch_taken, self.irq_pending, self.nmi_pending,
+                    self.prev_n_nmi, self.prev_n_so].map(|b| b as u8));
+        out
+    }
+
+    // Recreate a cpu from a snapshot. Errors are for snapshots that are
+    // corrupt, or from another version.
+    pub fn restore(data: &[u8]) -> Result<W6502, String> {
+        if !data.starts_with(MAGIC) {
+            return Err("Not a snapshot".to_string());
+        }
+        let mut r = Reader { data, pos: MAGIC.len() };
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(format!("Unsupported snapshot version: {version}, expected {VERSION}"));
+        }
+        let variant = r.u8()?;
+        let variant = *CpuVariant::ALL.get(variant as usize)
+            .ok_or(format!("Bad variant in snapshot: {variant}"))?;
+        let mut cpu = W6502::new(variant);
+        cpu.unstable = Unstable { ane_magic: r.u8()?, lxa_magic: r.u8()?, and_high: r.bool()? };
+
+        let address = r.u16()?;
+        let driven = r.bool()?;
+        let driven_data = r.u8()?;
+        cpu.outputs = Outputs {
+            address,
+            data: driven.then_some(driven_data),
+            rwb: r.bool()?,
+            sync: r.bool()?,
+        };
+        cpu.prev_clk = r.bool()?;
+
+        let (program, step, active) = (r.u8()?, r.u8()? as usize, r.u8()?);
+        cpu.opcode = r.u8()?;
+        cpu.opcode_addr = r.u16()?;
+        cpu.program = match program {
+            PROGRAM_NONE => &[],
+            PROGRAM_RESET => &decode::RESET,
+            PROGRAM_IRQ => &decode::IRQ,
+            PROGRAM_NMI => &decode::NMI,
+            PROGRAM_OPCODE => &decode::program(variant, cpu.opcode).uops,
+            _ => return Err(format!("Bad program in snapshot: {program}")),
+        };
+        if step > cpu.program.len() {
+            return Err(format!("Bad program step in snapshot: {step}"));
+        }
+        cpu.step = step;
+        cpu.active_uop = match active {
+            ACTIVE_FETCH => UOp::Fetch,
+            ACTIVE_NOP => UOp::Nop,
+            ACTIVE_PROGRAM if step > 0 => cpu.program[step - 1],
+            _ => return Err(format!("Bad active uop in snapshot: {active}")),
+        };
+
+        cpu.pc = r.u16()?;
+        cpu.acc = r.u8()?;
+        cpu.x = r.u8()?;
+        cpu.y = r.u8()?;
+        cpu.sp = r.u8()?;
+        cpu.flags = r.u8()?;
+        cpu.scratch1 = r.u8()?;
+        cpu.scratch2 = r.u8()?;
+        cpu.ea = r.u16()?;
+        cpu.page_crossed = r.bool()?;
+        cpu.branch_taken = r.bool()?;
+        cpu.irq_pending = r.bool()?;
+        cpu.nmi_pending = r.bool()?;
+        cpu.prev_n_nmi = r.bool()?;
+        cpu.prev_n_so = r.bool()?;
+        if r.pos != data.len() {
+            return Err("Snapshot has trailing data".to_string());
+        }
+        Ok(cpu)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::trace_log::TraceReader;
+    use crate::Inputs;
+
+    type Pins = (u16, Option<u8>, bool, bool);
+
+    // Run the cpu for a cycle per stimulus, with data read from memory.
+    // Returns the cpu before each cycle, and the outputs after it.
+    fn run(mut cpu: W6502, memory: &[u8], stimulus: &[Inputs]) -> (Vec<W6502>, Vec<Pins>) {
+        let mut cpus = Vec::new();
+        let mut pins = Vec::new();
+        for inputs in stimulus {
+            cpus.push(cpu.clone());
+            let inputs = Inputs { data: memory[cpu.outputs().address as usize], ..*inputs };
+            cpu.cycle(&inputs).unwrap();
+            let outputs = cpu.outputs();
+            pins.push((outputs.address, outputs.data, outputs.rwb, outputs.sync));
+        }
+        (cpus, pins)
+    }
+
+    // Snapshot at every cycle, and check that the restored cpu does the same
+    // as the original for the rest of the run.
+    fn check_every_cycle(cpu: W6502, memory: &[u8], stimulus: &[Inputs]) {
+        let (cpus, want) = run(cpu, memory, stimulus);
+        for (cycle, cpu) in cpus.into_iter().enumerate() {
+            let restored = W6502::restore(&cpu.snapshot()).unwrap();
+            let (_, have) = run(restored, memory, &stimulus[cycle ..]);
+            assert_eq!(want[cycle ..], have, "restored at cycle {cycle}");
+        }
+    }
+
+    fn reset(variant: CpuVariant) -> W6502 {
+        let mut cpu = W6502::new(variant);
+        for _ in 0 .. 2 {
+            cpu.cycle(&Inputs { n_reset: false, ..Inputs::default() }).unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_traces() {
+        for name in ["load_store_regs_basic", "nop_jmp_loop"] {
+            let memory = std::fs::read(format!("passing_traces/{name}.bin")).unwrap();
+            let log = std::fs::read_to_string(format!("passing_traces/{name}.log")).unwrap();
+            let (_, log) = log.split_once('\n').unwrap();
+            let (log, _) = log.split_once("===END").unwrap();
+            let (_, trace) = TraceReader::new(log.as_bytes()).unwrap();
+            let stimulus : Vec<Inputs> = trace.map(|line| {
+                let mut inputs = Inputs::default();
+                line.unwrap().stimulus.apply(&mut inputs);
+                inputs
+            }).collect();
+            check_every_cycle(reset(CpuVariant::W65C02S), &memory, &stimulus);
+        }
+    }
+
+    #[test]
+    fn test_interrupts() {
+        // Snapshots during interrupts, and NMOS read-modify-writes.
+        let memory = assemble("
+            org $0200
+            .start:
+              cli
+            .loop:
+              inc $10
+              ror $0300,x
+              jmp .loop
+            .handler:
+              pha
+              pla
+              rti
+            org $FFFA
+              dw .handler
+              dw .start
+              dw .handler
+        ").unwrap();
+        let stimulus : Vec<Inputs> = (0 .. 120).map(|cycle| Inputs {
+            n_irq: !(40 .. 45).contains(&cycle),
+            n_nmi: !(80 .. 82).contains(&cycle),
+            ..Inputs::default()
+        }).collect();
+        for variant in [CpuVariant::Nmos6502, CpuVariant::W65C02S] {
+            check_every_cycle(reset(variant), &memory, &stimulus);
+        }
+    }
+
+    #[test]
+    fn test_bad_snapshots() {
+        let snapshot = W6502::new(CpuVariant::W65C02S).snapshot();
+        assert!(W6502::restore(&snapshot).is_ok());
+        assert!(W6502::restore(&snapshot[.. snapshot.len() - 1]).is_err());
+        let mut version = snapshot.clone();
+        version[MAGIC.len()] = VERSION + 1;
+        assert!(W6502::restore(&version).is_err());
+        assert!(W6502::restore(b"not a snapshot").is_err());
+    }
+}
@@ -0,0 +1,382 @@
+// Instruction Stepping
+// A faster way to run the model when pin accuracy isn't needed: whole
+// instructions are executed against a Bus, rather than one clock phase at a time.
+//
+// This shares the opcode table, the mnemonic behaviors and the operations with
+// the cycle model, so the registers and memory end up the same, and cycle
+// counts include the same page crossing, branch and decimal penalties. What is
+// lost is what happens within an instruction:
+// - dummy reads aren't made, which only matters for devices with read side effects.
+// - the bus ticks after the instruction's accesses, rather than between them.
+//
+// Stepping starts and ends at instruction boundaries, where the cycle model has
+// just put an opcode fetch on the bus, so the two can be switched between at
+// any boundary. Anything that isn't an ordinary instruction (finishing reset,
+// interrupts, wai, stp and jam) is run on the cycle model.
+use crate::bus::Bus;
+use crate::decode::{self, behavior, Behavior};
+use crate::opcodes::Mode;
+use crate::operations::{Condition, Operation, FLAG_B, FLAG_D, FLAG_UNUSED};
+use crate::{Register, UOp, When, W6502, IRQ_VECTOR};
+
+fn read_word(bus: &mut impl Bus, addr: u16) -> u16 {
+    u16::from_le_bytes([bus.read(addr), bus.read(addr.wrapping_add(1))])
+}
+
+// Read a pointer from the zero page, wrapping within it.
+fn read_pointer(bus: &mut impl Bus, ptr: u8) -> u16 {
+    u16::from_le_bytes([bus.read(ptr as u16), bus.read(ptr.wrapping_add(1) as u16)])
+}
+
+impl W6502 {
+    // Whether the cpu has just started an opcode fetch.
+    fn at_fetch(&self) -> bool {
+        self.outputs.sync && matches!(self.active_uop, UOp::Fetch)
+    }
+
+    // Run the instruction at the current boundary, and stop at the next one.
+    // Returns the number of cycles taken.
+    //
+    // If the cpu is part way through an instruction, such as after reset, or
+    // is starting an interrupt, this runs cycles until the next boundary
+    // instead. While the cpu is stopped or waiting, one cycle is run.
+    pub fn step_instruction(&mut self, bus: &mut impl Bus) -> Result<u32, String> {
+        let next = self.variant.opcodes()[bus.peek(self.pc) as usize].mnemonic;
+        if !self.at_fetch() || matches!(next, "WAI" | "STP" | "JAM") {
+            return self.cycle_to_boundary(bus);
+        }
+        let opcode = bus.read(self.pc);
+        self.opcode = opcode;
+        self.opcode_addr = self.pc;
+        let cycles = self.execute(bus, opcode);
+
+        // Tick the bus once per cycle, sampling irq before the last tick as
+        // cycle_bus would, then start the next instruction.
+        for _ in 1 .. cycles {
+            bus.tick();
+        }
+        self.irq_pending = bus.irq();
+        bus.tick();
+        self.outputs.zero();
+        self.outputs.sync = true;
+        self.active_uop = self.start_instruction();
+        self.set_addr(self.pc);
+        Ok(cycles)
+    }
+
+    // Whether the cpu is stopped, or waiting for an interrupt.
+    pub(crate) fn halted(&self) -> bool {
+        matches!(self.active_uop, UOp::Wait | UOp::Stop)
+    }
+
+    // Whether the cpu is part way through the reset sequence.
+    pub(crate) fn resetting(&self) -> bool {
+        std::ptr::eq(self.program, &decode::RESET[..]) && !self.outputs.sync
+    }
+
+    // Whether the cpu is stopped, which only reset ends.
+    pub(crate) fn stopped(&self) -> bool {
+        self.active_uop == UOp::Stop
+    }
+
+    fn cycle_to_boundary(&mut self, bus: &mut impl Bus) -> Result<u32, String> {
+        let mut cycles = 0;
+        loop {
+            self.cycle_bus(bus)?;
+            cycles += 1;
+            if self.outputs.sync || self.halted() {
+                return Ok(cycles);
+            }
+        }
+    }
+
+    // Find the operand of an instruction at addr, returning its address and
+    // whether indexing crossed a page.
+    fn operand_address(&mut self, bus: &mut impl Bus, mode: Mode, addr: u16) -> (u16, bool) {
+        let operand = addr.wrapping_add(1);
+        let indexed = |base: u16, index: u8| {
+            let ea = base.wrapping_add(index as u16);
+            (ea, (base ^ ea) & 0xFF00 != 0)
+        };
+        match mode {
+            Mode::Immediate => (operand, false),
+            Mode::ZeroPage => (bus.read(operand) as u16, false),
+            Mode::ZeroPageX => (bus.read(operand).wrapping_add(self.x) as u16, false),
+            Mode::ZeroPageY => (bus.read(operand).wrapping_add(self.y) as u16, false),
+            Mode::ZeroPageIndirect => {
+                let ptr = bus.read(operand);
+                (read_pointer(bus, ptr), false)
+            },
+            Mode::IndirectX => {
+                let ptr = bus.read(operand).wrapping_add(self.x);
+                (read_pointer(bus, ptr), false)
+            },
+            Mode::IndirectY => {
+                let ptr = bus.read(operand);
+                indexed(read_pointer(bus, ptr), self.y)
+            },
+            Mode::Absolute => (read_word(bus, operand), false),
+            Mode::AbsoluteX => indexed(read_word(bus, operand), self.x),
+            Mode::AbsoluteY => indexed(read_word(bus, operand), self.y),
+            _ => unreachable!("{mode:?} has no operand in memory"),
+        }
+    }
+
+    fn push_byte(&mut self, bus: &mut impl Bus, val: u8) {
+        bus.write(0x0100 | self.sp as u16, val);
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    fn pull_byte(&mut self, bus: &mut impl Bus) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        bus.read(0x0100 | self.sp as u16)
+    }
+
+    fn push_pc(&mut self, bus: &mut impl Bus) {
+        self.push_byte(bus, (self.pc >> 8) as u8);
+        self.push_byte(bus, self.pc as u8);
+    }
+
+    // Execute the instruction at pc, leaving pc at the next one. Returns the
+    // number of cycles taken.
+    fn execute(&mut self, bus: &mut impl Bus, opcode: u8) -> u32 {
+        let op = &self.variant.opcodes()[opcode as usize];
+        let addr = self.pc;
+        let mut cycles = op.cycles as u32;
+        self.pc = addr.wrapping_add(op.mode.size());
+        if !op.documented && op.mnemonic == "NOP" {
+            if op.mode != Mode::Implied {
+                let (_, crossed) = self.operand_address(bus, op.mode, addr);
+                cycles += crossed as u32;
+            }
+            return cycles;
+        }
+        match behavior(op.mnemonic, op.mode, self.variant) {
+            Behavior::Read(operation) => {
+                let (ea, crossed) = self.operand_address(bus, op.mode, addr);
+                self.operate(operation, bus.read(ea));
+                cycles += crossed as u32;
+                if !self.variant.is_nmos() && matches!(operation, Operation::Adc | Operation::Sbc) {
+                    cycles += (self.flags & FLAG_D != 0) as u32;
+                }
+            },
+            Behavior::Write(reg) => {
+                let (ea, _) = self.operand_address(bus, op.mode, addr);
+                bus.write(ea, self.reg(reg));
+            },
+            Behavior::StoreHigh(reg) => {
+                let (ea, crossed) = self.operand_address(bus, op.mode, addr);
+                let (ea, val) = self.store_high(reg, ea, crossed);
+                bus.write(ea, val);
+            },
+            Behavior::Modify(operation, _) if op.mode == Mode::Accumulator => {
+                self.modify(operation, Register::Acc);
+            },
+            Behavior::Modify(operation, penalty) => {
+                let (ea, crossed) = self.operand_address(bus, op.mode, addr);
+                self.scratch1 = bus.read(ea);
+                // The NMOS 6502 writes the unmodified value back first.
+                if self.variant.is_nmos() {
+                    bus.write(ea, self.scratch1);
+                }
+                self.modify(operation, Register::Scratch1);
+                bus.write(ea, self.scratch1);
+                cycles += (crossed && penalty != When::Always) as u32;
+            },
+            Behavior::Implied(operation, reg) => self.modify(operation, reg),
+            Behavior::Branch(condition) => {
+                self.operate(Operation::Branch(condition), bus.read(addr.wrapping_add(1)));
+                // bra's count already includes taking the branch.
+                cycles += (self.branch_taken && condition != Condition::Always) as u32;
+                cycles += self.page_crossed as u32;
+            },
+            Behavior::BranchBit(condition) => {
+                let zero_page = bus.read(addr.wrapping_add(1));
+                self.scratch1 = bus.read(zero_page as u16);
+                self.operate(Operation::Branch(condition), bus.read(addr.wrapping_add(2)));
+                cycles += self.branch_taken as u32 + self.page_crossed as u32;
+            },
+            Behavior::Push(Register::Flags) => {
+                self.push_byte(bus, self.flags | FLAG_B | FLAG_UNUSED);
+            },
+            Behavior::Push(reg) => self.push_byte(bus, self.reg(reg)),
+            Behavior::Pull(reg) => {
+                let val = self.pull_byte(bus);
+                match reg {
+                    Register::Flags => self.flags = val,
+                    _ => self.operate(Operation::Load(reg), val),
+                }
+            },
+            Behavior::Jmp => {
+                let target = read_word(bus, addr.wrapping_add(1));
+                self.pc = match op.mode {
+                    Mode::Absolute => target,
+                    // The NMOS 6502 wraps within the page when reading the pointer.
+                    Mode::Indirect if self.variant.is_nmos() => u16::from_le_bytes([
+                        bus.read(target),
+                        bus.read((target & 0xFF00) | (target.wrapping_add(1) & 0x00FF)),
+                    ]),
+                    Mode::Indirect => read_word(bus, target),
+                    _ => read_word(bus, target.wrapping_add(self.x as u16)),
+                };
+            },
+            Behavior::Jsr => {
+                // The return address pushed is that of the last byte of the jsr.
+                let low = bus.read(addr.wrapping_add(1));
+                self.pc = addr.wrapping_add(2);
+                self.push_pc(bus);
+                let high = bus.read(addr.wrapping_add(2));
+                self.pc = u16::from_le_bytes([low, high]);
+            },
+            Behavior::Rts => {
+                let low = self.pull_byte(bus);
+                let high = self.pull_byte(bus);
+                self.pc = u16::from_le_bytes([low, high]).wrapping_add(1);
+            },
+            Behavior::Rti => {
+                self.flags = self.pull_byte(bus);
+                let low = self.pull_byte(bus);
+                let high = self.pull_byte(bus);
+                self.pc = u16::from_le_bytes([low, high]);
+            },
+            Behavior::Brk => {
+                self.pc = addr.wrapping_add(1);
+                self.modify(Operation::Break, Register::Scratch1);
+                self.push_pc(bus);
+                self.push_byte(bus, self.scratch1);
+                self.pc = read_word(bus, IRQ_VECTOR);
+            },
+            Behavior::Wai | Behavior::Stp => unreachable!("wai and stp run on the cycle model"),
+            Behavior::Nop => {},
+        }
+        cycles
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::memory_map::MemoryMap;
+    use crate::{CpuVariant, Inputs};
+
+    const OPCODE_STP: u8 = 0xDB;
+    const OPCODE_JAM: u8 = 0x02;
+
+    // Step a program an instruction at a time, alongside the cycle model,
+    // until it reaches the end opcode.
+    fn compare(variant: CpuVariant, image: &[u8], end: u8) {
+        let mut cycle = W6502::new(variant);
+        cycle.cycle(&Inputs { n_reset: false, ..Inputs::default() }).unwrap();
+        let mut step = cycle.clone();
+        let mut cycle_bus = MemoryMap::from_image(image).unwrap();
+        let mut step_bus = MemoryMap::from_image(image).unwrap();
+
+        while cycle_bus.peek(cycle.registers().pc) != end {
+            let cycles = cycle.cycle_to_boundary(&mut cycle_bus).unwrap();
+            assert_eq!(cycles, step.step_instruction(&mut step_bus).unwrap());
+            assert_eq!(cycle.registers(), step.registers());
+        }
+        for addr in 0 ..= 0xFFFF {
+            assert_eq!(cycle_bus.peek(addr), step_bus.peek(addr), "${addr:04X}");
+        }
+    }
+
+    #[test]
+    fn test_matches_cycle_model() {
+        let image = assemble("
+            org $0200
+            .start:
+              ldx #$FF
+              txs
+              lda #$10
+              sta $20
+              lda #$03
+              sta $21
+              ldy #$F8
+            .loop:
+              lda ($20),y
+              sed
+              adc #$19
+              cld
+              sta $0300,x
+              ror $0300,x
+              pha
+              php
+              plp
+              pla
+              jsr .sub
+              bbs0 $30,.skip
+              smb0 $30
+            .skip:
+              jmp (.vector)
+            .next:
+              dex
+              iny
+              bne .loop
+              stp
+            .sub:
+              tsb $31
+              rts
+            .vector:
+              dw .next
+            org $FFFC
+              dw .start
+        ").unwrap();
+        compare(CpuVariant::W65C02S, &image, OPCODE_STP);
+    }
+
+    #[test]
+    fn test_matches_cycle_model_nmos() {
+        // The undocumented opcodes, which the assembler doesn't know.
+        let image = assemble("
+            org $0200
+            .start:
+              ldx #$FF
+              txs
+              ldy #$F8
+            .loop:
+              lda #$35
+              db $07 $20      ; slo $20
+              db $A7 $20      ; lax $20
+              db $DF $F0 $02  ; dcp $02F0,x
+              db $9F $10 $03  ; sha $0310,y
+              db $6B $C3      ; arr #$C3
+              sed
+              db $7B $00 $03  ; rra $0300,y
+              cld
+              db $CB $01      ; sbx #$01
+              db $1C $F0 $02  ; nop $02F0,x
+              inc $0300,x
+              iny
+              bne .loop
+              db $02          ; jam
+            org $FFFC
+              dw .start
+        ").unwrap();
+        compare(CpuVariant::Nmos6502, &image, OPCODE_JAM);
+    }
+
+    #[test]
+    fn test_store_high_wrapping() {
+        // Indexing past $FFFF wraps to page zero, from a base in page $FF.
+        let image = assemble("
+            org $0200
+            .start:
+              lda #$FF
+              ldx #$20
+              ldy #$20
+              db $9F $F0 $FF  ; sha $FFF0,y
+              db $93 $40      ; sha ($40),y
+              db $9E $F0 $FF  ; shx $FFF0,y
+              db $9C $F0 $FF  ; shy $FFF0,x
+              db $9B $F0 $FF  ; tas $FFF0,y
+              db $02          ; jam
+            org $0040
+              dw $FFF0
+            org $FFFC
+              dw .start
+        ").unwrap();
+        compare(CpuVariant::Nmos6502, &image, OPCODE_JAM);
+    }
+}
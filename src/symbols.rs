@@ -0,0 +1,183 @@
+// Symbols
+// Names for addresses, so that disassembly, the debugger and trace reports can
+// show .loop+2 rather than $030C, and breakpoints can be set by name.
+//
+// Symbols are loaded from:
+//   .asm  the repo's own assembly, whose labels look like .reset and .loop
+//   .dbg  ca65/ld65 debug info (ld65 --dbgfile), using its label symbols
+//   else  VICE label files, as written by ld65 -Ln, with lines like:
+//         al 00C000 .reset
+use std::collections::{BTreeMap, HashMap};
+use crate::assembler::assemble_with_labels;
+
+// How far past a symbol an address can be and still be shown relative to it.
+const MAX_OFFSET: u16 = 0xFF;
+
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    by_name: HashMap<String, u16>,
+    // The first name given to each address, which is the one shown.
+    by_addr: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        self.by_name.insert(name.to_string(), addr);
+        self.by_addr.entry(addr).or_insert(name.to_string());
+    }
+
+    // Add the symbols from a file, chosen by its extension.
+    pub fn load(&mut self, path: &str) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .or(Err(format!("Failed to read symbols: '{path}'")))?;
+        let result = if path.ends_with(".asm") {
+            self.add_asm(&text)
+        } else if path.ends_with(".dbg") {
+            self.add_dbg(&text)
+        } else {
+            self.add_labels(&text)
+        };
+        result.map_err(|e| format!("{path}: {e}"))
+    }
+
+    // Labels from the repo's assembly source.
+    pub fn add_asm(&mut self, source: &str) -> Result<(), String> {
+        let (_, labels) = assemble_with_labels(source)?;
+        // Sorted, so that the name shown for a shared address is consistent.
+        let mut labels : Vec<(String, u16)> = labels.into_iter().collect();
+        labels.sort();
+        for (name, addr) in labels {
+            self.insert(&name, addr);
+        }
+        Ok(())
+    }
+
+    // Label symbols from ca65/ld65 debug info, which are lines like:
+    // sym	id=3,name="loop",addrsize=absolute,scope=0,def=5,ref=9,val=0x8003,seg=0,type=lab
+    pub fn add_dbg(&mut self, text: &str) -> Result<(), String> {
+        for (num, line) in text.lines().enumerate() {
+            let Some(fields) = line.strip_prefix("sym\t") else {
+                continue;
+            };
+            let fields : HashMap<&str, &str> = fields.split(',')
+                .filter_map(|field| field.split_once('='))
+                .collect();
+            if fields.get("type") != Some(&"lab") {
+                continue;
+            }
+            let bad = || format!("line {}: Bad symbol: '{line}'", num + 1);
+            let name = fields.get("name")
+                .and_then(|name| name.strip_prefix('"')?.strip_suffix('"'))
+                .ok_or_else(bad)?;
+            let val = fields.get("val")
+                .and_then(|val| val.strip_prefix("0x"))
+                .and_then(|val| u32::from_str_radix(val, 16).ok())
+                .ok_or_else(bad)?;
+            self.insert(name, val as u16);
+        }
+        Ok(())
+    }
+
+    // VICE label files. Other commands VICE accepts in them are skipped.
+    pub fn add_labels(&mut self, text: &str) -> Result<(), String> {
+        for (num, line) in text.lines().enumerate() {
+            let words : Vec<&str> = line.split_whitespace().collect();
+            let ["al", addr, name] = words[..] else {
+                continue;
+            };
+            // The address may have a bank, as 00C000 or C:C000.
+            let addr = addr.rsplit(':').next().unwrap();
+            let addr = u32::from_str_radix(addr, 16)
+                .or(Err(format!("line {}: Bad label address: '{line}'", num + 1)))?;
+            self.insert(name, addr as u16);
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    // The nearest symbol at or before addr, as name or name+offset.
+    pub fn label(&self, addr: u16) -> Option<String> {
+        let (&at, name) = self.by_addr.range(..= addr).next_back()?;
+        match addr - at {
+            0 => Some(name.clone()),
+            offset if offset <= MAX_OFFSET => Some(format!("{name}+{offset}")),
+            _ => None,
+        }
+    }
+
+    // The address named by a symbol, a symbol with an offset such as .loop+2,
+    // or a hex address, optionally prefixed with $ or 0x.
+    pub fn resolve(&self, s: &str) -> Result<u16, String> {
+        let bad = || format!("Unknown symbol or bad address: '{s}'");
+        if let Some(addr) = self.lookup(s) {
+            return Ok(addr);
+        }
+        if let Some(at) = s.rfind(['+', '-']) {
+            if let Some(addr) = self.lookup(&s[.. at]) {
+                let offset = &s[at + 1 ..];
+                let offset = match offset.strip_prefix('$') {
+                    Some(hex) => u16::from_str_radix(hex, 16),
+                    None => offset.parse(),
+                }.or(Err(bad()))?;
+                return Ok(match &s[at .. at + 1] {
+                    "+" => addr.wrapping_add(offset),
+                    _ => addr.wrapping_sub(offset),
+                });
+            }
+        }
+        let digits = s.strip_prefix('$')
+            .or(s.strip_prefix("0x"))
+            .unwrap_or(s);
+        u16::from_str_radix(digits, 16).or(Err(bad()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let mut symbols = Symbols::new();
+        symbols.add_asm("org $300\n.reset: nop\n.loop: jmp .loop").unwrap();
+        symbols.add_labels("al 00C000 .start\nal C:C010 .irq\nsave \"x\" 0 c000 c010").unwrap();
+        symbols.add_dbg("\
+version	major=2,minor=0
+sym	id=0,name=\"main\",addrsize=absolute,scope=0,def=1,val=0x8000,seg=0,type=lab
+sym	id=1,name=\"SIZE\",addrsize=zeropage,scope=0,def=2,val=0x10,type=equ
+").unwrap();
+        let want = [(".reset", 0x300), (".loop", 0x301), (".start", 0xC000), (".irq", 0xC010), ("main", 0x8000)];
+        for (name, addr) in want {
+            assert_eq!(Some(addr), symbols.lookup(name), "{name}");
+        }
+        assert_eq!(None, symbols.lookup("SIZE"));
+        assert!(symbols.add_labels("al nothex .bad").is_err());
+    }
+
+    #[test]
+    fn test_label_resolve() {
+        let mut symbols = Symbols::new();
+        symbols.insert(".loop", 0x0300);
+        symbols.insert(".also_loop", 0x0300);
+        assert_eq!(Some(".loop".to_string()), symbols.label(0x0300));
+        assert_eq!(Some(".loop+12".to_string()), symbols.label(0x030C));
+        assert_eq!(None, symbols.label(0x02FF));
+        assert_eq!(None, symbols.label(0x0400));
+
+        assert_eq!(Ok(0x0300), symbols.resolve(".also_loop"));
+        assert_eq!(Ok(0x0302), symbols.resolve(".loop+2"));
+        assert_eq!(Ok(0x0310), symbols.resolve(".loop+$10"));
+        assert_eq!(Ok(0x02FF), symbols.resolve(".loop-1"));
+        assert_eq!(Ok(0x1234), symbols.resolve("$1234"));
+        assert_eq!(Ok(0xABCD), symbols.resolve("abcd"));
+        assert!(symbols.resolve(".nowhere").is_err());
+        assert!(symbols.resolve(".loop+x").is_err());
+    }
+}
@@ -0,0 +1,339 @@
+// Trace Log
+// Parser for the bus traces recorded by the chiplab.
+//
+// The signed section of a log starts with a few key=value lines, such as the
+// checksum of the program that was run. The trace follows, with one line per
+// cycle giving the bus signals:
+//   a=0x0002 rwb=1 sync=0
+//   a=0x0010 rwb=0 d=0xAD sync=0
+// Values are hex when prefixed with 0x, and decimal otherwise. d(ata) is only
+// present when the chip drove the data bus.
+//
+// Traces may also record the input pins the lab drove during each cycle. The
+// header lists them by their pin names, and each line then gives their levels:
+//   Stimulus=irqb,rdy
+//   a=0x0200 rwb=1 sync=1 irqb=0 rdy=1
+// Pins that aren't recorded are held inactive.
+//
+// The trace is read a line at a time from any BufRead, so long traces are never
+// held in memory in parsed form. Trailing whitespace and CRLF line endings are
+// accepted, and blank lines are skipped. Line numbers count from the first line
+// given to the reader.
+use std::collections::HashMap;
+use std::fmt;
+use std::io::BufRead;
+use crate::Inputs;
+
+pub type TraceKV = HashMap<String, String>;
+
+// Keys that may appear before the trace.
+const ALLOWED_KEYS : &[&str] = &[
+    "InputSha256",
+    // Comma separated input pins recorded on each line.
+    "Stimulus",
+    // The chip the trace is from, by CpuVariant name. W65C02S if absent.
+    "Variant",
+];
+
+// Input pins which may be recorded, named as on the W65C02S.
+const STIMULUS_PINS : &[&str] = &["resb", "irqb", "nmib", "rdy", "sob"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    // 1 based, pointing at the offending field.
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Log parse failure on line {}, column {}: {}",
+               self.line, self.column, self.message)
+    }
+}
+
+// Levels of the input pins driven during a cycle, in the same order as
+// STIMULUS_PINS. None if the trace doesn't record the pin.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stimulus {
+    pub pins: [Option<bool>; 5],
+}
+
+impl Stimulus {
+    // Set the model's inputs to match. Unrecorded pins are left alone.
+    pub fn apply(&self, inputs: &mut Inputs) {
+        let [resb, irqb, nmib, rdy, sob] = self.pins;
+        inputs.n_reset = resb.unwrap_or(inputs.n_reset);
+        inputs.n_irq = irqb.unwrap_or(inputs.n_irq);
+        inputs.n_nmi = nmib.unwrap_or(inputs.n_nmi);
+        inputs.rdy = rdy.unwrap_or(inputs.rdy);
+        inputs.n_so = sob.unwrap_or(inputs.n_so);
+    }
+}
+
+// The bus signals of the chip for one cycle.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceLine {
+    pub line: usize,
+    pub address: u16,
+    pub rwb: bool,
+    pub data: Option<u8>,
+    pub sync: bool,
+    pub stimulus: Stimulus,
+}
+
+// Formats the line as it appears in the log.
+impl fmt::Display for TraceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a=0x{:04X} rwb={}", self.address, self.rwb as u8)?;
+        if let Some(d) = self.data {
+            write!(f, " d=0x{d:02X}")?;
+        }
+        write!(f, " sync={}", self.sync as u8)?;
+        for (name, level) in STIMULUS_PINS.iter().zip(self.stimulus.pins) {
+            if let Some(level) = level {
+                write!(f, " {name}={}", level as u8)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Parse the Stimulus header value into the set of recorded pins.
+fn parse_stimulus(value: &str) -> Result<[bool; 5], String> {
+    let mut recorded = [false; 5];
+    for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let Some(pin) = STIMULUS_PINS.iter().position(|p| *p == name) else {
+            return Err(format!("Unknown stimulus pin: '{name}'"));
+        };
+        recorded[pin] = true;
+    }
+    Ok(recorded)
+}
+
+fn parse_value(value: &str) -> Option<u16> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+// Parse one line of the trace, which should have exactly the recorded
+// stimulus pins.
+fn parse_line(text: &str, line: usize, recorded: &[bool; 5]) -> Result<TraceLine, ParseError> {
+    let error = |column, message| ParseError { line, column, message };
+    let mut fields : HashMap<&str, u16> = HashMap::new();
+    let mut offset = 0;
+    for field in text.split(' ') {
+        let column = offset + 1;
+        offset += field.len() + 1;
+        if field.is_empty() {
+            continue;
+        }
+        let Some((key, value)) = field.split_once('=') else {
+            return Err(error(column, format!("Expected key=value, found '{field}'")));
+        };
+        let value_column = column + key.len() + 1;
+        let Some(parsed) = parse_value(value) else {
+            return Err(error(value_column, format!("Bad value for {key}: '{value}'")));
+        };
+        let pin = STIMULUS_PINS.iter().position(|p| *p == key);
+        let max = match key {
+            "a" => 0xFFFF,
+            "d" => 0xFF,
+            "rwb" | "sync" => 1,
+            _ if pin.is_some_and(|pin| recorded[pin]) => 1,
+            _ if pin.is_some() => {
+                return Err(error(column, format!("Pin '{key}' isn't listed in Stimulus")));
+            },
+            _ => return Err(error(column, format!("Unknown field '{key}'"))),
+        };
+        if parsed > max {
+            return Err(error(value_column, format!("Value out of range for {key}: '{value}'")));
+        }
+        if fields.insert(key, parsed).is_some() {
+            return Err(error(column, format!("Duplicate field '{key}'")));
+        }
+    }
+
+    let required = |key: &str| match fields.get(key) {
+        Some(value) => Ok(*value),
+        None => Err(error(text.len() + 1, format!("Missing field '{key}'"))),
+    };
+    let mut stimulus = Stimulus::default();
+    for (pin, name) in STIMULUS_PINS.iter().enumerate() {
+        if recorded[pin] {
+            stimulus.pins[pin] = Some(required(name)? == 1);
+        }
+    }
+    Ok(TraceLine {
+        line,
+        address: required("a")?,
+        rwb: required("rwb")? == 1,
+        data: fields.get("d").map(|d| *d as u8),
+        sync: required("sync")? == 1,
+        stimulus,
+    })
+}
+
+// Reads trace lines, one per call to next(). Iteration ends at the end of the
+// input, or after the first error.
+pub struct TraceReader<R: BufRead> {
+    input: R,
+    buf: String,
+    line: usize,
+    // The first line of the trace, read while looking for the end of the header.
+    pending: Option<TraceLine>,
+    failed: bool,
+    // Stimulus pins recorded on each line.
+    recorded: [bool; 5],
+}
+
+impl<R: BufRead> TraceReader<R> {
+    // Read the key=value lines at the start of the input, leaving the reader
+    // at the first cycle of the trace.
+    pub fn new(input: R) -> Result<(TraceKV, TraceReader<R>), ParseError> {
+        let mut reader = TraceReader {
+            input,
+            buf: String::new(),
+            line: 0,
+            pending: None,
+            failed: false,
+            recorded: [false; 5],
+        };
+        let mut kv = HashMap::new();
+        while reader.read_line()? {
+            let text = reader.buf.trim_end();
+            if text.is_empty() {
+                continue;
+            }
+            if text.starts_with("a=") {
+                reader.pending = Some(parse_line(text, reader.line, &reader.recorded)?);
+                break;
+            }
+            let Some((key, value)) = text.split_once('=') else {
+                return Err(reader.error(1, format!("Expected key=value, found '{text}'")));
+            };
+            if !ALLOWED_KEYS.contains(&key) {
+                return Err(reader.error(1, format!("Unknown key in kv: '{key}'")));
+            }
+            if key == "Stimulus" {
+                reader.recorded = parse_stimulus(value)
+                    .map_err(|e| reader.error(key.len() + 2, e))?;
+            }
+            kv.insert(key.to_string(), value.to_string());
+        }
+        Ok((kv, reader))
+    }
+
+    fn error(&self, column: usize, message: String) -> ParseError {
+        ParseError { line: self.line, column, message }
+    }
+
+    // Read the next line into buf, returning false at the end of the input.
+    fn read_line(&mut self) -> Result<bool, ParseError> {
+        self.buf.clear();
+        self.line += 1;
+        match self.input.read_line(&mut self.buf) {
+            Ok(0) => Ok(false),
+            Ok(_) => Ok(true),
+            Err(e) => Err(self.error(1, format!("Read failed: {e}"))),
+        }
+    }
+
+    fn next_line(&mut self) -> Result<Option<TraceLine>, ParseError> {
+        if let Some(line) = self.pending.take() {
+            return Ok(Some(line));
+        }
+        while self.read_line()? {
+            let text = self.buf.trim_end();
+            if !text.is_empty() {
+                return parse_line(text, self.line, &self.recorded).map(Some);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = Result<TraceLine, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.next_line();
+        self.failed = result.is_err();
+        result.transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header_and_lines() {
+        let log = "InputSha256=XA2eNCnK6MOju3JTVGgsMRSv/huAlp7IEqmPevSX874=\r\n\
+                   a=0x0002 rwb=1 sync=0 \r\n\
+                   \r\n\
+                   a=0x0010 rwb=0 d=0xAD sync=1\n";
+        let (kv, reader) = TraceReader::new(log.as_bytes()).unwrap();
+        assert_eq!("XA2eNCnK6MOju3JTVGgsMRSv/huAlp7IEqmPevSX874=", kv["InputSha256"]);
+        let lines : Vec<TraceLine> = reader.map(|l| l.unwrap()).collect();
+        assert_eq!(vec![
+            TraceLine { line: 2, address: 0x0002, rwb: true, data: None, sync: false,
+                        stimulus: Stimulus::default() },
+            TraceLine { line: 4, address: 0x0010, rwb: false, data: Some(0xAD), sync: true,
+                        stimulus: Stimulus::default() },
+        ], lines);
+    }
+
+    #[test]
+    fn test_errors() {
+        fn first_error(log: &str) -> ParseError {
+            match TraceReader::new(log.as_bytes()) {
+                Err(e) => e,
+                Ok((_, mut reader)) => reader.find_map(|l| l.err()).unwrap(),
+            }
+        }
+        assert_eq!(ParseError { line: 1, column: 1, message: "Unknown key in kv: 'Foo'".into() },
+                   first_error("Foo=1\n"));
+        assert_eq!(ParseError { line: 1, column: 1, message: "Expected key=value, found 'x'".into() },
+                   first_error("x"));
+        assert_eq!(ParseError { line: 2, column: 19, message: "Missing field 'sync'".into() },
+                   first_error("a=0x0002 rwb=1 sync=0\na=0x0003 rwb=1 d=7\n"));
+        assert_eq!(ParseError { line: 1, column: 14, message: "Bad value for rwb: 'x'".into() },
+                   first_error("a=0x0002 rwb=x sync=0"));
+        assert_eq!(ParseError { line: 1, column: 18, message: "Value out of range for d: '0x100'".into() },
+                   first_error("a=0x0002 rwb=0 d=0x100 sync=0"));
+    }
+
+    #[test]
+    fn test_stops_after_error() {
+        let (_, reader) = TraceReader::new("a=1 rwb=1 sync=0\nbad\na=2 rwb=1 sync=0".as_bytes()).unwrap();
+        let results : Vec<_> = reader.collect();
+        assert_eq!(2, results.len());
+        assert!(results[1].is_err());
+    }
+
+    #[test]
+    fn test_stimulus() {
+        let log = "Stimulus=irqb,rdy\n\
+                   a=0x0200 rwb=1 sync=1 irqb=0 rdy=1\n";
+        let (_, mut reader) = TraceReader::new(log.as_bytes()).unwrap();
+        let line = reader.next().unwrap().unwrap();
+        assert_eq!([None, Some(false), None, Some(true), None], line.stimulus.pins);
+        assert_eq!("a=0x0200 rwb=1 sync=1 irqb=0 rdy=1", line.to_string());
+        let mut inputs = Inputs::default();
+        line.stimulus.apply(&mut inputs);
+        assert!(!inputs.n_irq && inputs.rdy && inputs.n_nmi);
+
+        // Every line must have exactly the listed pins.
+        let log = "Stimulus=irqb\na=0x0200 rwb=1 sync=1 irqb=0 nmib=1\n";
+        assert_eq!("Pin 'nmib' isn't listed in Stimulus", TraceReader::new(log.as_bytes()).err().unwrap().message);
+        let log = "Stimulus=irqb\na=0x0200 rwb=1 sync=1\n";
+        assert_eq!("Missing field 'irqb'", TraceReader::new(log.as_bytes()).err().unwrap().message);
+    }
+}
@@ -0,0 +1,72 @@
+// Trace Report
+// Describes where the model first diverged from a chiplab trace.
+//
+// The cycles around the mismatch are shown with the chip's bus signals and the
+// model's side by side, along with what the model thought it was doing at the
+// time: the instruction in flight, and the uop it was executing. The full model
+// state is also shown for the mismatched cycle.
+use std::fmt;
+use crate::{W6502, Outputs};
+
+// One cycle of the trace, with a copy of the model after running it.
+pub struct CycleRecord {
+    pub line: usize,
+    pub chip: String,
+    // None if the model failed during this cycle.
+    pub model: Option<W6502>,
+    // The instruction the model was executing, disassembled.
+    pub instruction: String,
+}
+
+pub struct DivergenceReport {
+    pub error: String,
+    // The signal which differed, or None if the model failed.
+    pub signal: Option<&'static str>,
+    pub failed_line: usize,
+    // Cycles which matched, after the lines skipped during reset.
+    pub matched_cycles: usize,
+    // The instruction being executed, disassembled.
+    pub instruction: String,
+    pub cycles: Vec<CycleRecord>,
+}
+
+// Format the model's outputs the same way as a line of the log.
+pub fn format_outputs(outputs: &Outputs) -> String {
+    let data = match outputs.data {
+        Some(d) => format!(" d=0x{d:02X}"),
+        None => String::new(),
+    };
+    format!("a=0x{:04X} rwb={}{data} sync={}",
+            outputs.address, outputs.rwb as u8, outputs.sync as u8)
+}
+
+impl fmt::Display for DivergenceReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.error)?;
+        write!(f, "  {:>7}  {:<32}{:<32}{:<30}uop", "line", "chip", "model", "instruction")?;
+        for cycle in &self.cycles {
+            let marker = if cycle.line == self.failed_line { ">" } else { " " };
+            write!(f, "\n  {marker} {:5}  {:<32}", cycle.line, cycle.chip.trim())?;
+            match &cycle.model {
+                Some(cpu) => write!(f, "{:<32}{:<30}{}",
+                                    format_outputs(cpu.outputs()),
+                                    cycle.instruction,
+                                    cpu.uop_state().0)?,
+                None => write!(f, "(model failed)")?,
+            }
+        }
+
+        let failed = self.cycles.iter()
+            .find(|c| c.line == self.failed_line)
+            .and_then(|c| c.model.as_ref());
+        if let Some(cpu) = failed {
+            let (uop, queue) = cpu.uop_state();
+            write!(f, "\n\nmodel state after line {}:", self.failed_line)?;
+            write!(f, "\n  registers:   {}", cpu.registers())?;
+            write!(f, "\n  instruction: {}", self.instruction)?;
+            write!(f, "\n  uop:         {uop}")?;
+            write!(f, "\n  queue:       {queue}")?;
+        }
+        Ok(())
+    }
+}
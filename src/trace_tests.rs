@@ -5,40 +5,40 @@
 // the same signals on each of its output pins as the real chip.
 //
 // The 6502 chiplab can be found at: https://chiplab.emulationonline.com/6502/
-use std::collections::HashMap;
-use crate::{W6502, Inputs};
-
-type TraceKV = HashMap<String, String>;
-
-// The trace may have several keys within the signed data, but before
-// the trace.
-// After the kv data comes the actual trace, which should start with "a="
-// Returns the parsed kv data, and the trace that follows.
-fn get_trace_kv(mut trace_content: &str) -> Result<(TraceKV, &str/*rest*/), String> {
-    const ALLOWED_KEYS : &[&'static str] = &[
-        "InputSha256",
-    ];
-    let mut result = HashMap::new();
-    loop {
-        let linesplit : Vec<&str> = trace_content.splitn(2, "\n").collect();
-        if linesplit.len() != 2 {
-            return Err(format!("Bad log({} parts) at '{}'", linesplit.len(), &trace_content[ .. 10]));
-        }
-        let line = linesplit[0];
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use crate::{CpuVariant, W6502, Inputs, Outputs};
+use crate::assembler::assemble;
+use crate::disasm::describe_variant;
+use crate::memory_map::MemoryMap;
+use crate::symbols::Symbols;
+use crate::trace_log::{TraceLine, TraceReader};
+use crate::trace_report::{format_outputs, CycleRecord, DivergenceReport};
+
+// Read a program's 64K image. If a .bin is missing, it is assembled from the
+// .asm next to it, so traces can be checked in with just their source.
+fn read_input(input_path: &str) -> Result<Vec<u8>, String> {
+    if let Ok(data) = std::fs::read(input_path) {
+        return Ok(data);
+    }
+    let asm_path = input_path.strip_suffix(".bin").map(|stem| format!("{stem}.asm"));
+    match asm_path.and_then(|path| std::fs::read_to_string(path).ok()) {
+        Some(source) => assemble(&source),
+        None => Err(format!("Failed to read input file: '{input_path}'")),
+    }
+}
 
-        let parts :Vec<&str> = line.splitn(2, "=").collect();
-        let (field, value) = (parts[0], parts[1]);
-        if field == "a" {
-            break;
-        }
-        trace_content = linesplit[1];
-        if ALLOWED_KEYS.contains(&field) {
-            result.insert(field.to_string(), value.to_string());
-        } else {
-            return Err(format!("Unknown key in kv: '{field}'"));
-        }
+// Labels for a program, from the .asm next to its .bin, so that reports can
+// show them. There are none if there is no source, or it doesn't assemble.
+fn read_symbols(input_path: &str) -> Symbols {
+    let mut symbols = Symbols::new();
+    let asm_path = input_path.strip_suffix(".bin").map(|stem| format!("{stem}.asm"));
+    if let Some(source) = asm_path.and_then(|path| std::fs::read_to_string(path).ok()) {
+        // Symbols are only a help, so a bad source just means none.
+        let _ = symbols.add_asm(&source);
     }
-    Ok((result, trace_content))
+    symbols
 }
 
 fn validate_input(data: &[u8], expected_checksum_b64: &str) -> Result<(), String> {
@@ -53,25 +53,6 @@ fn validate_input(data: &[u8], expected_checksum_b64: &str) -> Result<(), String
 }
 
 
-// given a line with key=value entries, return a hashmap representing the line.
-// Values may be either hex, prefixed with 0x, or are otherwise decimal.
-fn parse_fields(input: &str) -> HashMap<String, u16> {
-    let parts = input.trim().split(" ");
-    let mut result = HashMap::new();
-    for part in parts {
-        let kv : Vec<&str> = part.split("=").collect();
-        assert_eq!(2, kv.len(), "Log parse failure: '{input}' ");
-        let key = kv[0];
-        let (val, radix) = match kv[1].strip_prefix("0x") {
-            Some(v) => (v, 16),
-            None => (kv[1], 10),
-        };
-        let val = u16::from_str_radix(val, radix).unwrap();
-        result.insert(key.to_string(), val); 
-    }
-    result
-}
-
 // Return an error if the two values do not match.
 fn check_field(name: &str, want: u16, have: u16, line: usize) 
     -> Result<(), String> {
@@ -105,12 +86,12 @@ fn check_optional_field(name: &str, want: Option<u16>, have: Option<u16>, line:
 
 
 #[derive(PartialEq, Debug, Clone)]
-enum TraceFailure {
+pub enum TraceFailure {
     BadSetup(String),
     Incorrect(String),
 }
 impl TraceFailure {
-    fn is_badsetup(&self) -> bool {
+    pub fn is_badsetup(&self) -> bool {
         match self {
             TraceFailure::BadSetup(_) => true,
             _ => false,
@@ -131,7 +112,7 @@ impl From<&str> for TraceFailure {
 }
 
 
-type TestResult = Result<(), TraceFailure>;
+pub type TestResult = Result<(), TraceFailure>;
 // Run the model in a given environment, and ensure the model's trace
 // matches the trace from the real chip.
 // All the following must be met:
@@ -139,26 +120,93 @@ type TestResult = Result<(), TraceFailure>;
 // 2. The input must match the one in the trace
 // 3. The model bus signals match the trace after each cycle (starting
 // from the first reset read)
+#[cfg(test)]
 fn run_trace_test(
     checker: &pki_util::trace::TraceChecker,
     log_path: &str, input_path: &str) -> TestResult {
     println!("running trace: {log_path}");
-    let log_data = std::fs::read_to_string(log_path)
-        .or(Err("Failed to read log file."))?;
-    let log_data = checker.verify_trace(&log_data)
-        .or(Err("Verification failure"))?;
-    let (kv, log_data) = get_trace_kv(log_data)?;
+    verify_trace(checker, log_path, input_path, CONTEXT_CYCLES)
+}
 
-    let input_data : Vec<u8> = std::fs::read(input_path)
-        .or(Err(format!("Failed to read input file: '{input_path}'")))?;
+// As run_trace_test, with failure reports showing `context` cycles
+// either side of the first mismatch.
+pub fn verify_trace(
+    checker: &pki_util::trace::TraceChecker,
+    log_path: &str, input_path: &str, context: usize) -> TestResult {
+    match check_trace(checker, log_path, input_path, context)? {
+        None => Ok(()),
+        Some(report) => Err(TraceFailure::Incorrect(report.to_string())),
+    }
+}
+
+// Check the setup of a trace, then run it, returning where the model
+// diverged, if it did.
+// Errors are always BadSetup.
+pub fn check_trace(
+    checker: &pki_util::trace::TraceChecker,
+    log_path: &str, input_path: &str, context: usize)
+    -> Result<Option<DivergenceReport>, TraceFailure> {
+    let (start, len) = verify_signature(checker, log_path)?;
+    // The signed section is read back from the file as the model runs.
+    let mut log = File::open(log_path).or(Err("Failed to read log file."))?;
+    log.seek(SeekFrom::Start(start)).or(Err("Failed to read log file."))?;
+    let (kv, trace) = TraceReader::new(BufReader::new(log.take(len)))
+        .map_err(|e| e.to_string())?;
+
+    let input_data = read_input(input_path)?;
     let want_checksum = kv.get("InputSha256")
         .ok_or("Input checksum missing from log.")?;
     validate_input(&input_data, want_checksum)?;
+    // Traces are from the W65C02S unless they say otherwise.
+    let variant = match kv.get("Variant") {
+        None => CpuVariant::W65C02S,
+        Some(name) => CpuVariant::from_name(name)
+            .ok_or(format!("Unknown variant in log: '{name}'"))?,
+    };
 
-    match assert_model_log(&log_data, &input_data) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(TraceFailure::Incorrect(e)),
-    }
+    let symbols = read_symbols(input_path);
+    Ok(run_model_log(trace, &input_data, variant, &symbols, context)?)
+}
+
+// Check the signature of a log, returning the offset and length of the signed
+// section within it. TraceChecker only verifies a whole log, so the log is held
+// in memory for this, but dropped before the trace is run.
+fn verify_signature(checker: &pki_util::trace::TraceChecker, log_path: &str)
+    -> Result<(u64, u64), TraceFailure> {
+    let log_data = std::fs::read_to_string(log_path)
+        .or(Err("Failed to read log file."))?;
+    let signed = checker.verify_trace(&log_data)
+        .or(Err("Verification failure"))?;
+    let start = log_data.find(signed)
+        .ok_or("Signed data not found in log.")?;
+    Ok((start as u64, signed.len() as u64))
+}
+
+// How far the model gets through a trace.
+pub enum Score {
+    Passing,
+    Diverged(DivergenceReport),
+    BadSetup(String),
+}
+
+// Score each trace in a directory, in name order. This is used to track
+// progress on failing traces.
+pub fn score_traces(checker: &pki_util::trace::TraceChecker, directory: &str)
+    -> Result<Vec<(String, Score)>, String> {
+    let mut logs : Vec<String> = std::fs::read_dir(directory)
+        .or(Err(format!("Failed to read directory: '{directory}'")))?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.path().to_str()?.strip_suffix(".log").map(String::from))
+        .collect();
+    logs.sort();
+    Ok(logs.into_iter().map(|path| {
+        let score = match check_trace(checker, &format!("{path}.log"), &format!("{path}.bin"), 0) {
+            Ok(None) => Score::Passing,
+            Ok(Some(report)) => Score::Diverged(report),
+            Err(TraceFailure::BadSetup(e) | TraceFailure::Incorrect(e)) => Score::BadSetup(e),
+        };
+        (path, score)
+    }).collect())
 }
 
 #[cfg(test)]
@@ -166,67 +214,229 @@ mod test_utils {
     use super::*;
     // Tests for the test framework.
     #[test]
-    fn test_kv() {
-        let (kv, rest) = get_trace_kv(r#"InputSha256=XA2eNCnK6MOju3JTVGgsMRSv/huAlp7IEqmPevSX874=
-a=0x0002 rwb=1 
-a=0xFFFF rwb=1"#).unwrap();
-        assert_eq!("XA2eNCnK6MOju3JTVGgsMRSv/huAlp7IEqmPevSX874=", kv["InputSha256"]);
-        assert_eq!(true, rest.starts_with("a=0x0002 rwb=1"), "Actual='{rest}'");
+    fn test_record_trace() {
+        // The model's recording of a passing trace matches the signed data.
+        let input = std::fs::read("passing_traces/load_store_regs_basic.bin").unwrap();
+        let mut recorded = Vec::new();
+        record_trace(&input, CpuVariant::W65C02S, TRACE_CYCLES, &mut recorded).unwrap();
+        let recorded = String::from_utf8(recorded).unwrap();
+        let log = std::fs::read_to_string("passing_traces/load_store_regs_basic.log").unwrap();
+        // Compare up to the signature, other than the lines skipped in trace tests.
+        let signed = |log: &str| {
+            let (signed, _) = log.split_once("===END SIGNED DATA===").unwrap();
+            let mut lines : Vec<String> = signed.lines().map(String::from).collect();
+            lines.drain(2 .. 2 + SKIPPED_LINES);
+            lines
+        };
+        assert_eq!(signed(&log), signed(&recorded));
     }
-}
-
-// Assert that the model matches the log, for all cycles including
-// the first reset vector reads.
-fn assert_model_log(log: &str, environment: &[u8])
-    -> Result<(), String> {
-    let mut cpu = W6502::new();
-    let mut log = log.lines();
-    let skipped_lines = reset_model(&mut cpu, &mut log);
-
-    for (num, line) in log.enumerate() {
-        println!("log: {line}");
-        let num = num + skipped_lines + 1;  // start counting from 1
-        let fields = parse_fields(&line);
-        cpu.cycle(&Inputs {
-            data: environment[cpu.outputs().address as usize],
-            clk: false, /*unused*/
-            n_reset: true,
-        })?;
-
-        // Every line should have a and rwb
-        check_field("addr", fields["a"], cpu.outputs().address, num)?;
-        check_field("rwb", fields["rwb"], cpu.outputs().rwb as u16, num)?;
-        check_field("sync", fields["sync"], cpu.outputs().sync as u16, num)?;
 
+    #[test]
+    fn test_record_variant() {
+        // A recording from another variant names it, and checks against it.
+        let input = std::fs::read("passing_traces/load_store_regs_basic.bin").unwrap();
+        let mut recorded = Vec::new();
+        record_trace(&input, CpuVariant::Nmos6502, TRACE_CYCLES, &mut recorded).unwrap();
+        let recorded = String::from_utf8(recorded).unwrap();
+        let (_, log) = recorded.split_once('\n').unwrap();
+        let (log, _) = log.split_once("===END").unwrap();
+        let (kv, trace) = TraceReader::new(log.as_bytes()).unwrap();
+        assert_eq!(Some("6502"), kv.get("Variant").map(String::as_str));
+        assert!(run_model_log(trace, &input, CpuVariant::Nmos6502, &Symbols::new(), 0).unwrap().is_none());
+    }
 
-        // d(ata) is optional
-        check_optional_field("data", fields.get("d").copied(), 
-                             cpu.outputs().data.map(|v| v as u16), num)?;
+    #[test]
+    fn test_mismatch_context() {
+        let log = std::fs::read_to_string("passing_traces/nop_jmp_loop.log").unwrap();
+        let (_, log) = log.split_once('\n').unwrap();
+        let (log, _) = log.split_once("===END").unwrap();
+        let log = log.replacen("a=0x0003 rwb=1 sync=0", "a=0x0004 rwb=1 sync=0", 2);
+        let (_, trace) = TraceReader::new(log.as_bytes()).unwrap();
+        let input = std::fs::read("passing_traces/nop_jmp_loop.bin").unwrap();
+        let symbols = read_symbols("passing_traces/nop_jmp_loop.bin");
+        let report = run_model_log(trace, &input, CpuVariant::W65C02S, &symbols, 2).unwrap().unwrap();
+        assert_eq!((Some("addr"), 6, "$0001 .loop: JMP .loop"),
+                   (report.signal, report.matched_cycles, report.instruction.as_str()));
+        let report = report.to_string();
+        let lines : Vec<&str> = report.lines().collect();
+        assert_eq!("addr mismatch on line 14. Have=0003 Want=0004", lines[0]);
+        // The mismatch is shown with 2 cycles either side, then the model state.
+        assert!(lines[4].starts_with("  >    14  a=0x0004 rwb=1 sync=0"), "{report}");
+        assert!(lines[4].contains("a=0x0003 rwb=1 sync=0"), "{report}");
+        assert!(lines[4].contains("$0001 .loop: JMP .loop"), "{report}");
+        assert_eq!("model state after line 14:", lines[8], "{report}");
+        assert!(lines[12].starts_with("  queue:"), "{report}");
+    }
+}
 
+// Check the model's outputs against one line of the log.
+// Errors give the name of the mismatched signal, and a description.
+fn check_line(want: &TraceLine, outputs: &Outputs) -> Result<(), (&'static str, String)> {
+    let num = want.line;
+    check_field("addr", want.address, outputs.address, num)
+        .map_err(|e| ("addr", e))?;
+    check_field("rwb", want.rwb as u16, outputs.rwb as u16, num)
+        .map_err(|e| ("rwb", e))?;
+    check_field("sync", want.sync as u16, outputs.sync as u16, num)
+        .map_err(|e| ("sync", e))?;
+
+    // d(ata) is optional
+    check_optional_field("data", want.data.map(|v| v as u16),
+                         outputs.data.map(|v| v as u16), num)
+        .map_err(|e| ("data", e))
+}
 
+// Number of cycles shown before and after a mismatch, by default.
+pub const CONTEXT_CYCLES : usize = 5;
+
+// Run the model against the log, for all cycles including the first reset
+// vector reads. Returns a report of the first mismatch if there is one,
+// showing `context` cycles either side of it, with instructions labelled
+// from symbols.
+// Errors are for logs that can't be used.
+fn run_model_log(mut trace: TraceReader<impl BufRead>, environment: &[u8],
+                 variant: CpuVariant, symbols: &Symbols, context: usize)
+    -> Result<Option<DivergenceReport>, String> {
+    let mut cpu = W6502::new(variant);
+    reset_model(&mut cpu, &mut trace)?;
+
+    let mut history : VecDeque<CycleRecord> = VecDeque::new();
+    let mut failure : Option<DivergenceReport> = None;
+    let mut matched_cycles = 0;
+
+    for line in trace {
+        let line = line.map_err(|e| e.to_string())?;
+        let num = line.line;
+        // Replay the pins the lab drove, along with memory.
+        let mut inputs = Inputs {
+            data: environment[cpu.outputs().address as usize],
+            ..Inputs::default()
+        };
+        line.stimulus.apply(&mut inputs);
+        let result = cpu.cycle(&inputs);
+        let instruction = describe_variant(variant, environment, cpu.instruction().0, symbols);
+        let mut record = CycleRecord {
+            line: num,
+            chip: line.to_string(),
+            model: None,
+            instruction: instruction.clone(),
+        };
+        if let Err(error) = result {
+            // The model can't continue, so there is no more context to show.
+            history.push_back(record);
+            failure.get_or_insert(DivergenceReport {
+                error,
+                signal: None,
+                failed_line: num,
+                matched_cycles,
+                instruction,
+                cycles: Vec::new(),
+            });
+            break;
+        }
+        record.model = Some(cpu.clone());
+        history.push_back(record);
+
+        match &failure {
+            None => {
+                if history.len() > context + 1 {
+                    history.pop_front();
+                }
+                match check_line(&line, cpu.outputs()) {
+                    Ok(()) => matched_cycles += 1,
+                    Err((signal, error)) => failure = Some(DivergenceReport {
+                        error,
+                        signal: Some(signal),
+                        failed_line: num,
+                        matched_cycles,
+                        instruction: instruction.clone(),
+                        cycles: Vec::new(),
+                    }),
+                }
+            },
+            Some(report) => {
+                if num - report.failed_line >= context {
+                    break;
+                }
+            },
+        }
     }
-    Ok(())
+
+    Ok(failure.map(|report| DivergenceReport {
+        cycles: history.into(),
+        ..report
+    }))
 }
 
-// Reset the cpu, and step until the chip should
-// be reading the reset vector.
-// Returns the number of skipped log lines.
-fn reset_model(cpu: &mut W6502, lines: &mut std::str::Lines) -> usize {
+// Lines at the start of the trace, before the reset vector is read.
+// What the chip does during these depends on its state before reset, so
+// they aren't compared.
+const SKIPPED_LINES : usize = 6;
+
+// Hold the cpu in reset, returning the inputs to use for the skipped lines.
+fn hold_reset(cpu: &mut W6502) -> Inputs {
     let mut inputs = Inputs {
-        clk: false,
         data: 0xca,
         n_reset: false,
+        ..Inputs::default()
     };
-    for i in 0 .. 2 {
-        cpu.cycle(&inputs);
+    // Nothing is decoded before the reset vector is read, so these can't fail.
+    for _ in 0 .. 2 {
+        cpu.cycle(&inputs).unwrap();
     }
     inputs.n_reset = true;
-    const SKIPPED_LINES : usize = 6;
-    for i in 0 .. SKIPPED_LINES {
-        cpu.cycle(&inputs);
-        lines.next();
+    inputs
+}
+
+// Reset the cpu, and step until the chip should
+// be reading the reset vector.
+fn reset_model(cpu: &mut W6502, trace: &mut TraceReader<impl BufRead>)
+    -> Result<(), String> {
+    let inputs = hold_reset(cpu);
+    // The lines are not compared, but must still be present and well formed.
+    for _ in 0 .. SKIPPED_LINES {
+        cpu.cycle(&inputs).unwrap();
+        trace.next()
+            .ok_or("Log ended during reset.")?
+            .map_err(|e| e.to_string())?;
     }
-    SKIPPED_LINES
+    Ok(())
+}
+
+// Number of cycles recorded in a chiplab trace.
+pub const TRACE_CYCLES : usize = 100;
+
+// The inverse of a trace test: run the model on a 64K input and write what it
+// does, in the same format as the chiplab, including the input checksum.
+// The log has the signed data markers so that it can be diffed against a log
+// from the lab, but has no signature, so won't pass verification itself.
+//
+// If the model fails, the cycles before the failure are written, then the
+// error is returned.
+//
+// The variant is recorded in the log, unless it is the W65C02S.
+pub fn record_trace(environment: &[u8], variant: CpuVariant, cycles: usize, out: &mut impl Write)
+    -> Result<(), String> {
+    let mut memory = MemoryMap::from_image(environment)?;
+    let write_error = |e: std::io::Error| format!("Failed to write trace: {e}");
+    writeln!(out, "===BEGIN SIGNED DATA===").map_err(write_error)?;
+    writeln!(out, "InputSha256={}", pki_util::sha256_b64(environment)).map_err(write_error)?;
+    if variant != CpuVariant::W65C02S {
+        writeln!(out, "Variant={variant}").map_err(write_error)?;
+    }
+
+    let mut cpu = W6502::new(variant);
+    let inputs = hold_reset(&mut cpu);
+    for cycle in 0 .. cycles {
+        if cycle < SKIPPED_LINES {
+            cpu.cycle(&inputs).unwrap();
+        } else {
+            cpu.cycle_bus(&mut memory)?;
+        }
+        writeln!(out, "{}", format_outputs(cpu.outputs())).map_err(write_error)?;
+    }
+    writeln!(out, "===END SIGNED DATA===").map_err(write_error)?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -239,29 +449,33 @@ mod trace_tests {
             &std::fs::read("chiplab_trace_signing.bin.pub").unwrap())
     }
 
-    // Assert some function of the result for all tests in a directory.
-    fn assert_all_traces(directory: &str, expected_count: usize, 
-                         expected_result: fn(TestResult)->bool) {
-        let checker = checker();
-        let mut count = 0;
-        for entry in std::fs::read_dir(directory).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            let path = (*path).to_str().unwrap();
-            if !path.ends_with(".log") {
-                continue;
-            }
-            count += 1;
-
-            let input_path = format!("{}.bin", path.strip_suffix(".log").unwrap());
-            let result = run_trace_test(&checker, path, &input_path);
-            assert!(expected_result(result.clone()),
-                "Failure for test: '{path}' : {result:?}");
+    // Run the trace at path, without the .log or .bin extension.
+    fn run_trace(path: &str) -> TestResult {
+        run_trace_test(&checker(), &format!("{path}.log"), &format!("{path}.bin"))
+    }
+
+    fn assert_passing(path: &str) {
+        match run_trace(path) {
+            Ok(()) => (),
+            Err(TraceFailure::Incorrect(report)) => panic!("Failure for test: '{path}' : {report}"),
+            Err(e) => panic!("Failure for test: '{path}' : {e:?}"),
         }
+    }
 
-        assert_eq!(expected_count, count);
+    // Failing traces should be valid besides having an incorrect
+    // result.
+    // Unused while failing_traces/ is empty.
+    #[allow(dead_code)]
+    fn assert_failing(path: &str) {
+        match run_trace(path) {
+            Err(TraceFailure::Incorrect(_)) => (),
+            Ok(()) => panic!("'{path}' passes, and can be moved to passing_traces/"),
+            result => panic!("Expected an incorrect result for test: '{path}' : {result:?}"),
+        }
     }
 
+    // One test per trace, generated by build.rs.
+    include!(concat!(env!("OUT_DIR"), "/trace_tests.rs"));
 
     #[test]
     fn test_nop_jmp() {
@@ -271,18 +485,4 @@ mod trace_tests {
             Ok(()),
             run_trace_test(&checker, "passing_traces/nop_jmp_loop.log", "passing_traces/nop_jmp_loop.bin"));
     }
-
-    #[test]
-    fn test_passing_traces() {
-        assert_all_traces("passing_traces/", 2, |result| result.is_ok());
-    }
-
-    #[test]
-    fn test_failing_traces() {
-        // Failing traces should be valid besides having an incorrect
-        // result.
-        assert_all_traces("failing_traces/", 0, |result| {
-            result.is_err() && !result.err().unwrap().is_badsetup()
-        });
-    }
 }
@@ -0,0 +1,711 @@
+// W65C22 Versatile Interface Adapter (VIA)
+//
+// Two 8 bit ports with data direction registers, two 16 bit timers, a
+// shift register, and the CA1/CA2/CB1/CB2 handshake lines, all of which can
+// raise an interrupt on the shared irq line.
+//
+// The VIA is clocked by the same phi2 as the cpu, so it is ticked once per
+// cpu cycle. Timing follows the W65C22 datasheet:
+// - T1 and T2 count down once per cycle, and interrupt when they pass zero,
+//   N + 1.5 cycles after being loaded with N.
+// - In free running mode T1 reloads from its latches, for a period of N + 2.
+// - Shifting under phi2 or T2 control produces one CB1 clock per bit, with each
+//   half of the CB1 clock lasting 1 or (T2 latch low + 2) cycles respectively.
+//
+// Unlike the cpu model, this has not yet been checked against chiplab traces.
+//
+// Pins which connect to the outside world are exposed as methods. set_* drives
+// an input pin, while port_a(), port_b(), ca2(), cb1() and cb2() report what the
+// VIA is driving.
+use crate::memory_map::Device;
+
+// Register select (RS3-RS0) values.
+const REG_ORB: u16 = 0x0;
+const REG_ORA: u16 = 0x1;
+const REG_DDRB: u16 = 0x2;
+const REG_DDRA: u16 = 0x3;
+const REG_T1C_L: u16 = 0x4;
+const REG_T1C_H: u16 = 0x5;
+const REG_T1L_L: u16 = 0x6;
+const REG_T1L_H: u16 = 0x7;
+const REG_T2C_L: u16 = 0x8;
+const REG_T2C_H: u16 = 0x9;
+const REG_SR: u16 = 0xA;
+const REG_ACR: u16 = 0xB;
+const REG_PCR: u16 = 0xC;
+const REG_IFR: u16 = 0xD;
+const REG_IER: u16 = 0xE;
+const REG_ORA_NO_HANDSHAKE: u16 = 0xF;
+
+// Bits of IFR and IER.
+pub const INT_CA2: u8 = 0x01;
+pub const INT_CA1: u8 = 0x02;
+pub const INT_SR: u8 = 0x04;
+pub const INT_CB2: u8 = 0x08;
+pub const INT_CB1: u8 = 0x10;
+pub const INT_T2: u8 = 0x20;
+pub const INT_T1: u8 = 0x40;
+const INT_ANY: u8 = 0x80;
+
+// ACR bits.
+const ACR_PA_LATCH: u8 = 0x01;
+const ACR_PB_LATCH: u8 = 0x02;
+const ACR_T2_PULSE_COUNT: u8 = 0x20;
+const ACR_T1_FREE_RUN: u8 = 0x40;
+const ACR_T1_PB7: u8 = 0x80;
+
+// Modes for CA2 and CB2, as found in their three PCR bits.
+const C2_INPUT_NEG: u8 = 0;
+const C2_INDEPENDENT_NEG: u8 = 1;
+const C2_INPUT_POS: u8 = 2;
+const C2_INDEPENDENT_POS: u8 = 3;
+const C2_HANDSHAKE: u8 = 4;
+const C2_PULSE: u8 = 5;
+const C2_LOW: u8 = 6;
+const C2_HIGH: u8 = 7;
+
+// Shift register modes, from ACR bits 2-4.
+const SR_DISABLED: u8 = 0;
+const SR_IN_T2: u8 = 1;
+const SR_IN_PHI2: u8 = 2;
+const SR_IN_CB1: u8 = 3;
+const SR_OUT_FREE: u8 = 4;
+const SR_OUT_T2: u8 = 5;
+const SR_OUT_PHI2: u8 = 6;
+const SR_OUT_CB1: u8 = 7;
+
+pub struct Via {
+    ora: u8,
+    orb: u8,
+    ddra: u8,
+    ddrb: u8,
+    acr: u8,
+    pcr: u8,
+    ifr: u8,  // Bit 7 is computed on read.
+    ier: u8,
+
+    // Levels driven onto the pins from outside.
+    pa_in: u8,
+    pb_in: u8,
+    ca1_in: bool,
+    ca2_in: bool,
+    cb1_in: bool,
+    cb2_in: bool,
+    // Port values captured on a CA1 / CB1 edge, when latching is enabled.
+    pa_latch: u8,
+    pb_latch: u8,
+
+    // Levels driven by the VIA when CA2 / CB2 are outputs.
+    ca2_out: bool,
+    cb2_out: bool,
+    // Set when CA2 / CB2 are pulsed low, to return them high next cycle.
+    ca2_pulse: bool,
+    cb2_pulse: bool,
+
+    t1_counter: u16,
+    t1_latch: u16,
+    // Only one interrupt is produced per load in one shot mode.
+    t1_armed: bool,
+    // Set for the cycle where the counter is written, which does not count down.
+    t1_loaded: bool,
+    // Set for the extra cycle a free running timer spends reloading.
+    t1_reload: bool,
+    pb7: bool,
+
+    t2_counter: u16,
+    t2_latch_low: u8,
+    t2_armed: bool,
+    t2_loaded: bool,
+
+    sr: u8,
+    sr_bits: u8,       // bits shifted since the last SR access
+    sr_running: bool,
+    sr_timer: u16,     // cycles until the next CB1 edge in internal clock modes
+    cb1_out: bool,
+}
+
+impl Default for Via {
+    fn default() -> Self {
+        Via::new()
+    }
+}
+
+impl Via {
+    // The state after reset. Reset clears everything but the timers and
+    // shift register, whose contents are left at arbitrary values.
+    pub fn new() -> Via {
+        Via {
+            ora: 0,
+            orb: 0,
+            ddra: 0,
+            ddrb: 0,
+            acr: 0,
+            pcr: 0,
+            ifr: 0,
+            ier: 0,
+            pa_in: 0xFF,
+            pb_in: 0xFF,
+            ca1_in: true,
+            ca2_in: true,
+            cb1_in: true,
+            cb2_in: true,
+            pa_latch: 0,
+            pb_latch: 0,
+            ca2_out: true,
+            cb2_out: true,
+            ca2_pulse: false,
+            cb2_pulse: false,
+            t1_counter: 0xFFFF,
+            t1_latch: 0xFFFF,
+            t1_armed: false,
+            t1_loaded: false,
+            t1_reload: false,
+            pb7: true,
+            t2_counter: 0xFFFF,
+            t2_latch_low: 0xFF,
+            t2_armed: false,
+            t2_loaded: false,
+            sr: 0,
+            sr_bits: 0,
+            sr_running: false,
+            sr_timer: 0,
+            cb1_out: true,
+        }
+    }
+
+    //
+    // Pins
+    //
+
+    // Levels on port A. Output bits are driven from ORA, input bits
+    // follow whatever is driving them externally.
+    pub fn port_a(&self) -> u8 {
+        (self.ora & self.ddra) | (self.pa_in & !self.ddra)
+    }
+    pub fn port_b(&self) -> u8 {
+        let val = (self.orb & self.ddrb) | (self.pb_in & !self.ddrb);
+        if self.acr & ACR_T1_PB7 != 0 {
+            (val & 0x7F) | ((self.pb7 as u8) << 7)
+        } else {
+            val
+        }
+    }
+    pub fn set_port_a(&mut self, val: u8) {
+        self.pa_in = val;
+    }
+    pub fn set_port_b(&mut self, val: u8) {
+        // In pulse counting mode, T2 counts falling edges on PB6.
+        let pb6_fell = self.pb_in & 0x40 != 0 && val & 0x40 == 0;
+        self.pb_in = val;
+        if pb6_fell && self.acr & ACR_T2_PULSE_COUNT != 0 {
+            self.t2_counter = self.t2_counter.wrapping_sub(1);
+            if self.t2_counter == 0 && self.t2_armed {
+                self.ifr |= INT_T2;
+                self.t2_armed = false;
+            }
+        }
+    }
+
+    pub fn set_ca1(&mut self, level: bool) {
+        let prev = self.ca1_in;
+        self.ca1_in = level;
+        if !is_active_edge(prev, level, self.pcr & 0x01 != 0) {
+            return;
+        }
+        self.ifr |= INT_CA1;
+        self.pa_latch = self.port_a();
+        if self.ca2_mode() == C2_HANDSHAKE {
+            self.ca2_out = true;
+        }
+    }
+    pub fn set_ca2(&mut self, level: bool) {
+        let prev = self.ca2_in;
+        self.ca2_in = level;
+        if let Some(positive) = input_edge_polarity(self.ca2_mode()) {
+            if is_active_edge(prev, level, positive) {
+                self.ifr |= INT_CA2;
+            }
+        }
+    }
+    pub fn set_cb1(&mut self, level: bool) {
+        let prev = self.cb1_in;
+        self.cb1_in = level;
+        if prev != level && matches!(self.sr_mode(), SR_IN_CB1 | SR_OUT_CB1) {
+            self.shift_clock(level);
+        }
+        if !is_active_edge(prev, level, self.pcr & 0x10 != 0) {
+            return;
+        }
+        self.ifr |= INT_CB1;
+        self.pb_latch = self.port_b();
+        if self.cb2_mode() == C2_HANDSHAKE {
+            self.cb2_out = true;
+        }
+    }
+    pub fn set_cb2(&mut self, level: bool) {
+        let prev = self.cb2_in;
+        self.cb2_in = level;
+        if let Some(positive) = input_edge_polarity(self.cb2_mode()) {
+            if is_active_edge(prev, level, positive) {
+                self.ifr |= INT_CB2;
+            }
+        }
+    }
+
+    // Level driven on CA2, or None if it is an input.
+    pub fn ca2(&self) -> Option<bool> {
+        if self.ca2_mode() >= C2_HANDSHAKE { Some(self.ca2_out) } else { None }
+    }
+    // Level driven on CB1, or None if it is an input. CB1 is the shift
+    // clock output when shifting under phi2 or T2 control.
+    pub fn cb1(&self) -> Option<bool> {
+        match self.sr_mode() {
+            SR_DISABLED | SR_IN_CB1 | SR_OUT_CB1 => None,
+            _ => Some(self.cb1_out),
+        }
+    }
+    // Level driven on CB2, or None if it is an input. CB2 is the shift
+    // register's data output when shifting out.
+    pub fn cb2(&self) -> Option<bool> {
+        if self.sr_mode() >= SR_OUT_FREE || self.cb2_mode() >= C2_HANDSHAKE {
+            Some(self.cb2_out)
+        } else {
+            None
+        }
+    }
+
+    //
+    // Internals
+    //
+
+    fn ca2_mode(&self) -> u8 {
+        (self.pcr >> 1) & 7
+    }
+    fn cb2_mode(&self) -> u8 {
+        (self.pcr >> 5) & 7
+    }
+    fn sr_mode(&self) -> u8 {
+        (self.acr >> 2) & 7
+    }
+
+    // Accessing port A clears its interrupts, and runs the CA2 handshake.
+    fn port_a_access(&mut self) {
+        self.ifr &= !INT_CA1;
+        match self.ca2_mode() {
+            C2_INDEPENDENT_NEG | C2_INDEPENDENT_POS => {},
+            C2_HANDSHAKE => {
+                self.ifr &= !INT_CA2;
+                self.ca2_out = false;
+            },
+            C2_PULSE => {
+                self.ifr &= !INT_CA2;
+                self.ca2_out = false;
+                self.ca2_pulse = true;
+            },
+            _ => self.ifr &= !INT_CA2,
+        }
+    }
+    // Same for port B, except that the CB2 handshake only runs on writes.
+    fn port_b_access(&mut self, write: bool) {
+        self.ifr &= !INT_CB1;
+        match self.cb2_mode() {
+            C2_INDEPENDENT_NEG | C2_INDEPENDENT_POS => {},
+            C2_HANDSHAKE if write => {
+                self.ifr &= !INT_CB2;
+                self.cb2_out = false;
+            },
+            C2_PULSE if write => {
+                self.ifr &= !INT_CB2;
+                self.cb2_out = false;
+                self.cb2_pulse = true;
+            },
+            _ => self.ifr &= !INT_CB2,
+        }
+    }
+
+    fn sr_access(&mut self) {
+        self.ifr &= !INT_SR;
+        self.sr_bits = 0;
+        self.sr_running = self.sr_mode() != SR_DISABLED;
+        self.sr_timer = self.sr_half_period();
+    }
+
+    // Cycles for each half of the internally generated shift clock.
+    fn sr_half_period(&self) -> u16 {
+        match self.sr_mode() {
+            SR_IN_PHI2 | SR_OUT_PHI2 => 1,
+            SR_IN_T2 | SR_OUT_FREE | SR_OUT_T2 => self.t2_latch_low as u16 + 2,
+            // CB1 is clocked externally.
+            _ => 0,
+        }
+    }
+
+    // Handle an edge of the shift clock on CB1. Data is shifted out on
+    // falling edges, and in on rising edges.
+    fn shift_clock(&mut self, rising: bool) {
+        if !self.sr_running {
+            return;
+        }
+        let shift_out = self.sr_mode() >= SR_OUT_FREE;
+        if !rising {
+            if shift_out {
+                self.cb2_out = self.sr & 0x80 != 0;
+                self.sr = self.sr.rotate_left(1);
+            }
+            return;
+        }
+        if !shift_out {
+            self.sr = (self.sr << 1) | self.cb2_in as u8;
+        }
+        self.sr_bits += 1;
+        if self.sr_bits == 8 {
+            self.sr_bits = 0;
+            // Free running mode shifts continuously, without interrupting.
+            if self.sr_mode() != SR_OUT_FREE {
+                self.sr_running = false;
+                self.ifr |= INT_SR;
+            }
+        }
+    }
+
+    fn tick_t1(&mut self) {
+        if self.t1_loaded {
+            self.t1_loaded = false;
+            return;
+        }
+        if self.t1_reload {
+            self.t1_reload = false;
+            self.t1_counter = self.t1_latch;
+            return;
+        }
+        let (counter, underflow) = self.t1_counter.overflowing_sub(1);
+        self.t1_counter = counter;
+        if !underflow {
+            return;
+        }
+        let free_run = self.acr & ACR_T1_FREE_RUN != 0;
+        if self.t1_armed {
+            self.ifr |= INT_T1;
+            self.pb7 = if free_run { !self.pb7 } else { true };
+            self.t1_armed = free_run;
+        }
+        self.t1_reload = free_run;
+    }
+
+    fn tick_t2(&mut self) {
+        if self.acr & ACR_T2_PULSE_COUNT != 0 {
+            return;
+        }
+        if self.t2_loaded {
+            self.t2_loaded = false;
+            return;
+        }
+        let (counter, underflow) = self.t2_counter.overflowing_sub(1);
+        self.t2_counter = counter;
+        if underflow && self.t2_armed {
+            self.ifr |= INT_T2;
+            self.t2_armed = false;
+        }
+    }
+
+    fn tick_sr(&mut self) {
+        if !self.sr_running || matches!(self.sr_mode(), SR_DISABLED | SR_IN_CB1 | SR_OUT_CB1) {
+            return;
+        }
+        self.sr_timer -= 1;
+        if self.sr_timer == 0 {
+            self.sr_timer = self.sr_half_period();
+            self.cb1_out = !self.cb1_out;
+            self.shift_clock(self.cb1_out);
+        }
+    }
+}
+
+// Whether a transition from prev to level is the edge selected by positive.
+fn is_active_edge(prev: bool, level: bool, positive: bool) -> bool {
+    prev != level && level == positive
+}
+
+// For CA2 / CB2 input modes, whether a positive edge is active.
+fn input_edge_polarity(mode: u8) -> Option<bool> {
+    match mode {
+        C2_INPUT_NEG | C2_INDEPENDENT_NEG => Some(false),
+        C2_INPUT_POS | C2_INDEPENDENT_POS => Some(true),
+        _ => None,
+    }
+}
+
+impl Device for Via {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset & 0xF {
+            REG_ORB => {
+                self.port_b_access(false);
+                let pins = if self.acr & ACR_PB_LATCH != 0 { self.pb_latch } else { self.port_b() };
+                // Output bits read back from ORB, regardless of the pin level.
+                (self.orb & self.ddrb) | (pins & !self.ddrb)
+            },
+            reg @ (REG_ORA | REG_ORA_NO_HANDSHAKE) => {
+                if reg == REG_ORA {
+                    self.port_a_access();
+                }
+                if self.acr & ACR_PA_LATCH != 0 { self.pa_latch } else { self.port_a() }
+            },
+            REG_DDRB => self.ddrb,
+            REG_DDRA => self.ddra,
+            REG_T1C_L => {
+                self.ifr &= !INT_T1;
+                self.t1_counter as u8
+            },
+            REG_T1C_H => (self.t1_counter >> 8) as u8,
+            REG_T1L_L => self.t1_latch as u8,
+            REG_T1L_H => (self.t1_latch >> 8) as u8,
+            REG_T2C_L => {
+                self.ifr &= !INT_T2;
+                self.t2_counter as u8
+            },
+            REG_T2C_H => (self.t2_counter >> 8) as u8,
+            REG_SR => {
+                self.sr_access();
+                self.sr
+            },
+            REG_ACR => self.acr,
+            REG_PCR => self.pcr,
+            REG_IFR => {
+                let any = if self.irq() { INT_ANY } else { 0 };
+                self.ifr | any
+            },
+            REG_IER => self.ier | INT_ANY,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, offset: u16, val: u8) {
+        match offset & 0xF {
+            REG_ORB => {
+                self.port_b_access(true);
+                self.orb = val;
+            },
+            reg @ (REG_ORA | REG_ORA_NO_HANDSHAKE) => {
+                if reg == REG_ORA {
+                    self.port_a_access();
+                }
+                self.ora = val;
+            },
+            REG_DDRB => self.ddrb = val,
+            REG_DDRA => self.ddra = val,
+            REG_T1C_L | REG_T1L_L => {
+                self.t1_latch = (self.t1_latch & 0xFF00) | val as u16;
+            },
+            REG_T1C_H => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | ((val as u16) << 8);
+                self.t1_counter = self.t1_latch;
+                self.ifr &= !INT_T1;
+                self.t1_armed = true;
+                self.t1_loaded = true;
+                self.t1_reload = false;
+                self.pb7 = false;
+            },
+            REG_T1L_H => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | ((val as u16) << 8);
+                self.ifr &= !INT_T1;
+            },
+            REG_T2C_L => self.t2_latch_low = val,
+            REG_T2C_H => {
+                self.t2_counter = ((val as u16) << 8) | self.t2_latch_low as u16;
+                self.ifr &= !INT_T2;
+                self.t2_armed = true;
+                self.t2_loaded = true;
+            },
+            REG_SR => {
+                self.sr_access();
+                self.sr = val;
+            },
+            REG_ACR => {
+                let mode = self.sr_mode();
+                self.acr = val;
+                // A new shift mode restarts the shift clock, as CB1 and
+                // disabled modes leave no timer running.
+                if self.sr_mode() != mode {
+                    self.sr_running &= self.sr_mode() != SR_DISABLED;
+                    self.sr_timer = self.sr_half_period();
+                }
+            },
+            REG_PCR => {
+                self.pcr = val;
+                match self.ca2_mode() {
+                    C2_LOW => self.ca2_out = false,
+                    C2_HIGH => self.ca2_out = true,
+                    _ => {},
+                }
+                match self.cb2_mode() {
+                    C2_LOW => self.cb2_out = false,
+                    C2_HIGH => self.cb2_out = true,
+                    _ => {},
+                }
+            },
+            REG_IFR => self.ifr &= !(val & 0x7F),
+            REG_IER => {
+                if val & INT_ANY != 0 {
+                    self.ier |= val & 0x7F;
+                } else {
+                    self.ier &= !val;
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.ca2_pulse {
+            self.ca2_pulse = false;
+            self.ca2_out = true;
+        }
+        if self.cb2_pulse {
+            self.cb2_pulse = false;
+            self.cb2_out = true;
+        }
+        self.tick_t1();
+        self.tick_t2();
+        self.tick_sr();
+    }
+
+    fn irq(&self) -> bool {
+        self.ifr & self.ier & 0x7F != 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Ticks until an interrupt flag is set, returning the number of ticks.
+    fn ticks_until(via: &mut Via, flag: u8) -> usize {
+        for i in 1 .. 100000 {
+            via.tick();
+            if via.ifr & flag != 0 {
+                return i;
+            }
+        }
+        panic!("flag never set");
+    }
+
+    #[test]
+    fn test_ports() {
+        let mut via = Via::new();
+        via.write(REG_DDRA, 0xF0);
+        via.write(REG_ORA, 0xAB);
+        via.set_port_a(0x05);
+        assert_eq!(0xA5, via.port_a());
+        assert_eq!(0xA5, via.read(REG_ORA));
+
+        // output bits on port b read back from ORB, even if the pin is held low.
+        via.write(REG_DDRB, 0x0F);
+        via.write(REG_ORB, 0xFF);
+        via.set_port_b(0x30);
+        assert_eq!(0x3F, via.read(REG_ORB));
+    }
+
+    #[test]
+    fn test_t1_one_shot_and_free_run() {
+        let mut via = Via::new();
+        via.write(REG_IER, INT_ANY | INT_T1);
+        via.write(REG_T1C_L, 10);
+        via.write(REG_T1C_H, 0);
+        assert!(!via.irq());
+        // The write cycle, N cycles counting down to zero, then the
+        // cycle where the counter passes zero.
+        assert_eq!(12, ticks_until(&mut via, INT_T1));
+        assert!(via.irq());
+        assert_eq!(0xC0, via.read(REG_IFR));
+
+        // reading the low counter clears the flag, and one shot
+        // mode does not interrupt again.
+        via.read(REG_T1C_L);
+        assert!(!via.irq());
+        for _ in 0 .. 0x20000 {
+            via.tick();
+        }
+        assert!(!via.irq());
+
+        via.write(REG_ACR, ACR_T1_FREE_RUN | ACR_T1_PB7);
+        via.write(REG_T1C_H, 0);
+        assert_eq!(0, via.port_b() & 0x80);
+        assert_eq!(12, ticks_until(&mut via, INT_T1));
+        assert_eq!(0x80, via.port_b() & 0x80);
+        via.read(REG_T1C_L);
+        assert_eq!(12, ticks_until(&mut via, INT_T1));
+        assert_eq!(0, via.port_b() & 0x80);
+    }
+
+    #[test]
+    fn test_t2_pulse_count() {
+        let mut via = Via::new();
+        via.write(REG_ACR, ACR_T2_PULSE_COUNT);
+        via.write(REG_T2C_L, 3);
+        via.write(REG_T2C_H, 0);
+        for _ in 0 .. 100 {
+            via.tick();
+        }
+        for i in 0 .. 3 {
+            assert_eq!(0, via.ifr & INT_T2, "pulse {i}");
+            via.set_port_b(0x00);
+            via.set_port_b(0xFF);
+        }
+        assert_eq!(INT_T2, via.ifr & INT_T2);
+    }
+
+    #[test]
+    fn test_shift_out_phi2() {
+        let mut via = Via::new();
+        via.write(REG_ACR, SR_OUT_PHI2 << 2);
+        via.write(REG_SR, 0b1011_0010);
+        let mut bits = Vec::new();
+        for _ in 0 .. 16 {
+            via.tick();
+            // sample the data on rising edges, as a receiver would.
+            if via.cb1() == Some(true) {
+                bits.push(via.cb2().unwrap() as u8);
+            }
+        }
+        assert_eq!(vec![1, 0, 1, 1, 0, 0, 1, 0], bits);
+        assert_eq!(INT_SR, via.ifr & INT_SR);
+        via.read(REG_SR);
+        assert_eq!(0, via.ifr & INT_SR);
+    }
+
+    #[test]
+    fn test_shift_mode_change() {
+        // Start a shift clocked by CB1, then switch to phi2 part way.
+        let mut via = Via::new();
+        via.write(REG_ACR, SR_OUT_CB1 << 2);
+        via.write(REG_SR, 0xFF);
+        via.write(REG_ACR, SR_OUT_PHI2 << 2);
+        assert!(ticks_until(&mut via, INT_SR) <= 16);
+
+        // disabling the shift register stops the shift.
+        via.write(REG_SR, 0xFF);
+        via.write(REG_ACR, SR_DISABLED << 2);
+        for _ in 0 .. 100 {
+            via.tick();
+        }
+        assert_eq!(0, via.ifr & INT_SR);
+    }
+
+    #[test]
+    fn test_ca_handshake() {
+        let mut via = Via::new();
+        via.write(REG_PCR, (C2_HANDSHAKE << 1) | 0x01);  // CA1 positive edge
+        via.write(REG_IER, INT_ANY | INT_CA1);
+        via.set_port_a(0x42);
+        via.write(REG_ORA, 0x00);
+        assert_eq!(Some(false), via.ca2());
+
+        via.set_ca1(false);
+        assert!(!via.irq());
+        via.set_ca1(true);
+        assert!(via.irq());
+        assert_eq!(Some(true), via.ca2());
+        assert_eq!(0x42, via.read(REG_ORA));
+        assert!(!via.irq());
+    }
+}